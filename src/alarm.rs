@@ -0,0 +1,301 @@
+/*
+    Alarms start playing at set times, like weekdays at 07:30, with the volume ramping up from 0.
+    The wall clock is checked, as the monotonic clock may not count time the system was suspended.
+*/
+
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveTime, Offset, TimeDelta, TimeZone, Weekday};
+use tracing::warn;
+use crate::smart_playlist::parse_duration;
+
+
+
+/// Alarms that are this late are still played, even if missed alarms are skipped. (Waking up takes a moment.)
+const LATE_GRACE: Duration = Duration::from_secs(60);
+/// The wall clock is checked at least this often, to notice suspends.
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// How often the volume is changed while ramping up.
+const RAMP_STEP: Duration = Duration::from_millis(250);
+
+/// What to do with alarms whose time passed while the system was suspended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissedAlarms {
+    Skip,
+    Play,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alarm {
+    pub time: NaiveTime,
+    /// Empty is every day.
+    pub days: Vec<Weekday>,
+    /// Playlist to switch to, None keeps the current playlist.
+    pub playlist: Option<String>,
+    /// From 0 to 1, None keeps the current volume.
+    pub volume: Option<f64>,
+    /// Time to ramp up the volume from 0.
+    pub ramp: Duration,
+}
+
+impl Alarm {
+    /// `time` is like "07:30", `days` are like "mon" or "monday", or "weekdays" & "weekends".
+    pub fn parse(time: &str, days: &[String], playlist: Option<String>, volume: Option<f64>, ramp: Option<&str>) -> Result<Alarm, String> {
+        let time = NaiveTime::parse_from_str(time, "%H:%M")
+            .or_else(|_| NaiveTime::parse_from_str(time, "%H:%M:%S"))
+            .map_err(|_| format!("Alarm time \"{}\" is invalid, expected like \"07:30\"", time))?;
+
+        let mut weekdays = vec![];
+        for day in days {
+            match day.to_lowercase().as_str() {
+                "weekdays" => weekdays.extend([Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri]),
+                "weekends" => weekdays.extend([Weekday::Sat, Weekday::Sun]),
+                day => weekdays.push(day.parse::<Weekday>()
+                    .map_err(|_| format!("Alarm day \"{}\" is invalid, expected like \"mon\", \"monday\", \"weekdays\" or \"weekends\"", day))?),
+            }
+        }
+
+        if let Some(volume) = volume.filter(|v| !(0.0..=1.0).contains(v)) {
+            return Err(format!("Alarm volume {} is invalid, expected from 0 to 1", volume));
+        }
+
+        let ramp = match ramp {
+            Some(ramp) => parse_duration(ramp)
+                .ok_or_else(|| format!("Alarm ramp \"{}\" is invalid, expected a number followed by s, m or h (like \"5m\")", ramp))?,
+            None => Duration::from_secs(60),
+        };
+
+        Ok(Alarm { time, days: weekdays, playlist, volume, ramp })
+    }
+
+    /// The first time this alarm goes off after `after`.
+    pub fn next_after<Tz: TimeZone>(&self, after: DateTime<Tz>) -> Option<DateTime<Tz>> {
+        let timezone = after.timezone();
+        (0..=7)
+            .filter_map(|days| after.date_naive().checked_add_days(chrono::Days::new(days)))
+            .filter(|date| self.days.is_empty() || self.days.contains(&date.weekday()))
+            .filter_map(|date| self.time_on(date, &timezone))
+            .find(|time| *time > after)
+    }
+
+    /// Times repeated by daylight saving time go off the first time.
+    /// Times skipped by daylight saving time go off as much later as the clock jumped ahead, like 02:30 at 03:30.
+    fn time_on<Tz: TimeZone>(&self, date: NaiveDate, timezone: &Tz) -> Option<DateTime<Tz>> {
+        let time = date.and_time(self.time);
+        timezone.from_local_datetime(&time).earliest().or_else(|| {
+            // The offset from before the clock jumped ahead.
+            let offset = timezone.offset_from_utc_datetime(&(time - TimeDelta::days(1))).fix();
+            Some(timezone.from_utc_datetime(&(time - offset)))
+        })
+    }
+}
+
+
+
+/// Keeps track of when the alarms go off next.
+pub struct AlarmScheduler {
+    alarms: Vec<Alarm>,
+    /// Same order as `alarms`.
+    next: Vec<Option<DateTime<Local>>>,
+    missed: MissedAlarms,
+}
+
+impl AlarmScheduler {
+    pub fn new(alarms: Vec<Alarm>, missed: MissedAlarms) -> AlarmScheduler {
+        let now = Local::now();
+        let next = alarms.iter().map(|alarm| alarm.next_after(now)).collect();
+        AlarmScheduler { alarms, next, missed }
+    }
+
+    pub fn alarms(&self) -> &[Alarm] {
+        &self.alarms
+    }
+
+    /// The next alarm, with the index of the alarm.
+    pub fn next_alarm(&self) -> Option<(usize, DateTime<Local>)> {
+        self.next.iter().enumerate()
+            .filter_map(|(i, time)| time.map(|time| (i, time)))
+            .min_by_key(|(_, time)| *time)
+    }
+
+    /// Indices of the alarms that should go off now.
+    /// Alarms that were missed by more than `LATE_GRACE` are skipped, unless missed alarms are played.
+    pub fn poll(&mut self) -> Vec<usize> {
+        let now = Local::now();
+        let mut due = vec![];
+        for (i, alarm) in self.alarms.iter().enumerate() {
+            let Some(time) = self.next[i].filter(|time| *time <= now) else { continue };
+            let late = (now - time).to_std().unwrap_or_default();
+            if late <= LATE_GRACE || self.missed == MissedAlarms::Play {
+                due.push(i);
+            } else {
+                warn!("Skipped alarm of {}, the system was suspended.", time.format("%a %H:%M"));
+            }
+            self.next[i] = alarm.next_after(now);
+        }
+        due
+    }
+
+    /// When the alarms need to be checked again.
+    pub fn next_check(&self) -> Option<Instant> {
+        let (_, time) = self.next_alarm()?;
+        let until = (time - Local::now()).to_std().unwrap_or_default();
+        Some(Instant::now() + until.min(CHECK_INTERVAL))
+    }
+}
+
+
+
+/// Volume multiplier going from 0 to 1, for alarms.
+#[derive(Debug, Clone, Copy)]
+pub struct VolumeRamp {
+    start: Instant,
+    duration: Duration,
+}
+
+impl VolumeRamp {
+    pub fn new(duration: Duration) -> VolumeRamp {
+        VolumeRamp { start: Instant::now(), duration }
+    }
+
+    pub fn factor(&self, now: Instant) -> f32 {
+        if self.duration.is_zero() {
+            return 1.0;
+        }
+        (now.saturating_duration_since(self.start).as_secs_f32() / self.duration.as_secs_f32()).clamp(0.0, 1.0)
+    }
+
+    pub fn is_done(&self, now: Instant) -> bool {
+        now >= self.start + self.duration
+    }
+
+    pub fn next_check(&self, now: Instant) -> Option<Instant> {
+        (!self.is_done(now)).then(|| now + RAMP_STEP)
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{FixedOffset, MappedLocalTime, NaiveDateTime, Timelike};
+
+    /// Central European Time of 2024, the clock jumps from 02:00 to 03:00 on March 31 & back from 03:00 to 02:00 on October 27.
+    #[derive(Debug, Clone, Copy)]
+    struct TestZone;
+
+    impl TestZone {
+        fn offsets() -> [FixedOffset; 2] {
+            [FixedOffset::east_opt(3600).unwrap(), FixedOffset::east_opt(2 * 3600).unwrap()]
+        }
+    }
+
+    impl TimeZone for TestZone {
+        type Offset = FixedOffset;
+
+        fn from_offset(_: &FixedOffset) -> TestZone {
+            TestZone
+        }
+
+        fn offset_from_local_date(&self, local: &NaiveDate) -> MappedLocalTime<FixedOffset> {
+            self.offset_from_local_datetime(&local.and_time(NaiveTime::MIN))
+        }
+
+        fn offset_from_local_datetime(&self, local: &NaiveDateTime) -> MappedLocalTime<FixedOffset> {
+            let valid = TestZone::offsets().into_iter()
+                .filter(|offset| self.offset_from_utc_datetime(&(*local - *offset)) == *offset)
+                .collect::<Vec<_>>();
+            match valid.as_slice() {
+                [offset] => MappedLocalTime::Single(*offset),
+                // The earliest time has the largest offset.
+                [standard, summer] => MappedLocalTime::Ambiguous(*summer, *standard),
+                _ => MappedLocalTime::None,
+            }
+        }
+
+        fn offset_from_utc_date(&self, utc: &NaiveDate) -> FixedOffset {
+            self.offset_from_utc_datetime(&utc.and_time(NaiveTime::MIN))
+        }
+
+        fn offset_from_utc_datetime(&self, utc: &NaiveDateTime) -> FixedOffset {
+            let summer_start = date(2024, 3, 31).and_hms_opt(1, 0, 0).unwrap();
+            let summer_end = date(2024, 10, 27).and_hms_opt(1, 0, 0).unwrap();
+            let [standard, summer] = TestZone::offsets();
+            if (summer_start..summer_end).contains(utc) { summer } else { standard }
+        }
+    }
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    fn local(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<TestZone> {
+        TestZone.from_local_datetime(&date(year, month, day).and_hms_opt(hour, minute, 0).unwrap()).earliest().unwrap()
+    }
+
+    fn alarm(time: &str, days: &[&str]) -> Alarm {
+        Alarm::parse(time, &days.iter().map(|d| d.to_string()).collect::<Vec<_>>(), None, None, None).unwrap()
+    }
+
+    fn utc_hour_minute(time: DateTime<TestZone>) -> (u32, u32) {
+        let utc = time.naive_utc();
+        (utc.hour(), utc.minute())
+    }
+
+    #[test]
+    fn same_day() {
+        assert_eq!(alarm("07:30", &[]).next_after(local(2024, 1, 10, 6, 0)), Some(local(2024, 1, 10, 7, 30)));
+        // Strictly after, an alarm that just went off is next the day after.
+        assert_eq!(alarm("07:30", &[]).next_after(local(2024, 1, 10, 7, 30)), Some(local(2024, 1, 11, 7, 30)));
+    }
+
+    #[test]
+    fn midnight_wrap() {
+        assert_eq!(alarm("00:15", &[]).next_after(local(2024, 1, 10, 23, 50)), Some(local(2024, 1, 11, 0, 15)));
+        assert_eq!(alarm("23:59", &[]).next_after(local(2024, 12, 31, 23, 59)), Some(local(2025, 1, 1, 23, 59)));
+        assert_eq!(alarm("00:00", &["mon"]).next_after(local(2024, 1, 14, 23, 59)), Some(local(2024, 1, 15, 0, 0)));
+    }
+
+    #[test]
+    fn weekday_rollover() {
+        // Friday after the alarm, next is Monday.
+        assert_eq!(alarm("07:30", &["weekdays"]).next_after(local(2024, 1, 12, 8, 0)), Some(local(2024, 1, 15, 7, 30)));
+        assert_eq!(alarm("10:00", &["weekends"]).next_after(local(2024, 1, 14, 11, 0)), Some(local(2024, 1, 20, 10, 0)));
+        // Only Monday, after the alarm on Monday is the Monday a week later.
+        assert_eq!(alarm("07:30", &["monday"]).next_after(local(2024, 1, 15, 7, 31)), Some(local(2024, 1, 22, 7, 30)));
+        assert_eq!(alarm("07:30", &["Wed", "sun"]).next_after(local(2024, 1, 15, 7, 31)), Some(local(2024, 1, 17, 7, 30)));
+    }
+
+    #[test]
+    fn daylight_saving_time_skipped_hour() {
+        // 02:30 doesn't exist on March 31, it goes off at 03:30 instead.
+        let time = alarm("02:30", &[]).next_after(local(2024, 3, 31, 0, 0)).unwrap();
+        assert_eq!(time.naive_local(), date(2024, 3, 31).and_hms_opt(3, 30, 0).unwrap());
+        assert_eq!(utc_hour_minute(time), (1, 30));
+        // The day after is normal again.
+        assert_eq!(alarm("02:30", &[]).next_after(time), Some(local(2024, 4, 1, 2, 30)));
+        // Later times on that day are at their local time, an hour earlier in UTC.
+        assert_eq!(utc_hour_minute(alarm("07:30", &[]).next_after(local(2024, 3, 31, 0, 0)).unwrap()), (5, 30));
+        assert_eq!(utc_hour_minute(alarm("07:30", &[]).next_after(local(2024, 3, 30, 8, 0)).unwrap()), (5, 30));
+    }
+
+    #[test]
+    fn daylight_saving_time_repeated_hour() {
+        // 02:30 happens twice on October 27, only the first one goes off.
+        let first = alarm("02:30", &[]).next_after(local(2024, 10, 27, 0, 0)).unwrap();
+        assert_eq!(utc_hour_minute(first), (0, 30));
+        let second = first + TimeDelta::hours(1);
+        assert_eq!(second.naive_local(), first.naive_local());
+        assert_eq!(alarm("02:30", &[]).next_after(first), Some(local(2024, 10, 28, 2, 30)));
+        assert_eq!(alarm("02:30", &[]).next_after(second), Some(local(2024, 10, 28, 2, 30)));
+    }
+
+    #[test]
+    fn invalid_alarms() {
+        assert!(Alarm::parse("7:30pm", &[], None, None, None).is_err());
+        assert!(Alarm::parse("25:00", &[], None, None, None).is_err());
+        assert!(Alarm::parse("07:30", &["someday".to_string()], None, None, None).is_err());
+        assert!(Alarm::parse("07:30", &[], None, Some(1.5), None).is_err());
+        assert!(Alarm::parse("07:30", &[], None, None, Some("soon")).is_err());
+    }
+}
@@ -1,11 +1,343 @@
 
 // TODO: Refactor all of this, it's pretty ugly, I hope you don't look. . . ◑﹏◐
 
-use std::{fs, path::PathBuf};
-use clap::{ArgGroup, Parser, ValueEnum};
+use std::{collections::BTreeMap, env, fmt, fs, io, ops::Range, path::{Path, PathBuf}, time::Duration};
+use clap::{Parser, Subcommand, ValueEnum};
 use serde::Deserialize;
-use anyhow::Result;
-use crate::loudness_normalization::LoudnessNormalization;
+use thiserror::Error;
+use crate::{alarm::{Alarm, MissedAlarms}, ipc::IpcCommand, logging::LogFormat, loudness_normalization::LoudnessNormalization, paths, playlist::{ShuffleMode, LIBRARY_PLAYLIST}, sleep_timer::SleepAction, smart_playlist::SmartPlaylist, stats::StatsFormat, weights::WeightOptions};
+
+
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Failed to read config file {file:?}: {source}")]
+    Read { file: PathBuf, source: io::Error },
+    #[error("Failed to write config file {file:?}: {source}")]
+    Write { file: PathBuf, source: io::Error },
+    /// Line & column start at 1.
+    #[error("{}:{line}:{column}: {message}", file.display())]
+    File { file: PathBuf, line: usize, column: usize, message: String },
+    #[error("{0}")]
+    Invalid(String),
+}
+
+/// Where a config value came from, later ones override earlier ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigSource {
+    Default,
+    File,
+    Env,
+    Cli,
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigSource::Default => write!(f, "default"),
+            ConfigSource::File => write!(f, "file"),
+            ConfigSource::Env => write!(f, "environment"),
+            ConfigSource::Cli => write!(f, "command line"),
+        }
+    }
+}
+
+
+
+/// The config file & its text, to point errors at the line & column they're at.
+struct ConfigFile {
+    path: PathBuf,
+    text: String,
+}
+
+impl ConfigFile {
+    fn read(path: &Path) -> Result<ConfigFile, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|source| ConfigError::Read { file: path.to_path_buf(), source })?;
+        Ok(ConfigFile { path: path.to_path_buf(), text })
+    }
+
+    /// Without a span the error is at the start of the file.
+    fn error_at(&self, span: Option<Range<usize>>, message: String) -> ConfigError {
+        let offset = span.map(|span| span.start).unwrap_or(0).min(self.text.len());
+        let before = self.text.get(..offset).unwrap_or_default();
+        ConfigError::File {
+            file: self.path.clone(),
+            line: before.matches('\n').count() + 1,
+            column: before.rsplit('\n').next().unwrap_or_default().chars().count() + 1,
+            message,
+        }
+    }
+
+    /// Span of a value, `path` is like ["config", "volume"], or ["alarm", "0", "time"] for the first alarm.
+    fn span(&self, path: &[&str]) -> Option<Range<usize>> {
+        let document = toml_edit::ImDocument::parse(self.text.as_str()).ok()?;
+        let mut item = document.as_item();
+        for key in path {
+            item = match key.parse::<usize>() {
+                Ok(index) => item.get(index)?,
+                Err(_) => item.get(*key)?,
+            };
+        }
+        item.span()
+    }
+
+    fn toml_error(&self, err: toml::de::Error) -> ConfigError {
+        self.error_at(err.span(), suggest_key(err.message()))
+    }
+}
+
+/// Commented config file written by `config init`, `config set` starts from this if there's no config file yet.
+const CONFIG_TEMPLATE: &str = include_str!("../config.toml");
+
+fn write_config_file(file: &Path, text: &str) -> Result<(), ConfigError> {
+    let error = |source| ConfigError::Write { file: file.to_path_buf(), source };
+    if let Some(parent) = file.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).map_err(error)?;
+    }
+    fs::write(file, text).map_err(error)
+}
+
+/// Sets `key` (like "volume" or "config.volume") to `value` in the config file, keeping the comments & formatting.
+/// `value` is parsed like environment variables.
+fn set_value(file: &ConfigFile, key: &str, value: &str) -> Result<String, ConfigError> {
+    let keys = PartialConfig::empty().keys().into_iter()
+        .filter_map(|(key, _)| key.split_once('.').map(|(section, field)| (key, section, field)))
+        .collect::<Vec<_>>();
+    let Some((key, section, field)) = keys.iter().copied().find(|(full, _, field)| *full == key || *field == key) else {
+        return Err(ConfigError::Invalid(match similar(key, keys.iter().map(|(_, _, field)| *field)) {
+            Some(field) => format!("Unknown key \"{}\", did you mean \"{}\"?", key, field),
+            None => format!("Unknown key \"{}\" (Smart playlists & alarms can only be edited in the config file)", key),
+        }));
+    };
+    let value = env_value(section, field, value)
+        .map_err(|err| ConfigError::Invalid(format!("{}: {}", key, suggest_key(err.message()))))?;
+    let value = absolute_paths(key, value).to_string().parse::<toml_edit::Value>()
+        .map_err(|err| ConfigError::Invalid(format!("{}: {}", key, err.message())))?;
+
+    let mut document = file.text.parse::<toml_edit::DocumentMut>().map_err(|err| file.error_at(err.span(), err.message().to_string()))?;
+    let Some(table) = document.entry(section).or_insert(toml_edit::table()).as_table_like_mut() else {
+        return Err(file.error_at(file.span(&[section]), format!("\"{}\" should be a table", section)));
+    };
+    match table.get_mut(field) {
+        Some(toml_edit::Item::Value(old)) => {
+            // The decor has the spaces & the comment after the value.
+            let decor = old.decor().clone();
+            *old = value;
+            *old.decor_mut() = decor;
+        },
+        Some(item) => *item = toml_edit::Item::Value(value),
+        None => { table.insert(field, toml_edit::Item::Value(value)); },
+    }
+    Ok(document.to_string())
+}
+
+/// Relative paths in the config file are relative to the config file, on the command line they're relative to the working directory.
+fn absolute_paths(key: &str, value: toml::Value) -> toml::Value {
+    let absolute = |path: &str| std::path::absolute(path).map(|path| toml::Value::from(path.display().to_string())).unwrap_or_else(|_| toml::Value::from(path));
+    match (key, value) {
+        ("program-paths.yt-dlp-path" | "program-paths.ffmpeg-path", toml::Value::String(path)) if !is_program_name(Path::new(&path)) => absolute(&path),
+        ("config.local-directories" | "config.playlist-files", toml::Value::Array(paths)) => toml::Value::Array(paths.into_iter()
+            .map(|path| match path {
+                toml::Value::String(path) => absolute(&path),
+                path => path,
+            })
+            .collect()),
+        (_, value) => value,
+    }
+}
+
+/// Runs `config init` & `config set`. These write the config file, so they work without a valid config.
+/// Returns false for other commands.
+pub fn run_write_command() -> Result<bool, ConfigError> {
+    let cli = CliConfigParser::parse();
+    let Some(Command::Config(command)) = &cli.command else { return Ok(false) };
+    let config_file = cli.config_file.clone().unwrap_or_else(migrated_config_file);
+    match command {
+        ConfigCommand::Check => return Ok(false),
+        ConfigCommand::Init { force } => {
+            if config_file.exists() && !force {
+                return Err(ConfigError::Invalid(format!("Config file {:?} already exists, use --force to overwrite it", config_file)));
+            }
+            write_config_file(&config_file, CONFIG_TEMPLATE)?;
+            println!("Wrote {}", config_file.display());
+        },
+        ConfigCommand::Set { key, value } => {
+            let text = match config_file.exists() {
+                true => ConfigFile::read(&config_file)?.text,
+                false => CONFIG_TEMPLATE.to_string(),
+            };
+            let file = ConfigFile { path: config_file.clone(), text };
+            let text = set_value(&file, key, value)?;
+            // Checked like the player loads it before it's written, with the environment & CLI, so an invalid value isn't written.
+            let (cli_config, _, _) = PartialConfig::from_cli_args();
+            Config::from_layers(Some(ConfigFile { path: config_file.clone(), text: text.clone() }), Some(config_file.clone()), ConfigEnv::read()?, cli_config, None, false)?;
+            write_config_file(&config_file, &text)?;
+            println!("Set {} in {}", key, config_file.display());
+        },
+    }
+    Ok(true)
+}
+
+/// The default config file, copied from the working directory like older versions used.
+fn migrated_config_file() -> PathBuf {
+    let config_file = paths::config_file();
+    match paths::migrate_config_file(&config_file) {
+        Ok(Some(legacy_file)) => eprintln!("Copied config file {:?} to {:?}, the config file in the working directory isn't used anymore.", legacy_file, config_file),
+        Ok(None) => {},
+        Err(err) => eprintln!("Failed to copy the config file to {:?}: {}", config_file, err),
+    }
+    config_file
+}
+
+/// The most similar of `names`, if any is similar enough.
+fn similar<'a>(name: &str, names: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    names
+        .map(|candidate| (strsim::jaro_winkler(&name.to_lowercase(), &candidate.to_lowercase()), candidate))
+        .filter(|(similarity, _)| *similarity >= 0.8)
+        .max_by(|(a, _), (b, _)| a.total_cmp(b))
+        .map(|(_, candidate)| candidate)
+}
+
+/// Adds a suggestion to serde's "unknown field `voluem`, expected one of `volume`, . . ." errors.
+fn suggest_key(message: &str) -> String {
+    let (kind, rest) = if let Some(rest) = message.strip_prefix("unknown field `") {
+        ("key", rest)
+    } else if let Some(rest) = message.strip_prefix("unknown variant `") {
+        ("value", rest)
+    } else {
+        return message.trim().to_string();
+    };
+    let Some((unknown, expected)) = rest.split_once('`') else { return message.trim().to_string() };
+    // The expected names are between backticks.
+    match similar(unknown, expected.split('`').skip(1).step_by(2)) {
+        Some(name) => format!("Unknown {} \"{}\", did you mean \"{}\"?", kind, unknown, name),
+        None => format!("Unknown {} \"{}\"{}", kind, unknown, expected.replace('`', "\"")),
+    }
+}
+
+/// Environment variables are named like the keys in the config file, with or without the section.
+/// (Like YTDMP_VOLUME or YTDMP_CONFIG_VOLUME, YTDMP_YT_DLP_PATH or YTDMP_PROGRAM_PATHS_YT_DLP_PATH)
+const ENV_PREFIX: &str = "YTDMP_";
+
+fn env_variable_names(key: &str) -> Vec<String> {
+    let name = |key: &str| format!("{}{}", ENV_PREFIX, key.to_uppercase().replace(['-', '.'], "_"));
+    match key.split_once('.') {
+        Some((_, field)) => vec![name(key), name(field)],
+        None => vec![name(key)],
+    }
+}
+
+/// The `ENV_PREFIX` environment variables, as a table like the config file so they're parsed the same way.
+struct ConfigEnv {
+    table: toml::Table,
+    /// Name of the variable each key came from.
+    variables: BTreeMap<&'static str, String>,
+}
+
+impl ConfigEnv {
+    /// Unknown `ENV_PREFIX` variables are printed as a warning.
+    fn read() -> Result<ConfigEnv, ConfigError> {
+        // Values that aren't Unicode can't be parsed anyway.
+        let variables = env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+            .collect::<BTreeMap<_, _>>();
+        let (env, warnings) = ConfigEnv::parse(&variables)?;
+        // Read before logging is set up, like the config file migration.
+        for warning in warnings {
+            eprintln!("{}", warning);
+        }
+        Ok(env)
+    }
+
+    /// Values are TOML, like `0.5`, `true` or `["/music", "/podcasts"]`, anything else is a string.
+    /// Lists of paths may also be like PATH, "/music:/podcasts" or "C:\Music;D:\Podcasts".
+    /// Smart playlists & alarms can only be set in the config file.
+    /// Also returns warnings for unknown `ENV_PREFIX` variables, which are ignored.
+    fn parse(variables: &BTreeMap<String, String>) -> Result<(ConfigEnv, Vec<String>), ConfigError> {
+        let keys = PartialConfig::empty().keys().into_iter()
+            .filter_map(|(key, _)| key.split_once('.').map(|(section, field)| (key, section, field)))
+            .collect::<Vec<_>>();
+
+        let mut table = toml::Table::new();
+        let mut key_variables = BTreeMap::new();
+        for (key, section, field) in &keys {
+            let Some((variable, value)) = env_variable_names(key).into_iter()
+                .find_map(|variable| variables.get(&variable).map(|value| (variable, value))) else { continue };
+            let value = env_value(section, field, value)
+                .map_err(|err| ConfigError::Invalid(format!("Environment variable {}: {}", variable, suggest_key(err.message()))))?;
+            let section = table.entry(section.to_string()).or_insert_with(|| toml::Table::new().into());
+            if let Some(section) = section.as_table_mut() {
+                section.insert(field.to_string(), value);
+            }
+            key_variables.insert(*key, variable);
+        }
+
+        // A typo would silently be ignored otherwise.
+        let known = keys.iter().flat_map(|(key, _, _)| env_variable_names(key)).collect::<Vec<_>>();
+        let warnings = variables.keys()
+            .filter(|variable| variable.starts_with(ENV_PREFIX) && !known.contains(variable))
+            .map(|variable| match similar(variable, known.iter().map(|name| name.as_str())) {
+                Some(name) => format!("Unknown environment variable {} is ignored, did you mean {}?", variable, name),
+                None => format!("Unknown environment variable {} is ignored", variable),
+            })
+            .collect();
+
+        Ok((ConfigEnv { table, variables: key_variables }, warnings))
+    }
+}
+
+/// Parses `value` as TOML if that's valid for the key, otherwise as a string or a list of paths.
+/// (So a playlist ID like "1234" isn't a number.)
+fn env_value(section: &str, field: &str, value: &str) -> Result<toml::Value, toml::de::Error> {
+    let check = |value: &toml::Value| {
+        let table = toml::Table::from_iter([(section.to_string(), toml::Value::from(toml::Table::from_iter([(field.to_string(), value.clone())])))]);
+        toml::Value::from(table).try_into::<TomlConfigParser>().map(|_| ())
+    };
+    let parsed = toml::from_str::<toml::Table>(&format!("value = {}", value)).ok().and_then(|mut table| table.remove("value"));
+    if let Some(parsed) = parsed.filter(|parsed| check(parsed).is_ok()) {
+        return Ok(parsed);
+    }
+    let string = toml::Value::from(value);
+    let err = match check(&string) {
+        Ok(()) => return Ok(string),
+        Err(err) => err,
+    };
+    // Lists of paths may also be separated like PATH.
+    let paths = toml::Value::from(env::split_paths(value).map(|path| path.display().to_string()).collect::<Vec<_>>());
+    check(&paths).map(|_| paths).map_err(|_| err)
+}
+
+/// Sources of the config values, to point errors at where the value was set.
+struct ConfigOrigin {
+    file: Option<ConfigFile>,
+    env: ConfigEnv,
+    sources: BTreeMap<&'static str, ConfigSource>,
+}
+
+impl ConfigOrigin {
+    fn source(&self, key: &str) -> ConfigSource {
+        self.sources.get(key).copied().unwrap_or(ConfigSource::Default)
+    }
+
+    /// `path` is the same as for `ConfigFile::span`.
+    fn error(&self, path: &[&str], message: String) -> ConfigError {
+        let key = match path {
+            [section @ ("config" | "program-paths"), key, ..] => format!("{}.{}", section, key),
+            [key, ..] => key.to_string(),
+            [] => String::new(),
+        };
+        match (self.source(&key), &self.file) {
+            (ConfigSource::File, Some(file)) => file.error_at(file.span(path), message),
+            (ConfigSource::Env, _) => match self.env.variables.get(key.as_str()) {
+                Some(variable) => ConfigError::Invalid(format!("Environment variable {}: {}", variable, message)),
+                None => ConfigError::Invalid(message),
+            },
+            (ConfigSource::Cli, _) => ConfigError::Invalid(format!("Command line: {}", message)),
+            _ => ConfigError::Invalid(message),
+        }
+    }
+}
 
 
 
@@ -28,8 +360,81 @@ impl TomlConfigParserConfigLoudnessNormalization {
 
 
 
+#[derive(Deserialize, Clone, Copy, Debug)]
+enum TomlConfigParserConfigShuffle {
+    Normal,
+    Random,
+    SmartRandom,
+    Weighted,
+}
+
+impl TomlConfigParserConfigShuffle {
+    fn to_final(&self) -> ShuffleMode {
+        match self {
+            TomlConfigParserConfigShuffle::Normal => ShuffleMode::Normal,
+            TomlConfigParserConfigShuffle::Random => ShuffleMode::Random,
+            TomlConfigParserConfigShuffle::SmartRandom => ShuffleMode::SmartRandom,
+            TomlConfigParserConfigShuffle::Weighted => ShuffleMode::Weighted,
+        }
+    }
+}
+
+
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+enum TomlConfigParserConfigSleepAction {
+    Pause,
+    Exit,
+}
+
+impl TomlConfigParserConfigSleepAction {
+    fn to_final(&self) -> SleepAction {
+        match self {
+            TomlConfigParserConfigSleepAction::Pause => SleepAction::Pause,
+            TomlConfigParserConfigSleepAction::Exit => SleepAction::Exit,
+        }
+    }
+}
+
+
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+enum TomlConfigParserConfigMissedAlarms {
+    Skip,
+    Play,
+}
+
+impl TomlConfigParserConfigMissedAlarms {
+    fn to_final(&self) -> MissedAlarms {
+        match self {
+            TomlConfigParserConfigMissedAlarms::Skip => MissedAlarms::Skip,
+            TomlConfigParserConfigMissedAlarms::Play => MissedAlarms::Play,
+        }
+    }
+}
+
+
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+enum TomlConfigParserConfigLogFormat {
+    Text,
+    Json,
+}
+
+impl TomlConfigParserConfigLogFormat {
+    fn to_final(&self) -> LogFormat {
+        match self {
+            TomlConfigParserConfigLogFormat::Text => LogFormat::Text,
+            TomlConfigParserConfigLogFormat::Json => LogFormat::Json,
+        }
+    }
+}
+
+
+
 // I cannot get serde_flat_path to work, so we have to deal with multiple structs for now. . .
 #[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 struct TomlConfigParserProgramPaths {
     #[serde(rename = "yt-dlp-path")]
     yt_dlp_path: Option<PathBuf>,
@@ -38,25 +443,77 @@ struct TomlConfigParserProgramPaths {
 }
 
 #[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 struct TomlConfigParserConfig {
     #[serde(rename="yt-playlist")]
     yt_playlist: Option<String>,
-    #[serde(rename="skip_playlist_update")]
+    #[serde(rename="local-directories")]
+    local_directories: Option<Vec<PathBuf>>,
+    #[serde(rename="skip-playlist-update")]
     skip_playlist_update: Option<bool>,
     volume: Option<f64>,
     #[serde(rename="loudness-normalization")]
     loudness_normalization: Option<TomlConfigParserConfigLoudnessNormalization>,
+    shuffle: Option<TomlConfigParserConfigShuffle>,
+    #[serde(rename="freshness-boost")]
+    freshness_boost: Option<f64>,
+    #[serde(rename="freshness-days")]
+    freshness_days: Option<f64>,
+    #[serde(rename="favorites-only")]
+    favorites_only: Option<bool>,
+    #[serde(rename="playlist-files")]
+    playlist_files: Option<Vec<PathBuf>>,
+    playlist: Option<String>,
+    notifications: Option<bool>,
+    #[serde(rename="sleep-action")]
+    sleep_action: Option<TomlConfigParserConfigSleepAction>,
+    #[serde(rename="missed-alarms")]
+    missed_alarms: Option<TomlConfigParserConfigMissedAlarms>,
     #[serde(rename="start-paused")]
     start_paused: Option<bool>,
     #[serde(rename="hide-console")]
     hide_console: Option<bool>,
+    #[serde(rename="sync-interval")]
+    sync_interval: Option<u64>,
+    #[serde(rename="output-device")]
+    output_device: Option<String>,
+    #[serde(rename="ipc-port")]
+    ipc_port: Option<u16>,
+    #[serde(rename="log-level")]
+    log_level: Option<String>,
+    #[serde(rename="log-format")]
+    log_format: Option<TomlConfigParserConfigLogFormat>,
+    #[serde(rename="log-file")]
+    log_file: Option<bool>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct TomlConfigParserSmartPlaylist {
+    name: String,
+    rules: Vec<String>,
 }
 
 #[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct TomlConfigParserAlarm {
+    time: String,
+    days: Option<Vec<String>>,
+    playlist: Option<String>,
+    volume: Option<f64>,
+    ramp: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 struct TomlConfigParser {
     #[serde(rename="program-paths")]
     program_paths: Option<TomlConfigParserProgramPaths>,
     config: Option<TomlConfigParserConfig>,
+    #[serde(rename="smart-playlist")]
+    smart_playlists: Option<Vec<TomlConfigParserSmartPlaylist>>,
+    #[serde(rename="alarm")]
+    alarms: Option<Vec<TomlConfigParserAlarm>>,
 }
 
 
@@ -78,41 +535,259 @@ impl CliConfigParserLoudnessNormalization {
     }
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum CliConfigParserShuffle {
+    Normal,
+    Random,
+    SmartRandom,
+    Weighted,
+}
+
+impl CliConfigParserShuffle {
+    fn to_final(&self) -> ShuffleMode {
+        match self {
+            CliConfigParserShuffle::Normal => ShuffleMode::Normal,
+            CliConfigParserShuffle::Random => ShuffleMode::Random,
+            CliConfigParserShuffle::SmartRandom => ShuffleMode::SmartRandom,
+            CliConfigParserShuffle::Weighted => ShuffleMode::Weighted,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum CliConfigParserSleepAction {
+    Pause,
+    Exit,
+}
+
+impl CliConfigParserSleepAction {
+    fn to_final(&self) -> SleepAction {
+        match self {
+            CliConfigParserSleepAction::Pause => SleepAction::Pause,
+            CliConfigParserSleepAction::Exit => SleepAction::Exit,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum CliConfigParserMissedAlarms {
+    Skip,
+    Play,
+}
+
+impl CliConfigParserMissedAlarms {
+    fn to_final(&self) -> MissedAlarms {
+        match self {
+            CliConfigParserMissedAlarms::Skip => MissedAlarms::Skip,
+            CliConfigParserMissedAlarms::Play => MissedAlarms::Play,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum CliConfigParserLogFormat {
+    Text,
+    Json,
+}
+
+impl CliConfigParserLogFormat {
+    fn to_final(&self) -> LogFormat {
+        match self {
+            CliConfigParserLogFormat::Text => LogFormat::Text,
+            CliConfigParserLogFormat::Json => LogFormat::Json,
+        }
+    }
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Print listening statistics.
+    Stats {
+        #[arg(short, long, value_enum, default_value_t = StatsFormat::Table)]
+        format: StatsFormat,
+        /// Amount of songs in the top & most skipped lists.
+        #[arg(short = 'n', long, default_value_t = 10)]
+        limit: usize,
+    },
+    /// Print the weight of every song used by the weighted shuffle.
+    Weights,
+    /// Decode every song to find files that can't be played, they're skipped from then on.
+    Verify,
+    /// List the audio output devices, for the output-device config.
+    ListDevices,
+    /// Commands for the config file.
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Commands sent to the running player.
+    #[command(flatten)]
+    Ipc(IpcCommand),
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ConfigCommand {
+    /// Check the config, and print every value with where it came from. (Default, file, environment or command line)
+    Check,
+    /// Write a config file with every value & comments explaining them.
+    Init {
+        /// Overwrite the config file if there already is one.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Set a value in the config file, keeping its comments & formatting.
+    Set {
+        /// Like "volume", or "config.volume" with the section.
+        key: String,
+        /// Like 0.5, true or ["/music", "/podcasts"], anything else is text. Lists of paths may also be separated like PATH.
+        value: String,
+    },
+}
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 #[command(propagate_version = true)]
-#[command(group = ArgGroup::new("all_args").required(false).args(&["yt_dlp_path", "ffmpeg_path"]))]
 struct CliConfigParser {
-    #[arg(index = 1, group = "all_args")]
+    /// Config file to use instead of the one in the config directory.
+    #[arg(long = "config", global = true)]
+    config_file: Option<PathBuf>,
+    /// yt-dlp program, found on the PATH by default.
+    #[arg(long)]
     yt_dlp_path: Option<PathBuf>,
-    #[arg(index = 2, group = "all_args")]
+    /// ffmpeg program or directory, found on the PATH by default.
+    #[arg(long)]
     ffmpeg_path: Option<PathBuf>,
     #[arg(short = 'p', long)]
     yt_playlist: Option<String>,
-    #[arg(short, long)]
-    skip_playlist_update: Option<bool>,
+    /// Local directory to play songs from, may be used multiple times.
+    #[arg(short = 'd', long = "local-directory")]
+    local_directories: Option<Vec<PathBuf>>,
+    /// Don't download the newly added videos of the playlist.
+    #[arg(short, long, overrides_with = "no_skip_playlist_update")]
+    skip_playlist_update: bool,
+    /// Download the newly added videos of the playlist.
+    #[arg(long, overrides_with = "skip_playlist_update")]
+    no_skip_playlist_update: bool,
     #[arg(short, long)]
     volume: Option<f64>,
     #[arg(short, long)]
     loudness_normalization: Option<CliConfigParserLoudnessNormalization>,
-    #[arg(short='a', long)]
-    start_paused: Option<bool>,
-    #[arg(short='c', long)]
-    hide_console: Option<bool>,
+    #[arg(long)]
+    shuffle: Option<CliConfigParserShuffle>,
+    #[arg(long)]
+    freshness_boost: Option<f64>,
+    #[arg(long)]
+    freshness_days: Option<f64>,
+    /// Only play favorite songs.
+    #[arg(long, overrides_with = "no_favorites_only")]
+    favorites_only: bool,
+    /// Play all songs, not only favorites.
+    #[arg(long, overrides_with = "favorites_only")]
+    no_favorites_only: bool,
+    /// M3U8 or XSPF playlist file to import, may be used multiple times.
+    #[arg(long = "playlist-file")]
+    playlist_files: Option<Vec<PathBuf>>,
+    /// Name of the playlist to play, "Library", the name of a smart playlist, or the file name of a playlist file.
+    #[arg(long)]
+    playlist: Option<String>,
+    /// Desktop notification on song change. (Linux only)
+    #[arg(long, overrides_with = "no_notifications")]
+    notifications: bool,
+    /// No desktop notifications.
+    #[arg(long, overrides_with = "notifications")]
+    no_notifications: bool,
+    /// What happens when the sleep timer ends.
+    #[arg(long)]
+    sleep_action: Option<CliConfigParserSleepAction>,
+    /// Play alarms that were missed while the system was suspended, or skip them.
+    #[arg(long)]
+    missed_alarms: Option<CliConfigParserMissedAlarms>,
+    /// Start paused.
+    #[arg(short = 'a', long, overrides_with = "no_start_paused")]
+    start_paused: bool,
+    /// Start playing right away.
+    #[arg(long, overrides_with = "start_paused")]
+    no_start_paused: bool,
+    /// Hide the console window. (Windows only)
+    #[arg(short = 'c', long, overrides_with = "no_hide_console")]
+    hide_console: bool,
+    /// Keep the console window.
+    #[arg(long, overrides_with = "hide_console")]
+    no_hide_console: bool,
+    /// Seconds between checks for new songs in the local directories & downloaded playlist.
+    #[arg(long)]
+    sync_interval: Option<u64>,
+    /// Name of the audio output device, see "list-devices". The default device is used while it isn't there.
+    #[arg(long)]
+    output_device: Option<String>,
+    #[arg(long)]
+    ipc_port: Option<u16>,
+    /// Like "info" or "warn,yt_dlp_music_player::source=debug", RUST_LOG overrides this.
+    #[arg(long)]
+    log_level: Option<String>,
+    #[arg(long)]
+    log_format: Option<CliConfigParserLogFormat>,
+    /// Write a daily rotated log file in the state directory.
+    #[arg(long, overrides_with = "no_log_file")]
+    log_file: bool,
+    /// Only log to the console.
+    #[arg(long, overrides_with = "log_file")]
+    no_log_file: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+
+
+/// A `--flag` & `--no-flag` pair, None if neither is used. (The last one used overrides the other.)
+fn flag(on: bool, off: bool) -> Option<bool> {
+    match (on, off) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
 }
 
+/// Relative paths in the config file are relative to the directory of the config file, not the working directory.
+fn relative_to(directory: &Path, paths: &[PathBuf]) -> Vec<PathBuf> {
+    paths.iter().map(|path| directory.join(path)).collect()
+}
 
+/// Like `relative_to`, but plain program names (like "yt-dlp") are kept, so they're found on the PATH.
+fn program_relative_to(directory: &Path, program: &Path) -> PathBuf {
+    if is_program_name(program) {
+        program.to_path_buf()
+    } else {
+        relative_to(directory, &[program.to_path_buf()]).remove(0)
+    }
+}
 
 #[derive(Debug, Default)]
 struct PartialConfig {
     yt_dlp_path: Option<PathBuf>,
     ffmpeg_path: Option<PathBuf>,
     yt_playlist: Option<String>,
+    local_directories: Option<Vec<PathBuf>>,
     skip_playlist_update: Option<bool>,
     volume: Option<f64>,
     loudness_normalization: Option<LoudnessNormalization>,
+    shuffle: Option<ShuffleMode>,
+    freshness_boost: Option<f64>,
+    freshness_days: Option<f64>,
+    favorites_only: Option<bool>,
+    smart_playlists: Option<Vec<SmartPlaylist>>,
+    playlist_files: Option<Vec<PathBuf>>,
+    playlist: Option<String>,
+    notifications: Option<bool>,
+    sleep_action: Option<SleepAction>,
+    alarms: Option<Vec<Alarm>>,
+    missed_alarms: Option<MissedAlarms>,
     start_paused: Option<bool>,
     hide_console: Option<bool>,
+    sync_interval: Option<u64>,
+    output_device: Option<String>,
+    ipc_port: Option<u16>,
+    log_level: Option<String>,
+    log_format: Option<LogFormat>,
+    log_file: Option<bool>,
 }
 
 impl PartialConfig {
@@ -120,45 +795,166 @@ impl PartialConfig {
         PartialConfig::default()
     }
 
+    /// Keys of the values that are set, like they're named in the config file.
+    fn set_keys(&self) -> Vec<&'static str> {
+        self.keys().into_iter().filter(|(_, set)| *set).map(|(key, _)| key).collect()
+    }
+
+    /// Every key, with if it's set.
+    fn keys(&self) -> Vec<(&'static str, bool)> {
+        vec![
+            ("program-paths.yt-dlp-path", self.yt_dlp_path.is_some()),
+            ("program-paths.ffmpeg-path", self.ffmpeg_path.is_some()),
+            ("config.yt-playlist", self.yt_playlist.is_some()),
+            ("config.local-directories", self.local_directories.is_some()),
+            ("config.skip-playlist-update", self.skip_playlist_update.is_some()),
+            ("config.volume", self.volume.is_some()),
+            ("config.loudness-normalization", self.loudness_normalization.is_some()),
+            ("config.shuffle", self.shuffle.is_some()),
+            ("config.freshness-boost", self.freshness_boost.is_some()),
+            ("config.freshness-days", self.freshness_days.is_some()),
+            ("config.favorites-only", self.favorites_only.is_some()),
+            ("config.playlist-files", self.playlist_files.is_some()),
+            ("config.playlist", self.playlist.is_some()),
+            ("config.notifications", self.notifications.is_some()),
+            ("config.sleep-action", self.sleep_action.is_some()),
+            ("config.missed-alarms", self.missed_alarms.is_some()),
+            ("config.start-paused", self.start_paused.is_some()),
+            ("config.hide-console", self.hide_console.is_some()),
+            ("config.sync-interval", self.sync_interval.is_some()),
+            ("config.output-device", self.output_device.is_some()),
+            ("config.ipc-port", self.ipc_port.is_some()),
+            ("config.log-level", self.log_level.is_some()),
+            ("config.log-format", self.log_format.is_some()),
+            ("config.log-file", self.log_file.is_some()),
+            ("smart-playlist", self.smart_playlists.is_some()),
+            ("alarm", self.alarms.is_some()),
+        ]
+    }
+
     pub fn merge(a: PartialConfig, b: PartialConfig) -> PartialConfig {
         PartialConfig {
             yt_dlp_path: a.yt_dlp_path.or(b.yt_dlp_path),
             ffmpeg_path: a.ffmpeg_path.or(b.ffmpeg_path),
             yt_playlist: a.yt_playlist.or(b.yt_playlist),
+            local_directories: a.local_directories.or(b.local_directories),
             skip_playlist_update: a.skip_playlist_update.or(b.skip_playlist_update),
             volume: a.volume.or(b.volume),
             loudness_normalization: a.loudness_normalization.or(b.loudness_normalization),
+            shuffle: a.shuffle.or(b.shuffle),
+            freshness_boost: a.freshness_boost.or(b.freshness_boost),
+            freshness_days: a.freshness_days.or(b.freshness_days),
+            favorites_only: a.favorites_only.or(b.favorites_only),
+            smart_playlists: a.smart_playlists.or(b.smart_playlists),
+            playlist_files: a.playlist_files.or(b.playlist_files),
+            playlist: a.playlist.or(b.playlist),
+            notifications: a.notifications.or(b.notifications),
+            sleep_action: a.sleep_action.or(b.sleep_action),
+            alarms: a.alarms.or(b.alarms),
+            missed_alarms: a.missed_alarms.or(b.missed_alarms),
             start_paused: a.start_paused.or(b.start_paused),
             hide_console: a.hide_console.or(b.hide_console),
+            sync_interval: a.sync_interval.or(b.sync_interval),
+            output_device: a.output_device.or(b.output_device),
+            ipc_port: a.ipc_port.or(b.ipc_port),
+            log_level: a.log_level.or(b.log_level),
+            log_format: a.log_format.or(b.log_format),
+            log_file: a.log_file.or(b.log_file),
         }
     }
 
-    pub fn from_config_file(config_file: &PathBuf) -> Result<PartialConfig> {
-        let config: TomlConfigParser = toml::from_str(&fs::read_to_string(&config_file)?)?;
+    fn from_config_file(file: &ConfigFile) -> Result<PartialConfig, ConfigError> {
+        let config: TomlConfigParser = toml::from_str(&file.text).map_err(|err| file.toml_error(err))?;
+        let directory = file.path.parent().unwrap_or(Path::new("."));
+        PartialConfig::from_toml(config, directory, |path, message| file.error_at(file.span(path), message))
+    }
+
+    /// Relative paths are relative to the working directory, like on the command line.
+    fn from_env(env: &ConfigEnv) -> Result<PartialConfig, ConfigError> {
+        let config: TomlConfigParser = toml::Value::from(env.table.clone()).try_into()
+            .map_err(|err| ConfigError::Invalid(format!("Environment variables: {}", suggest_key(err.message()))))?;
+        PartialConfig::from_toml(config, Path::new(""), |_, message| ConfigError::Invalid(message))
+    }
+
+    /// `error` makes an error for the value at a path like in `ConfigFile::span`.
+    fn from_toml(config: TomlConfigParser, directory: &Path, error: impl Fn(&[&str], String) -> ConfigError) -> Result<PartialConfig, ConfigError> {
+        let smart_playlists = match &config.smart_playlists {
+            Some(smart_playlists) => Some(smart_playlists.iter().enumerate()
+                .map(|(i, p)| SmartPlaylist::parse(&p.name, &p.rules).map_err(|err| error(&["smart-playlist", &i.to_string()], err)))
+                .collect::<Result<Vec<_>, _>>()?),
+            None => None,
+        };
+        let alarms = match &config.alarms {
+            Some(alarms) => Some(alarms.iter().enumerate()
+                .map(|(i, a)| Alarm::parse(&a.time, a.days.as_deref().unwrap_or_default(), a.playlist.clone(), a.volume, a.ramp.as_deref())
+                    .map_err(|err| error(&["alarm", &i.to_string()], err)))
+                .collect::<Result<Vec<_>, _>>()?),
+            None => None,
+        };
         Ok(PartialConfig {
-            yt_dlp_path: config.program_paths.as_ref().and_then(|c| c.yt_dlp_path.clone()),
-            ffmpeg_path: config.program_paths.as_ref().and_then(|c| c.ffmpeg_path.clone()),
+            yt_dlp_path: config.program_paths.as_ref().and_then(|c| c.yt_dlp_path.as_ref().map(|p| program_relative_to(directory, p))),
+            ffmpeg_path: config.program_paths.as_ref().and_then(|c| c.ffmpeg_path.as_ref().map(|p| program_relative_to(directory, p))),
             yt_playlist: config.config.as_ref().and_then(|c| c.yt_playlist.clone()),
+            local_directories: config.config.as_ref().and_then(|c| c.local_directories.as_ref().map(|d| relative_to(directory, d))),
             skip_playlist_update: config.config.as_ref().and_then(|c| c.skip_playlist_update.clone()),
             volume: config.config.as_ref().and_then(|c| c.volume.clone()),
             loudness_normalization: config.config.as_ref().and_then(|c| c.loudness_normalization.map(|l| l.to_final())),
+            shuffle: config.config.as_ref().and_then(|c| c.shuffle.map(|s| s.to_final())),
+            freshness_boost: config.config.as_ref().and_then(|c| c.freshness_boost),
+            freshness_days: config.config.as_ref().and_then(|c| c.freshness_days),
+            favorites_only: config.config.as_ref().and_then(|c| c.favorites_only),
+            smart_playlists,
+            playlist_files: config.config.as_ref().and_then(|c| c.playlist_files.as_ref().map(|f| relative_to(directory, f))),
+            playlist: config.config.as_ref().and_then(|c| c.playlist.clone()),
+            notifications: config.config.as_ref().and_then(|c| c.notifications),
+            sleep_action: config.config.as_ref().and_then(|c| c.sleep_action.map(|s| s.to_final())),
+            alarms,
+            missed_alarms: config.config.as_ref().and_then(|c| c.missed_alarms.map(|m| m.to_final())),
             start_paused: config.config.as_ref().and_then(|c| c.start_paused),
             hide_console: config.config.as_ref().and_then(|c| c.hide_console),
+            sync_interval: config.config.as_ref().and_then(|c| c.sync_interval),
+            output_device: config.config.as_ref().and_then(|c| c.output_device.clone()),
+            ipc_port: config.config.as_ref().and_then(|c| c.ipc_port),
+            log_level: config.config.as_ref().and_then(|c| c.log_level.clone()),
+            log_format: config.config.as_ref().and_then(|c| c.log_format.map(|l| l.to_final())),
+            log_file: config.config.as_ref().and_then(|c| c.log_file),
         })
     }
 
-    pub fn from_cli_args() -> PartialConfig {
-        let config: CliConfigParser = CliConfigParser::parse();
-        PartialConfig {
+    /// Also returns the `--config` file.
+    pub fn from_cli_args() -> (PartialConfig, Option<Command>, Option<PathBuf>) {
+        PartialConfig::from_cli(CliConfigParser::parse())
+    }
+
+    fn from_cli(config: CliConfigParser) -> (PartialConfig, Option<Command>, Option<PathBuf>) {
+        (PartialConfig {
             yt_dlp_path: config.yt_dlp_path,
             ffmpeg_path: config.ffmpeg_path,
             yt_playlist: config.yt_playlist,
-            skip_playlist_update: config.skip_playlist_update,
+            local_directories: config.local_directories,
+            skip_playlist_update: flag(config.skip_playlist_update, config.no_skip_playlist_update),
             volume: config.volume,
             loudness_normalization: config.loudness_normalization.map(|l| l.to_final()),
-            start_paused: config.start_paused,
-            hide_console: config.hide_console
-        }
+            shuffle: config.shuffle.map(|s| s.to_final()),
+            freshness_boost: config.freshness_boost,
+            freshness_days: config.freshness_days,
+            favorites_only: flag(config.favorites_only, config.no_favorites_only),
+            smart_playlists: None,
+            playlist_files: config.playlist_files,
+            playlist: config.playlist,
+            notifications: flag(config.notifications, config.no_notifications),
+            sleep_action: config.sleep_action.map(|s| s.to_final()),
+            alarms: None,
+            missed_alarms: config.missed_alarms.map(|m| m.to_final()),
+            start_paused: flag(config.start_paused, config.no_start_paused),
+            hide_console: flag(config.hide_console, config.no_hide_console),
+            sync_interval: config.sync_interval,
+            output_device: config.output_device,
+            ipc_port: config.ipc_port,
+            log_level: config.log_level,
+            log_format: config.log_format.map(|l| l.to_final()),
+            log_file: flag(config.log_file, config.no_log_file),
+        }, config.command, config.config_file)
     }
 }
 
@@ -168,37 +964,386 @@ impl PartialConfig {
 pub struct Config {
     pub yt_dlp_path: PathBuf,
     pub ffmpeg_path: PathBuf,
-    pub yt_playlist: String,
+    pub yt_playlist: Option<String>,
+    pub local_directories: Vec<PathBuf>,
     pub skip_playlist_update: bool,
     pub volume: f64,
     pub loudness_normalization: LoudnessNormalization,
+    pub shuffle: ShuffleMode,
+    pub weight_options: WeightOptions,
+    pub favorites_only: bool,
+    pub smart_playlists: Vec<SmartPlaylist>,
+    /// Imported M3U8/XSPF files, named by `playlist_file_name`.
+    pub playlist_files: Vec<PathBuf>,
+    /// Name of the playlist to play, `LIBRARY_PLAYLIST`, a smart playlist, or a playlist file.
+    pub playlist: String,
+    /// Desktop notification on song change, only on Linux.
+    pub notifications: bool,
+    /// What happens when the sleep timer ends, unless set for the timer itself.
+    pub sleep_action: SleepAction,
+    pub alarms: Vec<Alarm>,
+    pub missed_alarms: MissedAlarms,
     pub start_paused: bool,
     pub hide_console: bool,
+    /// How often the sources are checked for new songs.
+    pub sync_interval: Duration,
+    /// Name of the audio output device, None for the default device.
+    pub output_device: Option<String>,
+    /// 0 disables IPC, it's off unless a port is set.
+    pub ipc_port: u16,
+    /// `EnvFilter` directives.
+    pub log_level: String,
+    pub log_format: LogFormat,
+    pub log_file: bool,
+    /// None if there's no config file.
+    pub config_file: Option<PathBuf>,
+    /// Keyed like in the config file, values that aren't in here are defaults.
+    pub sources: BTreeMap<&'static str, ConfigSource>,
+    pub command: Option<Command>,
 }
 
 impl Config {
-    pub fn load() -> Result<Config> {
-        let config: PartialConfig = PartialConfig::empty();
+    pub fn load() -> Result<Config, ConfigError> {
+        let (cli_config, command, cli_config_file) = PartialConfig::from_cli_args();
 
-        #[cfg(debug_assertions)]
-        let config_file = PathBuf::from("./target/debug/config.toml");
-        #[cfg(not(debug_assertions))]
-        let config_file = PathBuf::from("./config.toml");
-        let config = PartialConfig::merge(PartialConfig::from_config_file(&config_file)?, config);
+        let config_file = match cli_config_file {
+            Some(config_file) => Some(config_file),
+            None => {
+                let config_file = migrated_config_file();
+                // Everything may be set by the CLI instead.
+                config_file.exists().then_some(config_file)
+            },
+        };
+        let file = match &config_file {
+            Some(config_file) => Some(ConfigFile::read(config_file)?),
+            None => None,
+        };
+        Config::from_layers(file, config_file, ConfigEnv::read()?, cli_config, command, true)
+    }
 
-        let config = PartialConfig::merge(PartialConfig::from_cli_args(), config);
+    /// Merges the file, environment & CLI configs, and validates the result.
+    /// Without `require_source` a config without yt-playlist & local-directories is valid, so `config set` can set one key at a time.
+    fn from_layers(file: Option<ConfigFile>, config_file: Option<PathBuf>, env: ConfigEnv, cli_config: PartialConfig, command: Option<Command>, require_source: bool) -> Result<Config, ConfigError> {
+        let mut config: PartialConfig = PartialConfig::empty();
+        let mut sources = BTreeMap::new();
+        if let Some(file) = &file {
+            let file_config = PartialConfig::from_config_file(file)?;
+            sources.extend(file_config.set_keys().into_iter().map(|key| (key, ConfigSource::File)));
+            config = PartialConfig::merge(file_config, config);
+        }
+        let env_config = PartialConfig::from_env(&env)?;
+        sources.extend(env_config.set_keys().into_iter().map(|key| (key, ConfigSource::Env)));
+        config = PartialConfig::merge(env_config, config);
+        sources.extend(cli_config.set_keys().into_iter().map(|key| (key, ConfigSource::Cli)));
+        let config = PartialConfig::merge(cli_config, config);
+        let origin = ConfigOrigin { file, env, sources };
 
-        Ok(Config {
-            yt_dlp_path: config.yt_dlp_path.expect("CLI or Config must have yt_dlp_path set"),
-            ffmpeg_path: config.ffmpeg_path.expect("CLI or Config must have ffmpeg_path set"),
-            yt_playlist: config.yt_playlist.expect("CLI or Config must have yt_playlist set"),
+        // Empty string is the same as not set, that's what the default config.toml has.
+        let yt_playlist = config.yt_playlist.filter(|p| !p.is_empty());
+        let local_directories = config.local_directories.unwrap_or_default();
+        if require_source && yt_playlist.is_none() && local_directories.is_empty() {
+            return Err(ConfigError::Invalid("CLI or Config must have yt-playlist or local-directories set".to_string()));
+        }
+
+        let smart_playlists = config.smart_playlists.unwrap_or_default();
+        let playlist_files = config.playlist_files.unwrap_or_default();
+        let playlist_names = std::iter::once(LIBRARY_PLAYLIST.to_string())
+            .chain(smart_playlists.iter().map(|p| p.name.clone()))
+            .chain(playlist_files.iter().map(|f| playlist_file_name(f)))
+            .collect::<Vec<_>>();
+        for (i, name) in playlist_names.iter().enumerate() {
+            if playlist_names[..i].iter().any(|n| n.eq_ignore_ascii_case(name)) {
+                return Err(ConfigError::Invalid(format!("Multiple playlists are named \"{}\" (\"{}\" is the playlist of all songs, playlist files are named by their file name)", name, LIBRARY_PLAYLIST)));
+            }
+        }
+        // Empty string is the same as not set, like yt_playlist.
+        let playlist = config.playlist.filter(|p| !p.is_empty()).unwrap_or(LIBRARY_PLAYLIST.to_string());
+        if !playlist_names.iter().any(|n| n.eq_ignore_ascii_case(&playlist)) {
+            return Err(origin.error(&["config", "playlist"], format!("Playlist \"{}\" doesn't exist, expected one of: {}", playlist, playlist_names.join(", "))));
+        }
+        let alarms = config.alarms.unwrap_or_default();
+        for (i, alarm) in alarms.iter().enumerate() {
+            let Some(alarm_playlist) = &alarm.playlist else { continue };
+            if !playlist_names.iter().any(|n| n.eq_ignore_ascii_case(alarm_playlist)) {
+                return Err(origin.error(&["alarm", &i.to_string(), "playlist"], format!("Alarm playlist \"{}\" doesn't exist, expected one of: {}", alarm_playlist, playlist_names.join(", "))));
+            }
+        }
+
+        let loudness_normalization = config.loudness_normalization.unwrap_or(LoudnessNormalization::RMS);
+        if loudness_normalization == LoudnessNormalization::EbuR128 {
+            return Err(origin.error(&["config", "loudness-normalization"], "EbuR128 loudness-normalization isn't implemented yet, use RMS or None".to_string()));
+        }
+
+        let config = Config {
+            // Found on the PATH if not set.
+            yt_dlp_path: config.yt_dlp_path.filter(|p| !p.as_os_str().is_empty()).unwrap_or(PathBuf::from("yt-dlp")),
+            ffmpeg_path: config.ffmpeg_path.filter(|p| !p.as_os_str().is_empty()).unwrap_or(PathBuf::from("ffmpeg")),
+            yt_playlist,
+            local_directories,
             skip_playlist_update: config.skip_playlist_update.unwrap_or(false),
             volume: config.volume.unwrap_or(0.5),
-            loudness_normalization: config.loudness_normalization.unwrap_or(LoudnessNormalization::RMS),
+            loudness_normalization,
+            shuffle: config.shuffle.unwrap_or(ShuffleMode::SmartRandom),
+            weight_options: WeightOptions {
+                freshness_boost: config.freshness_boost.unwrap_or(2.0),
+                freshness_days: config.freshness_days.unwrap_or(14.0),
+            },
+            favorites_only: config.favorites_only.unwrap_or(false),
+            smart_playlists,
+            playlist_files,
+            playlist,
+            notifications: config.notifications.unwrap_or(true),
+            sleep_action: config.sleep_action.unwrap_or(SleepAction::Pause),
+            alarms,
+            missed_alarms: config.missed_alarms.unwrap_or(MissedAlarms::Skip),
             start_paused: config.start_paused.unwrap_or(false),
             hide_console: config.hide_console.unwrap_or(true),
-        })
+            sync_interval: Duration::from_secs(config.sync_interval.unwrap_or(10)),
+            // Empty string is the same as not set, like yt_playlist.
+            output_device: config.output_device.filter(|d| !d.is_empty()),
+            ipc_port: config.ipc_port.unwrap_or(0),
+            log_level: config.log_level.unwrap_or("warn,yt_dlp_music_player=info".to_string()),
+            log_format: config.log_format.unwrap_or(LogFormat::Text),
+            log_file: config.log_file.unwrap_or(true),
+            config_file,
+            sources: origin.sources.clone(),
+            command,
+        };
+        config.validate(&origin)?;
+        Ok(config)
+    }
+
+    /// Checks the ranges & paths, the types are already checked by the parsers.
+    fn validate(&self, origin: &ConfigOrigin) -> Result<(), ConfigError> {
+        if !(0.0..=1.0).contains(&self.volume) {
+            return Err(origin.error(&["config", "volume"], format!("Volume {} is invalid, expected from 0 to 1", self.volume)));
+        }
+        if self.weight_options.freshness_boost < 0.0 {
+            return Err(origin.error(&["config", "freshness-boost"], format!("Freshness boost {} is invalid, expected 0 or more", self.weight_options.freshness_boost)));
+        }
+        if self.weight_options.freshness_days <= 0.0 {
+            return Err(origin.error(&["config", "freshness-days"], format!("Freshness days {} is invalid, expected more than 0", self.weight_options.freshness_days)));
+        }
+        if self.sync_interval.is_zero() {
+            return Err(origin.error(&["config", "sync-interval"], "Sync interval 0 is invalid, expected 1 second or more".to_string()));
+        }
+        for (i, directory) in self.local_directories.iter().enumerate() {
+            if !directory.is_dir() {
+                return Err(origin.error(&["config", "local-directories", &i.to_string()], format!("Local directory {:?} doesn't exist", directory)));
+            }
+        }
+        for (i, file) in self.playlist_files.iter().enumerate() {
+            if !file.is_file() {
+                return Err(origin.error(&["config", "playlist-files", &i.to_string()], format!("Playlist file {:?} doesn't exist", file)));
+            }
+        }
+        // Plain program names are looked up on the PATH when they're run. (ffmpeg-path may be a directory.)
+        for (key, program) in [("yt-dlp-path", &self.yt_dlp_path), ("ffmpeg-path", &self.ffmpeg_path)] {
+            if !is_program_name(program) && !program.exists() {
+                return Err(origin.error(&["program-paths", key], format!("{} {:?} doesn't exist", key, program)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Every value with its key in the config file, formatted as TOML.
+    fn values(&self) -> Vec<(&'static str, toml::Value)> {
+        let path = |path: &PathBuf| toml::Value::from(path.display().to_string());
+        let paths = |paths: &[PathBuf]| toml::Value::from(paths.iter().map(path).collect::<Vec<_>>());
+        vec![
+            ("program-paths.yt-dlp-path", path(&self.yt_dlp_path)),
+            ("program-paths.ffmpeg-path", path(&self.ffmpeg_path)),
+            ("config.yt-playlist", toml::Value::from(self.yt_playlist.clone().unwrap_or_default())),
+            ("config.local-directories", paths(&self.local_directories)),
+            ("config.skip-playlist-update", toml::Value::from(self.skip_playlist_update)),
+            ("config.volume", toml::Value::from(self.volume)),
+            ("config.loudness-normalization", toml::Value::from(format!("{:?}", self.loudness_normalization))),
+            ("config.shuffle", toml::Value::from(format!("{:?}", self.shuffle))),
+            ("config.freshness-boost", toml::Value::from(self.weight_options.freshness_boost)),
+            ("config.freshness-days", toml::Value::from(self.weight_options.freshness_days)),
+            ("config.favorites-only", toml::Value::from(self.favorites_only)),
+            ("config.playlist-files", paths(&self.playlist_files)),
+            ("config.playlist", toml::Value::from(self.playlist.clone())),
+            ("config.notifications", toml::Value::from(self.notifications)),
+            ("config.sleep-action", toml::Value::from(format!("{:?}", self.sleep_action))),
+            ("config.missed-alarms", toml::Value::from(format!("{:?}", self.missed_alarms))),
+            ("config.start-paused", toml::Value::from(self.start_paused)),
+            ("config.hide-console", toml::Value::from(self.hide_console)),
+            ("config.sync-interval", toml::Value::from(self.sync_interval.as_secs() as i64)),
+            ("config.output-device", toml::Value::from(self.output_device.clone().unwrap_or_default())),
+            ("config.ipc-port", toml::Value::from(self.ipc_port as i64)),
+            ("config.log-level", toml::Value::from(self.log_level.clone())),
+            ("config.log-format", toml::Value::from(format!("{:?}", self.log_format))),
+            ("config.log-file", toml::Value::from(self.log_file)),
+            ("smart-playlist", toml::Value::from(self.smart_playlists.iter().map(|p| p.name.clone()).collect::<Vec<_>>())),
+            ("alarm", toml::Value::from(self.alarms.iter().map(|a| a.time.format("%H:%M").to_string()).collect::<Vec<_>>())),
+        ]
     }
+
+    /// Keys of the values that are different in `other`, like they're named in the config file.
+    pub fn changed_keys(&self, other: &Config) -> Vec<&'static str> {
+        self.values().into_iter().zip(other.values())
+            .filter(|((key, value), (_, other_value))| match *key {
+                // Only the names & times are in the values.
+                "smart-playlist" => self.smart_playlists != other.smart_playlists,
+                "alarm" => self.alarms != other.alarms,
+                _ => value != other_value,
+            })
+            .map(|((key, _), _)| key)
+            .collect()
+    }
+
+    /// Prints every value with where it came from, for the "config check" command.
+    pub fn print_effective(&self) {
+        match &self.config_file {
+            Some(config_file) => println!("# Config file: {}", config_file.display()),
+            None => println!("# No config file, expected at {}", paths::config_file().display()),
+        }
+        let values = self.values().into_iter()
+            .map(|(key, value)| (format!("{} = {}", key, value), self.sources.get(key).copied().unwrap_or(ConfigSource::Default)))
+            .collect::<Vec<_>>();
+        let width = values.iter().map(|(line, _)| line.chars().count()).max().unwrap_or(0);
+        for (line, source) in values {
+            println!("{:width$}  # {}", line, source, width = width);
+        }
+    }
+}
+
+/// Program names without a directory, like "yt-dlp".
+fn is_program_name(program: &Path) -> bool {
+    program.components().count() <= 1
 }
 
+/// Name of an imported playlist file, the file name without extension.
+pub fn playlist_file_name(file: &Path) -> String {
+    file.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_else(|| file.display().to_string())
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_file(text: &str) -> ConfigFile {
+        ConfigFile { path: PathBuf::from("config.toml"), text: text.to_string() }
+    }
+
+    fn config_env(variables: &[(&str, &str)]) -> (ConfigEnv, Vec<String>) {
+        ConfigEnv::parse(&variables.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()).unwrap()
+    }
+
+    fn cli_config(args: &[&str]) -> PartialConfig {
+        PartialConfig::from_cli(CliConfigParser::parse_from(std::iter::once("yt-dlp-music-player").chain(args.iter().copied()))).0
+    }
+
+    #[test]
+    fn env_values() {
+        let (env, warnings) = config_env(&[("YTDMP_VOLUME", "0.25"), ("YTDMP_CONFIG_YT_PLAYLIST", "1234"), ("YTDMP_SHUFFLE", "Random")]);
+        assert!(warnings.is_empty());
+        let config = PartialConfig::from_env(&env).unwrap();
+        assert_eq!(config.volume, Some(0.25));
+        // Parses as a number, but the playlist is a string.
+        assert_eq!(config.yt_playlist.as_deref(), Some("1234"));
+        assert_eq!(config.shuffle, Some(ShuffleMode::Random));
+    }
+
+    #[test]
+    fn env_path_list() {
+        let directories = env::join_paths(["music", "podcasts"]).unwrap().into_string().unwrap();
+        let (env, _) = config_env(&[("YTDMP_LOCAL_DIRECTORIES", &directories)]);
+        let config = PartialConfig::from_env(&env).unwrap();
+        assert_eq!(config.local_directories, Some(vec![PathBuf::from("music"), PathBuf::from("podcasts")]));
+
+        let (env, _) = config_env(&[("YTDMP_LOCAL_DIRECTORIES", r#"["music"]"#)]);
+        assert_eq!(PartialConfig::from_env(&env).unwrap().local_directories, Some(vec![PathBuf::from("music")]));
+    }
 
+    #[test]
+    fn env_invalid_value() {
+        let variables = BTreeMap::from([("YTDMP_VOLUME".to_string(), "loud".to_string())]);
+        let Err(err) = ConfigEnv::parse(&variables) else { panic!("Invalid volume was accepted") };
+        assert!(err.to_string().starts_with("Environment variable YTDMP_VOLUME:"), "{}", err);
+    }
+
+    #[test]
+    fn env_unknown_variable() {
+        let (env, warnings) = config_env(&[("YTDMP_VOLUEM", "0.3"), ("YTDMP_IPC_PORT", "1")]);
+        assert_eq!(warnings, vec!["Unknown environment variable YTDMP_VOLUEM is ignored, did you mean YTDMP_VOLUME?".to_string()]);
+        let config = PartialConfig::from_env(&env).unwrap();
+        assert_eq!(config.volume, None);
+        assert_eq!(config.ipc_port, Some(1));
+    }
+
+    #[test]
+    fn file_env_cli_precedence() {
+        let file = config_file("[config]\nyt-playlist = \"PL1234\"\nvolume = 0.1\nshuffle = \"Normal\"\nipc-port = 1\n");
+        let (env, _) = config_env(&[("YTDMP_VOLUME", "0.2"), ("YTDMP_SHUFFLE", "Random")]);
+        let config = Config::from_layers(Some(file), None, env, cli_config(&["--volume", "0.3"]), None, true).unwrap();
+        assert_eq!(config.volume, 0.3);
+        assert_eq!(config.shuffle, ShuffleMode::Random);
+        assert_eq!(config.ipc_port, 1);
+        assert_eq!(config.sources.get("config.volume"), Some(&ConfigSource::Cli));
+        assert_eq!(config.sources.get("config.shuffle"), Some(&ConfigSource::Env));
+        assert_eq!(config.sources.get("config.ipc-port"), Some(&ConfigSource::File));
+        assert_eq!(config.sources.get("config.log-level"), None);
+    }
+
+    fn file_error(text: &str) -> (usize, usize, String) {
+        let (env, _) = config_env(&[]);
+        match Config::from_layers(Some(config_file(text)), None, env, PartialConfig::empty(), None, true) {
+            Err(ConfigError::File { line, column, message, .. }) => (line, column, message),
+            Err(err) => panic!("Expected a file error, got: {}", err),
+            Ok(_) => panic!("Invalid config was accepted"),
+        }
+    }
+
+    #[test]
+    fn invalid_value_location() {
+        let (line, column, _) = file_error("[config]\nyt-playlist = \"PL1234\"\nvolume = \"loud\"\n");
+        assert_eq!((line, column), (3, 10));
+    }
+
+    #[test]
+    fn out_of_range_location() {
+        let (line, column, message) = file_error("[config]\nyt-playlist = \"PL1234\"\n\n  volume = 5\n");
+        assert_eq!((line, column), (4, 12));
+        assert_eq!(message, "Volume 5 is invalid, expected from 0 to 1");
+    }
+
+    #[test]
+    fn unknown_key_suggestion() {
+        let (line, column, message) = file_error("[config]\nyt-playlist = \"PL1234\"\nvoluem = 0.5\n");
+        assert_eq!((line, column), (3, 1));
+        assert_eq!(message, "Unknown key \"voluem\", did you mean \"volume\"?");
+    }
+
+    #[test]
+    fn unknown_variant_suggestion() {
+        let (_, _, message) = file_error("[config]\nyt-playlist = \"PL1234\"\nshuffle = \"randm\"\n");
+        assert_eq!(message, "Unknown value \"randm\", did you mean \"Random\"?");
+    }
+
+    #[test]
+    fn env_error_names_variable() {
+        let (env, _) = config_env(&[("YTDMP_VOLUME", "5")]);
+        let Err(err) = Config::from_layers(None, None, env, cli_config(&["--yt-playlist", "PL1234"]), None, true) else { panic!("Invalid volume was accepted") };
+        assert_eq!(err.to_string(), "Environment variable YTDMP_VOLUME: Volume 5 is invalid, expected from 0 to 1");
+    }
+
+    #[test]
+    fn similar_names() {
+        let names = ["volume", "shuffle", "start-paused"];
+        assert_eq!(similar("voluem", names.into_iter()), Some("volume"));
+        assert_eq!(similar("SHUFFEL", names.into_iter()), Some("shuffle"));
+        assert_eq!(similar("port", names.into_iter()), None);
+        assert_eq!(similar("volume", std::iter::empty()), None);
+    }
+
+    #[test]
+    fn suggest_key_messages() {
+        assert_eq!(suggest_key("unknown field `voluem`, expected one of `volume`, `shuffle`\n"), "Unknown key \"voluem\", did you mean \"volume\"?");
+        assert_eq!(suggest_key("unknown field `port`, expected one of `volume`, `shuffle`"), "Unknown key \"port\", expected one of \"volume\", \"shuffle\"");
+        assert_eq!(suggest_key("invalid type: string \"loud\", expected f32\n"), "invalid type: string \"loud\", expected f32");
+    }
+}
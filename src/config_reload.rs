@@ -0,0 +1,47 @@
+/*
+    Reloads the config file while playing, so most settings don't need a restart.
+    The modification time of the file is polled on a separate thread, which sends an event when it changed.
+    A file that fails to load is only logged, the old config is kept until the file changes again.
+*/
+
+use std::{fs, path::{Path, PathBuf}, thread, time::{Duration, SystemTime}};
+
+
+
+/// Saving in an editor should apply quickly, checking the time of one file is cheap.
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Values that are only used at startup.
+pub const RESTART_KEYS: &[&str] = &[
+    "config.yt-playlist",
+    "config.local-directories",
+    "config.skip-playlist-update",
+    "config.playlist",
+    "config.start-paused",
+    "config.hide-console",
+    "config.ipc-port",
+    "config.log-level",
+    "config.log-format",
+    "config.log-file",
+];
+
+/// Checks the config file for changes every `CHECK_INTERVAL` on another thread, so the event loop only wakes up when it changed.
+/// The file may not exist yet, it's loaded once it's created.
+pub fn watch(file: PathBuf, on_change: impl Fn() + Send + 'static) {
+    thread::spawn(move || {
+        let mut modified = modified_time(&file);
+        loop {
+            thread::sleep(CHECK_INTERVAL);
+            // Editors may replace the file, so it's briefly missing. A deleted file keeps the config.
+            let Some(new_modified) = modified_time(&file) else { continue };
+            if modified != Some(new_modified) {
+                modified = Some(new_modified);
+                on_change();
+            }
+        }
+    });
+}
+
+fn modified_time(file: &Path) -> Option<SystemTime> {
+    fs::metadata(file).and_then(|metadata| metadata.modified()).ok()
+}
@@ -0,0 +1,39 @@
+/*
+    Every subsystem has its own error type, the process exit code tells scripts which one failed.
+    Wrong command line arguments exit with 2, as done by clap.
+*/
+
+use crate::{config::ConfigError, media_controls::MediaControlsError, output_device::OutputDeviceError, playlist_file::PlaylistError, song::DecodeError, yt_dlp::DownloadError};
+
+
+
+pub const EXIT_GENERAL: u8 = 1;
+pub const EXIT_CONFIG: u8 = 3;
+pub const EXIT_DOWNLOAD: u8 = 4;
+pub const EXIT_DECODE: u8 = 5;
+pub const EXIT_PLAYLIST: u8 = 6;
+pub const EXIT_MEDIA_CONTROLS: u8 = 7;
+pub const EXIT_OUTPUT_DEVICE: u8 = 8;
+
+/// Exit code of the first typed error in the chain of causes.
+pub fn exit_code(err: &anyhow::Error) -> u8 {
+    err.chain()
+        .find_map(|cause| {
+            if cause.is::<ConfigError>() {
+                Some(EXIT_CONFIG)
+            } else if cause.is::<DownloadError>() {
+                Some(EXIT_DOWNLOAD)
+            } else if cause.is::<DecodeError>() {
+                Some(EXIT_DECODE)
+            } else if cause.is::<PlaylistError>() {
+                Some(EXIT_PLAYLIST)
+            } else if cause.is::<MediaControlsError>() {
+                Some(EXIT_MEDIA_CONTROLS)
+            } else if cause.is::<OutputDeviceError>() {
+                Some(EXIT_OUTPUT_DEVICE)
+            } else {
+                None
+            }
+        })
+        .unwrap_or(EXIT_GENERAL)
+}
@@ -0,0 +1,347 @@
+/*
+    Other programs (and the CLI itself) can control a running yt-dlp-music-player over a local TCP socket.
+    It's off unless `ipc-port` is set.
+    Each connection sends a single JSON encoded `IpcMessage` line, and gets a single JSON encoded `IpcResponse` line back.
+
+    Other users can connect to localhost too, so every message has to contain the token the player wrote to `paths::ipc_token_file`.
+    That file is only readable by the user. (Mode 0600 on Unix, the local app data directory on Windows is already private)
+
+    Example:
+        > {"token":"<token>","command":{"Queue":{"Add":{"song":"never gonna give you up"}}}}
+        < {"Ok":"Queued: Rick Astley - Never Gonna Give You Up"}
+*/
+
+use std::{fs, io::{self, BufRead, BufReader, Read, Write}, net::{Ipv4Addr, TcpListener, TcpStream}, path::{Path, PathBuf}, sync::{mpsc::{self, Sender}, Arc}, thread, time::Duration};
+use anyhow::{anyhow, Result};
+use clap::Subcommand;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use crate::{paths, playlist_file::PlaylistFileFormat};
+
+
+
+/// Longer messages are refused, so a client can't make the player buffer without limit.
+const MAX_MESSAGE_LENGTH: u64 = 64 * 1024;
+/// Clients that don't send a complete message in time are disconnected.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+
+
+#[derive(Subcommand, Serialize, Deserialize, Debug, Clone)]
+pub enum QueueCommand {
+    /// Add a song to the end of the queue.
+    Add {
+        /// Video ID, file path, or search query.
+        song: String,
+    },
+    /// Add a song to the start of the queue, so it plays next.
+    Next {
+        /// Video ID, file path, or search query.
+        song: String,
+    },
+    /// Remove the song at a position in the queue.
+    Remove {
+        /// Starts at 1.
+        position: usize,
+    },
+    /// Move a song in the queue to another position.
+    Move {
+        /// Starts at 1.
+        from: usize,
+        /// Starts at 1.
+        to: usize,
+    },
+    /// Remove all songs from the queue.
+    Clear,
+    /// Print the current song, the queue, & the playlist played after it.
+    List,
+}
+
+#[derive(Subcommand, Serialize, Deserialize, Debug, Clone)]
+pub enum IpcCommand {
+    /// Print the current song, the next queued song, the sleep timer & the next alarm.
+    Status,
+    /// Play the song best matching the search query, or list candidates if there's no clear match.
+    /// In a terminal, asks which of the candidates to play.
+    Play {
+        /// Title and/or artist, typos are allowed.
+        query: String,
+        /// Play this match of `search` instead of the best one, starting at 1.
+        #[arg(short = 'n', long)]
+        number: Option<usize>,
+    },
+    /// List the songs matching the search query.
+    Search {
+        /// Title and/or artist, typos are allowed.
+        query: String,
+        #[arg(short = 'n', long, default_value_t = 10)]
+        limit: usize,
+    },
+    /// Rate the current song.
+    Rate {
+        /// From 1 to 5 stars, 0 removes the rating.
+        #[arg(value_parser = clap::value_parser!(u8).range(0..=5))]
+        rating: u8,
+    },
+    /// Add or remove the current song from the favorites.
+    Favorite,
+    /// Never play the current song again, and skip it.
+    Block,
+    /// Allow a blocked song to be played again.
+    Unblock {
+        /// Video ID, file path, or search query.
+        song: String,
+    },
+    /// Only play favorite songs.
+    FavoritesOnly {
+        #[arg(action = clap::ArgAction::Set)]
+        enabled: bool,
+    },
+    /// Switch to a playlist, or list the playlists.
+    Playlist {
+        /// "Library" for all songs, or the name of a smart playlist.
+        name: Option<String>,
+    },
+    /// Export a playlist, or the play history, to an M3U8 or XSPF file in the exports directory.
+    Export {
+        /// File name in the exports directory, like "mix.m3u8".
+        file: PathBuf,
+        /// Replace the file if it already exists.
+        #[arg(long)]
+        overwrite: bool,
+        /// Defaults to the file extension.
+        #[arg(short, long, value_enum)]
+        format: Option<PlaylistFileFormat>,
+        /// Name of the playlist, defaults to the current playlist.
+        #[arg(short, long)]
+        playlist: Option<String>,
+        /// Export the songs played so far instead of a playlist.
+        #[arg(long, conflicts_with = "playlist")]
+        history: bool,
+    },
+    /// Stop playing after some time or songs, or print the sleep timer.
+    /// The last 30 seconds fade out.
+    Sleep {
+        /// Like "30m" or "1h30m", a plain number is in minutes.
+        #[arg(conflicts_with_all = ["songs", "cancel"])]
+        duration: Option<String>,
+        /// Songs to play after the current one, 0 stops after the current song.
+        #[arg(long, conflicts_with = "cancel")]
+        songs: Option<u32>,
+        /// Exit instead of pausing, defaults to the sleep-action config.
+        #[arg(long, conflicts_with = "pause")]
+        exit: bool,
+        /// Pause instead of exiting, defaults to the sleep-action config.
+        #[arg(long)]
+        pause: bool,
+        /// Turn off the sleep timer.
+        #[arg(long)]
+        cancel: bool,
+        /// Keep printing the remaining time until the sleep timer ends.
+        #[arg(long, conflicts_with_all = ["duration", "songs", "cancel"])]
+        watch: bool,
+    },
+    /// Control the play queue.
+    #[command(subcommand)]
+    Queue(QueueCommand),
+    /// Switch the audio output device, or list the devices.
+    /// The current song continues on the new device.
+    OutputDevice {
+        /// Name of the device, or a part of the name.
+        #[arg(conflicts_with = "default")]
+        name: Option<String>,
+        /// Use the default device of the system.
+        #[arg(long)]
+        default: bool,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct IpcMessage {
+    /// Contents of the token file, see the top of this file.
+    token: String,
+    command: IpcCommand,
+}
+
+/// Message to print, or error message.
+pub type IpcResponse = Result<String, String>;
+
+/// Start of the `Play` error listing the candidates, so the command line client can ask which one to play.
+pub const NO_CLEAR_MATCH: &str = "No clear match for";
+
+#[derive(Debug)]
+pub struct IpcRequest {
+    pub command: IpcCommand,
+    response: Sender<IpcResponse>,
+}
+
+impl IpcRequest {
+    pub fn respond(self, response: IpcResponse) {
+        // Client may have already disconnected, nothing to do about that.
+        let _ = self.response.send(response);
+    }
+}
+
+
+
+/// Writes a new random token to the token file, readable only by the user.
+fn write_token(file: &Path) -> io::Result<String> {
+    let token = rand::random::<[u8; 32]>().iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+    if let Some(parent) = file.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Removed first, the mode only applies to new files.
+    match fs::remove_file(file) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {},
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(file)?.write_all(token.as_bytes())?;
+    Ok(token)
+}
+
+/// Compares every byte, so the time taken doesn't tell how much of the token was right.
+fn token_matches(token: &str, expected: &str) -> bool {
+    token.len() == expected.len() && token.bytes().zip(expected.bytes()).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+/// Listens for commands on localhost, received commands are passed to `on_request` on the connection thread.
+/// The request has to be responded to, dropping it responds with an error.
+pub fn start_server(port: u16, on_request: impl Fn(IpcRequest) + Send + Sync + 'static) -> Result<()> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+    let token_file = paths::ipc_token_file(port);
+    let token: Arc<str> = write_token(&token_file)
+        .map_err(|err| anyhow!("Failed to write IPC token file {:?}: {}", token_file, err))?
+        .into();
+    let on_request = Arc::new(on_request);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else { continue };
+            let on_request = Arc::clone(&on_request);
+            let token = Arc::clone(&token);
+            thread::spawn(move || {
+                if let Err(err) = handle_connection(stream, &token, on_request.as_ref()) {
+                    warn!("IPC connection failed: {}", err);
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+fn handle_connection(stream: TcpStream, token: &str, on_request: &dyn Fn(IpcRequest)) -> Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut line = String::new();
+    BufReader::new(&stream).take(MAX_MESSAGE_LENGTH).read_line(&mut line)?;
+
+    let response = match serde_json::from_str::<IpcMessage>(&line) {
+        Ok(message) if !token_matches(&message.token, token) => Err("Invalid token.".to_string()),
+        Ok(message) => {
+            let (response_sender, response_receiver) = mpsc::channel();
+            on_request(IpcRequest { command: message.command, response: response_sender });
+            response_receiver.recv().unwrap_or_else(|_| Err("Player closed.".to_string()))
+        },
+        Err(_) if !line.ends_with('\n') && line.len() as u64 >= MAX_MESSAGE_LENGTH => Err(format!("Command is longer than {} bytes.", MAX_MESSAGE_LENGTH)),
+        Err(err) => Err(format!("Invalid command: {}", err)),
+    };
+
+    let mut stream = stream;
+    writeln!(stream, "{}", serde_json::to_string(&response)?)?;
+    Ok(())
+}
+
+
+
+/// Sends a command to the running player.
+pub fn send(port: u16, command: &IpcCommand) -> Result<IpcResponse> {
+    if port == 0 {
+        return Err(anyhow!("Controlling the player from the command line is off, set ipc-port in the config to turn it on."));
+    }
+    let token_file = paths::ipc_token_file(port);
+    let token = fs::read_to_string(&token_file)
+        .map_err(|err| anyhow!("Failed to read IPC token file {:?}, is the player running? ({})", token_file, err))?;
+
+    let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+        .map_err(|err| anyhow!("Failed to connect to player on port {}, is it running? ({})", port, err))?;
+    stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+
+    let message = IpcMessage { token: token.trim().to_string(), command: command.clone() };
+    writeln!(stream, "{}", serde_json::to_string(&message)?)?;
+
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    const TOKEN: &str = "0123456789abcdef";
+
+    /// Sends `message` to a connection handled like by the server, returns the response.
+    fn request(message: &[u8]) -> IpcResponse {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address: SocketAddr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(stream, TOKEN, &|request: IpcRequest| {
+                let response = format!("{:?}", request.command);
+                request.respond(Ok(response));
+            }).unwrap();
+        });
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(message).unwrap();
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line).unwrap();
+        server.join().unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    fn message(token: &str) -> Vec<u8> {
+        format!("{}\n", serde_json::to_string(&IpcMessage { token: token.to_string(), command: IpcCommand::Status }).unwrap()).into_bytes()
+    }
+
+    #[test]
+    fn valid_token() {
+        assert_eq!(request(&message(TOKEN)), Ok("Status".to_string()));
+    }
+
+    #[test]
+    fn invalid_token() {
+        assert_eq!(request(&message("0123456789abcdeF")), Err("Invalid token.".to_string()));
+        assert_eq!(request(&message("")), Err("Invalid token.".to_string()));
+        assert!(request(b"\"Status\"\n").unwrap_err().starts_with("Invalid command"));
+    }
+
+    #[test]
+    fn message_too_long() {
+        // Exactly as long as is read, unread data would reset the connection before the response is read.
+        let message = vec![b' '; MAX_MESSAGE_LENGTH as usize];
+        assert_eq!(request(&message), Err(format!("Command is longer than {} bytes.", MAX_MESSAGE_LENGTH)));
+    }
+
+    #[test]
+    fn token_file() {
+        let file = std::env::temp_dir().join(format!("yt-dlp-music-player-test-{}", std::process::id())).join("ipc.token");
+        let token = write_token(&file).unwrap();
+        assert_eq!(token.len(), 64);
+        assert_eq!(fs::read_to_string(&file).unwrap(), token);
+        // A new token every time.
+        assert_ne!(write_token(&file).unwrap(), token);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&file).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        fs::remove_dir_all(file.parent().unwrap()).unwrap();
+    }
+}
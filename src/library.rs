@@ -0,0 +1,708 @@
+use std::{collections::BTreeMap, fs::{self, OpenOptions}, io::{self, BufRead, BufReader, Write}, path::{Path, PathBuf}, time::{Duration, SystemTime, UNIX_EPOCH}};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use crate::{loudness_normalization::LoudnessNormalization, paths, song::Song, source::PlaylistSource, stats::{PlayEvent, PlayOutcome}};
+
+
+
+const LIBRARY_FILE_NAME: &str = "library.json";
+const PLAYS_FILE_NAME: &str = "plays.jsonl";
+/// In the cache directory, shared by all sources.
+const LOUDNESS_CACHE_FILE_NAME: &str = "loudness.json";
+/// Only needs to be bumped for changes that aren't backwards compatible.
+/// New fields should use `#[serde(default)]` instead.
+const LIBRARY_VERSION: u32 = 1;
+/// A stopped song counts as played after this part of the song, or after `MIN_PLAYED` for long songs.
+const MIN_PLAYED_FRACTION: f64 = 0.5;
+const MIN_PLAYED: Duration = Duration::from_secs(4 * 60);
+
+pub fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+pub fn unix_time_now() -> u64 {
+    unix_time(SystemTime::now())
+}
+
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct LoudnessGain {
+    pub normalization: LoudnessNormalization,
+    pub gain: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LibraryEntry {
+    /// Video ID, only for songs downloaded by yt-dlp.
+    pub video_id: Option<String>,
+    pub title: String,
+    pub artist: Option<String>,
+    /// In seconds.
+    pub duration: Option<f64>,
+    /// File extension.
+    pub format: String,
+    /// Moved to the `LoudnessCache`, only read to move it there from older indices.
+    #[serde(default, skip_serializing)]
+    loudness_gain: Option<LoudnessGain>,
+    /// Unix timestamp.
+    pub date_added: u64,
+    /// Unix timestamp of the last file modification, metadata is read again if this changes.
+    pub modified: u64,
+    #[serde(default)]
+    pub play_count: u32,
+    #[serde(default)]
+    pub skip_count: u32,
+    /// Unix timestamp.
+    #[serde(default)]
+    pub last_played: Option<u64>,
+    /// From 1 to 5 stars.
+    #[serde(default)]
+    pub rating: Option<u8>,
+    #[serde(default)]
+    pub favorite: bool,
+    /// Never played, unless explicitly chosen.
+    #[serde(default)]
+    pub blocked: bool,
+    /// Why the file couldn't be played, it's skipped until the file changes or `verify` succeeds.
+    #[serde(default)]
+    pub unplayable: Option<String>,
+}
+
+impl LibraryEntry {
+    fn update_metadata(&mut self, song: &Song) {
+        self.video_id = song.id();
+        self.title = song.name();
+        self.artist = song.artist();
+        self.duration = song.duration().map(|d| d.as_secs_f64());
+        self.format = song.file().extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default();
+    }
+}
+
+
+
+#[derive(Deserialize)]
+struct LibraryFileVersion {
+    version: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LibraryFile {
+    version: u32,
+    /// Keyed by file path relative to the source directory.
+    songs: BTreeMap<String, LibraryEntry>,
+}
+
+/// Persistent index of a single source, stored in `paths::library_directory` of the source directory.
+#[derive(Debug)]
+pub struct LibraryIndex {
+    source: PlaylistSource,
+    /// Where the index & play log are stored.
+    directory: PathBuf,
+    songs: BTreeMap<String, LibraryEntry>,
+    dirty: bool,
+}
+
+impl LibraryIndex {
+    pub fn load(source: PlaylistSource) -> Result<LibraryIndex> {
+        let directory = paths::library_directory(source.directory());
+        LibraryIndex::load_in(source, directory)
+    }
+
+    /// Same as `load`, but with the index stored in `directory`.
+    fn load_in(source: PlaylistSource, directory: PathBuf) -> Result<LibraryIndex> {
+        let migrated = LibraryIndex::migrate(source.directory(), &directory)?;
+        let file = if migrated { source.directory().join(LIBRARY_FILE_NAME) } else { directory.join(LIBRARY_FILE_NAME) };
+        let songs = match fs::read_to_string(&file) {
+            Ok(data) => {
+                let version: LibraryFileVersion = serde_json::from_str(&data)?;
+                if version.version == LIBRARY_VERSION {
+                    serde_json::from_str::<LibraryFile>(&data)?.songs
+                } else {
+                    warn!("Library index {:?} has unknown version {}, rebuilding it.", &file, version.version);
+                    BTreeMap::new()
+                }
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(LibraryIndex { source, directory, songs, dirty: migrated })
+    }
+
+    /// Older versions stored the index & play log in the source directory, they're copied to `directory` if it has no index yet.
+    /// The old files are left alone, as the source directory may be read-only. Returns if the index must be read from the old file.
+    fn migrate(source_directory: &Path, directory: &Path) -> Result<bool> {
+        let legacy_file = source_directory.join(LIBRARY_FILE_NAME);
+        if directory.join(LIBRARY_FILE_NAME).exists() || !legacy_file.is_file() {
+            return Ok(false);
+        }
+        info!("Moving library index {:?} to {:?}, the old files may be deleted.", &legacy_file, directory);
+        fs::create_dir_all(directory)?;
+        let legacy_plays_file = source_directory.join(PLAYS_FILE_NAME);
+        if legacy_plays_file.is_file() {
+            fs::copy(&legacy_plays_file, directory.join(PLAYS_FILE_NAME))?;
+        }
+        Ok(true)
+    }
+
+    pub fn source(&self) -> &PlaylistSource {
+        &self.source
+    }
+
+    fn key(&self, file: &Path) -> Option<String> {
+        let relative = file.strip_prefix(self.source.directory()).ok()?;
+        let components = relative.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>();
+        Some(components.join("/"))
+    }
+
+    fn song(&self, key: &str, entry: &LibraryEntry) -> Song {
+        Song::new(
+            self.source.directory().join(key),
+            entry.video_id.clone(),
+            entry.title.clone(),
+            entry.artist.clone(),
+            entry.duration.map(Duration::from_secs_f64),
+        )
+    }
+
+    /// Updates the index with the files in the source directory, only new or changed files are read.
+    pub fn scan(&mut self) -> Result<Vec<Song>> {
+        let kind = self.source.song_kind();
+        let mut found: BTreeMap<String, LibraryEntry> = BTreeMap::new();
+
+        for file in Song::playlist_directory_files(self.source.directory(), kind)? {
+            let Some(key) = self.key(&file) else { continue };
+            let metadata = fs::metadata(&file)?;
+            let modified = metadata.modified().map(unix_time).unwrap_or(0);
+
+            let entry = match self.songs.remove(&key) {
+                Some(entry) if entry.modified == modified => entry,
+                Some(mut entry) => {
+                    entry.update_metadata(&Song::load(file, kind));
+                    entry.modified = modified;
+                    // May have been downloaded again, or fixed.
+                    entry.unplayable = None;
+                    self.dirty = true;
+                    entry
+                },
+                None => {
+                    let mut entry = LibraryEntry {
+                        video_id: None,
+                        title: String::new(),
+                        artist: None,
+                        duration: None,
+                        format: String::new(),
+                        loudness_gain: None,
+                        // Creation time is the closest thing to when the song was added, for songs that existed before the index.
+                        date_added: metadata.created().or(metadata.modified()).map(unix_time).unwrap_or_else(|_| unix_time_now()),
+                        modified,
+                        play_count: 0,
+                        skip_count: 0,
+                        last_played: None,
+                        rating: None,
+                        favorite: false,
+                        blocked: false,
+                        unplayable: None,
+                    };
+                    entry.update_metadata(&Song::load(file, kind));
+                    self.dirty = true;
+                    entry
+                },
+            };
+            found.insert(key, entry);
+        }
+
+        // Anything left over was removed from the directory.
+        if !self.songs.is_empty() {
+            self.dirty = true;
+        }
+        self.songs = found;
+
+        self.save()?;
+        Ok(self.songs())
+    }
+
+    pub fn songs(&self) -> Vec<Song> {
+        self.songs.iter().map(|(key, entry)| self.song(key, entry)).collect()
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (Song, &LibraryEntry)> {
+        self.songs.iter().map(|(key, entry)| (self.song(key, entry), entry))
+    }
+
+    /// Appends a play event to the play log & updates the statistics of the song.
+    /// Songs stopped before `MIN_PLAYED_FRACTION` or `MIN_PLAYED` are skips, like going back right after a song started.
+    fn record_play(&mut self, key: &str, start: u64, played: Duration, duration: Duration, outcome: PlayOutcome) -> Result<()> {
+        let Some(entry) = self.songs.get_mut(key) else { return Ok(()) };
+
+        let outcome = match outcome {
+            PlayOutcome::Stopped if played < duration.mul_f64(MIN_PLAYED_FRACTION).min(MIN_PLAYED) => PlayOutcome::Skipped,
+            outcome => outcome,
+        };
+        match outcome {
+            PlayOutcome::Completed | PlayOutcome::Stopped => entry.play_count += 1,
+            PlayOutcome::Skipped => entry.skip_count += 1,
+        }
+        entry.last_played = Some(start);
+        self.dirty = true;
+
+        let event = PlayEvent {
+            song_id: entry.video_id.clone().unwrap_or_else(|| key.to_string()),
+            title: entry.title.clone(),
+            start,
+            played: played.as_secs_f64(),
+            outcome,
+        };
+        fs::create_dir_all(&self.directory)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.directory.join(PLAYS_FILE_NAME))?;
+        writeln!(file, "{}", serde_json::to_string(&event)?)?;
+
+        self.save()
+    }
+
+    pub fn play_events(&self) -> Result<Vec<PlayEvent>> {
+        let file = match fs::File::open(self.directory.join(PLAYS_FILE_NAME)) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err.into()),
+        };
+        let mut events = vec![];
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            events.push(serde_json::from_str(&line)?);
+        }
+        Ok(events)
+    }
+
+    pub fn save(&mut self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        fs::create_dir_all(&self.directory)?;
+        let file = self.directory.join(LIBRARY_FILE_NAME);
+        let temp_file = file.with_extension("json.tmp");
+        let data = serde_json::to_string_pretty(&LibraryFile {
+            version: LIBRARY_VERSION,
+            songs: self.songs.clone(),
+        })?;
+        // Write to a temporary file first, so a crash can't leave a half written index.
+        fs::write(&temp_file, data)?;
+        fs::rename(&temp_file, &file)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+struct CachedGain {
+    /// Unix timestamp of the file modification the gain was calculated for.
+    modified: u64,
+    normalization: LoudnessNormalization,
+    gain: f64,
+}
+
+/// Results of the (slow) loudness analysis, keyed by absolute file path.
+/// Only a cache, so it's thrown away if it can't be read.
+#[derive(Debug)]
+struct LoudnessCache {
+    file: PathBuf,
+    gains: BTreeMap<String, CachedGain>,
+    dirty: bool,
+}
+
+impl LoudnessCache {
+    fn load(file: PathBuf) -> LoudnessCache {
+        let gains = match fs::read_to_string(&file) {
+            Ok(data) => serde_json::from_str(&data).unwrap_or_else(|err| {
+                warn!("Loudness cache {:?} is invalid, rebuilding it: {}", &file, err);
+                BTreeMap::new()
+            }),
+            Err(_) => BTreeMap::new(),
+        };
+        LoudnessCache { file, gains, dirty: false }
+    }
+
+    fn key(file: &Path) -> String {
+        std::path::absolute(file).unwrap_or_else(|_| file.to_path_buf()).to_string_lossy().to_string()
+    }
+
+    fn get(&self, file: &Path, modified: u64, normalization: LoudnessNormalization) -> Option<f64> {
+        self.gains.get(&LoudnessCache::key(file))
+            .filter(|cached| cached.modified == modified && cached.normalization == normalization)
+            .map(|cached| cached.gain)
+    }
+
+    fn insert(&mut self, file: &Path, modified: u64, gain: LoudnessGain) {
+        self.gains.insert(LoudnessCache::key(file), CachedGain { modified, normalization: gain.normalization, gain: gain.gain });
+        self.dirty = true;
+    }
+
+    fn save(&mut self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        if let Some(directory) = self.file.parent() {
+            fs::create_dir_all(directory)?;
+        }
+        let temp_file = self.file.with_extension("json.tmp");
+        fs::write(&temp_file, serde_json::to_string(&self.gains)?)?;
+        fs::rename(&temp_file, &self.file)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+
+
+/// All library indices of the configured sources.
+#[derive(Debug)]
+pub struct Library {
+    indices: Vec<LibraryIndex>,
+    loudness_cache: LoudnessCache,
+}
+
+impl Library {
+    pub fn load(sources: &[PlaylistSource]) -> Result<Library> {
+        let mut library = Library {
+            indices: sources.iter().cloned().map(LibraryIndex::load).collect::<Result<Vec<_>>>()?,
+            loudness_cache: LoudnessCache::load(paths::cache_directory().join(LOUDNESS_CACHE_FILE_NAME)),
+        };
+
+        // Loudness gains used to be stored in the index.
+        for index in library.indices.iter_mut() {
+            let directory = index.source.directory().clone();
+            for (key, entry) in index.songs.iter_mut() {
+                if let Some(gain) = entry.loudness_gain.take() {
+                    library.loudness_cache.insert(&directory.join(key), entry.modified, gain);
+                    index.dirty = true;
+                }
+            }
+        }
+
+        Ok(library)
+    }
+
+    pub fn indices(&self) -> &[LibraryIndex] {
+        &self.indices
+    }
+
+    pub fn scan(&mut self) -> Result<Vec<Song>> {
+        let mut songs = vec![];
+        for index in self.indices.iter_mut() {
+            songs.extend(index.scan()?);
+        }
+        Ok(songs)
+    }
+
+    pub fn scan_source(&mut self, source_index: usize) -> Result<Vec<Song>> {
+        match self.indices.get_mut(source_index) {
+            Some(index) => index.scan(),
+            None => Ok(vec![]),
+        }
+    }
+
+    pub fn songs(&self) -> Vec<Song> {
+        self.indices.iter().flat_map(|index| index.songs()).collect()
+    }
+
+    fn index_of(&self, file: &Path) -> Option<(usize, String)> {
+        self.indices.iter().enumerate().find_map(|(i, index)| index.key(file).map(|key| (i, key)))
+    }
+
+    pub fn entry(&self, song: &Song) -> Option<&LibraryEntry> {
+        let (i, key) = self.index_of(song.file())?;
+        self.indices[i].songs.get(&key)
+    }
+
+    /// Marks the index as changed, use `save` to write it.
+    pub fn entry_mut(&mut self, song: &Song) -> Option<&mut LibraryEntry> {
+        let (i, key) = self.index_of(song.file())?;
+        let index = &mut self.indices[i];
+        index.dirty = true;
+        index.songs.get_mut(&key)
+    }
+
+    /// From 1 to 5 stars, 0 removes the rating. Returns false if the song isn't in the library.
+    pub fn set_rating(&mut self, song: &Song, rating: u8) -> bool {
+        let Some(entry) = self.entry_mut(song) else { return false };
+        entry.rating = (rating > 0).then_some(rating.min(5));
+        true
+    }
+
+    /// Cached loudness gain, if the song didn't change since.
+    pub fn loudness_gain(&self, song: &Song, normalization: LoudnessNormalization) -> Option<f64> {
+        let entry = self.entry(song)?;
+        self.loudness_cache.get(song.file(), entry.modified, normalization)
+    }
+
+    /// Use `save` to write it.
+    pub fn set_loudness_gain(&mut self, song: &Song, gain: LoudnessGain) {
+        if let Some(modified) = self.entry(song).map(|entry| entry.modified) {
+            self.loudness_cache.insert(song.file(), modified, gain);
+        }
+    }
+
+    /// `duration` is the length of the song.
+    pub fn record_play(&mut self, song: &Song, start: u64, played: Duration, duration: Duration, outcome: PlayOutcome) -> Result<()> {
+        let Some((i, key)) = self.index_of(song.file()) else { return Ok(()) };
+        self.indices[i].record_play(&key, start, played, duration, outcome)
+    }
+
+    pub fn play_events(&self) -> Result<Vec<PlayEvent>> {
+        let mut events = vec![];
+        for index in &self.indices {
+            events.extend(index.play_events()?);
+        }
+        Ok(events)
+    }
+
+    pub fn save(&mut self) -> Result<()> {
+        for index in self.indices.iter_mut() {
+            index.save()?;
+        }
+        self.loudness_cache.save()
+    }
+}
+
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn test_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("yt-dlp-music-player-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    pub(crate) fn song_file(directory: &Path, name: &str) -> PathBuf {
+        let file = directory.join(name);
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(&file, []).unwrap();
+        file
+    }
+
+    /// Library of the local source "songs" in `directory`, with the index & caches in `directory` instead of the user's data directory.
+    pub(crate) fn test_library(directory: &Path) -> Library {
+        let source = PlaylistSource::LocalDirectory { directory: directory.join("songs") };
+        fs::create_dir_all(source.directory()).unwrap();
+        Library {
+            indices: vec![LibraryIndex::load_in(source, directory.join("index")).unwrap()],
+            loudness_cache: LoudnessCache::load(directory.join(LOUDNESS_CACHE_FILE_NAME)),
+        }
+    }
+
+    fn counts(library: &Library, song: &Song) -> (u32, u32) {
+        let entry = library.entry(song).unwrap();
+        (entry.play_count, entry.skip_count)
+    }
+
+    #[test]
+    fn play_threshold() {
+        let directory = test_directory("library-play-threshold");
+        let mut library = test_library(&directory);
+        song_file(&directory.join("songs"), "Song.mp3");
+        let song = library.scan().unwrap().remove(0);
+        let secs = Duration::from_secs_f64;
+
+        // Half of short songs.
+        library.record_play(&song, 1, secs(29.9), secs(60.0), PlayOutcome::Stopped).unwrap();
+        assert_eq!(counts(&library, &song), (0, 1));
+        library.record_play(&song, 2, secs(30.0), secs(60.0), PlayOutcome::Stopped).unwrap();
+        assert_eq!(counts(&library, &song), (1, 1));
+
+        // At most 4 minutes of long songs.
+        library.record_play(&song, 3, secs(239.9), secs(600.0), PlayOutcome::Stopped).unwrap();
+        assert_eq!(counts(&library, &song), (1, 2));
+        library.record_play(&song, 4, secs(240.0), secs(600.0), PlayOutcome::Stopped).unwrap();
+        assert_eq!(counts(&library, &song), (2, 2));
+    }
+
+    #[test]
+    fn play_outcomes() {
+        let directory = test_directory("library-play-outcomes");
+        let mut library = test_library(&directory);
+        song_file(&directory.join("songs"), "Song.mp3");
+        let song = library.scan().unwrap().remove(0);
+        let secs = Duration::from_secs_f64;
+
+        // Only stopped songs depend on how long they were played.
+        library.record_play(&song, 1, secs(1.0), secs(60.0), PlayOutcome::Completed).unwrap();
+        assert_eq!(counts(&library, &song), (1, 0));
+        library.record_play(&song, 2, secs(59.0), secs(60.0), PlayOutcome::Skipped).unwrap();
+        assert_eq!(counts(&library, &song), (1, 1));
+        assert_eq!(library.entry(&song).unwrap().last_played, Some(2));
+
+        let events = library.play_events().unwrap();
+        assert_eq!(events.iter().map(|e| e.outcome).collect::<Vec<_>>(), vec![PlayOutcome::Completed, PlayOutcome::Skipped]);
+        assert_eq!(events[1].song_id, "Song.mp3");
+        assert_eq!(events[1].played, 59.0);
+
+        // Counts are saved with the index.
+        let index = LibraryIndex::load_in(library.indices[0].source.clone(), directory.join("index")).unwrap();
+        let entry = index.songs.get("Song.mp3").unwrap();
+        assert_eq!((entry.play_count, entry.skip_count), (1, 1));
+    }
+
+    fn keys(index: &LibraryIndex) -> Vec<&str> {
+        index.songs.keys().map(|key| key.as_str()).collect()
+    }
+
+    #[test]
+    fn local_scan_is_recursive() {
+        let directory = test_directory("library-local-scan");
+        let mut library = test_library(&directory);
+        let songs = directory.join("songs");
+        for name in ["Song.mp3", "Album/Song.FLAC", "Album/Disc 2/Song.wav", "Album/cover.jpg", "notes.txt"] {
+            song_file(&songs, name);
+        }
+        library.scan().unwrap();
+        assert_eq!(keys(&library.indices[0]), vec!["Album/Disc 2/Song.wav", "Album/Song.FLAC", "Song.mp3"]);
+    }
+
+    #[test]
+    fn yt_dlp_scan_is_not_recursive() {
+        let directory = test_directory("library-yt-dlp-scan");
+        let source = PlaylistSource::YtDlp {
+            url: url::Url::parse("https://www.youtube.com/playlist?list=test").unwrap(),
+            directory: directory.join("songs"),
+        };
+        for name in ["abc Song.m4a", "abc Song.mp3", "Nested/def Song.m4a"] {
+            song_file(source.directory(), name);
+        }
+        let mut index = LibraryIndex::load_in(source, directory.join("index")).unwrap();
+        let songs = index.scan().unwrap();
+        assert_eq!(keys(&index), vec!["abc Song.m4a"]);
+        assert_eq!(songs[0].id().as_deref(), Some("abc"));
+    }
+
+    /// One second of silence, a file lofty can read & write the tags of.
+    fn wav_file(directory: &Path, name: &str, title: Option<&str>) -> PathBuf {
+        use lofty::{config::WriteOptions, prelude::{Accessor, TagExt}, tag::{Tag, TagType}};
+
+        let samples = vec![128u8; 8000];
+        let mut data = vec![];
+        data.extend(b"RIFF");
+        data.extend((36 + samples.len() as u32).to_le_bytes());
+        data.extend(b"WAVEfmt ");
+        // PCM, mono, 8 kHz, 8 bits.
+        for (value, size) in [(16u32, 4), (1, 2), (1, 2), (8000, 4), (8000, 4), (1, 2), (8, 2)] {
+            data.extend(&value.to_le_bytes()[..size]);
+        }
+        data.extend(b"data");
+        data.extend((samples.len() as u32).to_le_bytes());
+        data.extend(samples);
+
+        let file = directory.join(name);
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(&file, data).unwrap();
+        if let Some(title) = title {
+            let mut tag = Tag::new(TagType::Id3v2);
+            tag.set_title(title.to_string());
+            tag.set_artist("Artist".to_string());
+            tag.save_to_path(&file, WriteOptions::default()).unwrap();
+        }
+        file
+    }
+
+    fn set_modified(file: &Path, modified: u64) {
+        let file = fs::File::options().write(true).open(file).unwrap();
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(modified)).unwrap();
+    }
+
+    #[test]
+    fn embedded_tags() {
+        let directory = test_directory("library-tags");
+        let mut library = test_library(&directory);
+        wav_file(&directory.join("songs"), "Tagged.wav", Some("Tag Title"));
+        wav_file(&directory.join("songs"), "Untagged.wav", None);
+        library.scan().unwrap();
+
+        let index = &library.indices[0];
+        let tagged = index.songs.get("Tagged.wav").unwrap();
+        assert_eq!(tagged.title, "Tag Title");
+        assert_eq!(tagged.artist.as_deref(), Some("Artist"));
+        assert_eq!(tagged.duration, Some(1.0));
+        assert_eq!(tagged.format, "wav");
+        // Falls back to the file name.
+        let untagged = index.songs.get("Untagged.wav").unwrap();
+        assert_eq!(untagged.title, "Untagged");
+        assert_eq!(untagged.artist, None);
+    }
+
+    #[test]
+    fn rescan_changed_files() {
+        let directory = test_directory("library-rescan");
+        let mut library = test_library(&directory);
+        let songs = directory.join("songs");
+        let file = wav_file(&songs, "Song.wav", Some("Old Title"));
+        song_file(&songs, "Removed.mp3");
+        set_modified(&file, 1_000_000);
+        let song = library.scan().unwrap().remove(1);
+        library.record_play(&song, 1, Duration::from_secs(1), Duration::from_secs(1), PlayOutcome::Completed).unwrap();
+        library.entry_mut(&song).unwrap().unplayable = Some("Broken".to_string());
+
+        // Tags are only read again if the file was modified.
+        wav_file(&songs, "Song.wav", Some("New Title"));
+        set_modified(&file, 1_000_000);
+        fs::remove_file(songs.join("Removed.mp3")).unwrap();
+        library.scan().unwrap();
+        assert_eq!(keys(&library.indices[0]), vec!["Song.wav"]);
+        assert_eq!(library.entry(&song).unwrap().title, "Old Title");
+
+        set_modified(&file, 2_000_000);
+        library.scan().unwrap();
+        let entry = library.entry(&song).unwrap();
+        assert_eq!(entry.title, "New Title");
+        assert_eq!(entry.modified, 2_000_000);
+        assert_eq!(entry.play_count, 1);
+        assert_eq!(entry.unplayable, None);
+    }
+
+    #[test]
+    fn migrate_index() {
+        let directory = test_directory("library-migrate");
+        let source = PlaylistSource::LocalDirectory { directory: directory.join("songs") };
+        song_file(source.directory(), "Song.mp3");
+
+        // Older versions stored the index in the source directory.
+        let mut legacy_index = LibraryIndex::load_in(source.clone(), source.directory().clone()).unwrap();
+        legacy_index.scan().unwrap();
+        legacy_index.record_play("Song.mp3", 1, Duration::from_secs(60), Duration::from_secs(60), PlayOutcome::Completed).unwrap();
+
+        let mut index = LibraryIndex::load_in(source.clone(), directory.join("index")).unwrap();
+        assert_eq!(index.songs.get("Song.mp3").unwrap().play_count, 1);
+        assert_eq!(index.play_events().unwrap().len(), 1);
+        index.save().unwrap();
+        assert!(directory.join("index").join(LIBRARY_FILE_NAME).is_file());
+        // Left alone, the source directory may be read-only.
+        assert!(source.directory().join(LIBRARY_FILE_NAME).is_file());
+        assert!(source.directory().join(PLAYS_FILE_NAME).is_file());
+
+        // Only migrated once.
+        legacy_index.record_play("Song.mp3", 2, Duration::from_secs(60), Duration::from_secs(60), PlayOutcome::Completed).unwrap();
+        let index = LibraryIndex::load_in(source, directory.join("index")).unwrap();
+        assert_eq!(index.songs.get("Song.mp3").unwrap().play_count, 1);
+        assert_eq!(index.play_events().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn unknown_song_not_recorded() {
+        let directory = test_directory("library-unknown-song");
+        let mut library = test_library(&directory);
+        let song = Song::new(directory.join("elsewhere.mp3"), None, "Elsewhere".to_string(), None, None);
+        library.record_play(&song, 1, Duration::from_secs(60), Duration::from_secs(60), PlayOutcome::Completed).unwrap();
+        assert!(library.play_events().unwrap().is_empty());
+    }
+}
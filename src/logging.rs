@@ -0,0 +1,76 @@
+/*
+    Logs go to the console, and for the player also to a daily rotated log file in the state directory.
+    The console is hidden on Windows, so the log file is the only way to find out what happened afterwards.
+
+    Filtering uses the `RUST_LOG` syntax, like "info" or "warn,yt_dlp_music_player::source=debug".
+    The `RUST_LOG` environment variable overrides the `log-level` config.
+*/
+
+use std::path::PathBuf;
+use anyhow::Result;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer, Registry};
+use crate::{config::ConfigError, paths};
+
+
+
+/// Amount of daily log files that are kept.
+const MAX_LOG_FILES: usize = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+/// Directory of the log files, like "~/.local/state/yt-dlp-music-player/logs" or "%LOCALAPPDATA%\yt-dlp-music-player\logs".
+pub fn log_directory() -> PathBuf {
+    paths::state_directory().join("logs")
+}
+
+fn format_layer<W>(format: LogFormat, writer: W, ansi: bool) -> Box<dyn Layer<Registry> + Send + Sync>
+where
+    W: for<'writer> fmt::MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let layer = fmt::layer().with_writer(writer).with_ansi(ansi);
+    match format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer.json().boxed(),
+    }
+}
+
+/// `log_file` also writes to the log file, only the player itself does so, not the other commands.
+/// Returns the log directory if the log file is used.
+pub fn init(level: &str, format: LogFormat, log_file: bool) -> Result<Option<PathBuf>> {
+    let filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) => filter,
+        Err(_) => EnvFilter::try_new(level).map_err(|err| ConfigError::Invalid(format!("Invalid log-level \"{}\": {}", level, err)))?,
+    };
+
+    let mut layers = vec![format_layer(format, std::io::stdout, true)];
+    let log_directory = log_file.then(log_directory);
+    if let Some(log_directory) = &log_directory {
+        std::fs::create_dir_all(log_directory)?;
+        let appender = RollingFileAppender::builder()
+            .rotation(Rotation::DAILY)
+            .filename_prefix("yt-dlp-music-player")
+            .filename_suffix("log")
+            .max_log_files(MAX_LOG_FILES)
+            .build(log_directory)?;
+        layers.push(format_layer(format, appender, false));
+    }
+
+    tracing_subscriber::registry()
+        .with(layers.with_filter(filter))
+        .try_init()?;
+
+    // Panics would otherwise only be printed to the (hidden) console.
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        tracing::error!("{}", info);
+        default_hook(info);
+    }));
+
+    Ok(log_directory)
+}
@@ -3,10 +3,11 @@ mod rms;
 
 use rms::loudness_normalization_rms;
 use rodio::Source;
+use serde::{Deserialize, Serialize};
 
 
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum LoudnessNormalization {
     None,
     RMS,
@@ -1,58 +1,194 @@
 
 #![allow(dead_code)]
 
+mod alarm;
 mod config;
+mod config_reload;
+mod error;
+mod ipc;
+mod library;
+mod logging;
+mod paths;
 mod playlist;
+mod playlist_file;
+mod read_ahead;
+mod search;
+mod sleep_timer;
+mod smart_playlist;
 mod song;
+mod source;
+mod tray;
+mod stats;
+mod verify;
+mod weights;
 mod loudness_normalization;
 mod media_controls;
+mod notifications;
+mod output_device;
+mod yt_dlp;
 
-use config::Config;
+use alarm::{Alarm, AlarmScheduler, VolumeRamp};
+use config::{Command, Config, ConfigCommand};
+use config_reload::RESTART_KEYS;
+use ipc::{IpcCommand, IpcRequest, IpcResponse, QueueCommand};
+use library::{unix_time_now, Library, LoudnessGain};
 use media_controls::{create_media_controls_multi_os, CreateMediaControlsMultiOSOptions, MediaControls, MediaControlsEvent, MediaControlsMetadata, MediaControlsPlayback};
-use playlist::{Playlist, PlaylistSeekable};
+use notifications::{NotificationAction, Notifier};
+use output_device::OutputDeviceError;
+use playlist::{NamedPlaylist, Playlist, PlaylistKind, PlaylistSeekable, ShuffleMode, LIBRARY_PLAYLIST};
+use sleep_timer::{SleepAction, SleepTimer, SleepUntil};
 use song::Song;
-use rodio::{OutputStream, OutputStreamHandle, Sink};
-use std::{ffi::c_void, fs, path::PathBuf, process::{Command, Stdio}, time::Duration};
-use tray_icon::{TrayIcon, TrayIconBuilder, TrayIconEvent};
-use winit::{application::ApplicationHandler, event::WindowEvent, event_loop::{ActiveEventLoop, ControlFlow, EventLoop}, window::{Window, WindowId}};
-use anyhow::{anyhow, Result};
+use source::{PlaylistSource, SourceWatcher};
+use stats::PlayOutcome;
+use rodio::{cpal, Sink};
+use std::{ffi::c_void, fs, io::{self, IsTerminal, Write}, process::ExitCode, sync::Arc, time::{Duration, Instant}};
+use tray::{Tray, TrayAction, TrayState};
+use tray_icon::{menu::MenuEvent, TrayIconEvent};
+use winit::{application::ApplicationHandler, event::WindowEvent, event_loop::{ActiveEventLoop, ControlFlow, EventLoop, EventLoopProxy}, window::{Window, WindowId}};
+use anyhow::{anyhow, Context, Result};
+use tracing::{debug, error, info, warn};
 
 
 
+/// Response to `sleep` without a sleep timer.
+const SLEEP_TIMER_OFF: &str = "Sleep timer is off.";
+
+
+
+/// Wakes up the event loop, everything that happens on other threads is sent as one of these.
+#[derive(Debug)]
+enum UserEvent {
+    /// Use `MediaControls::next_event` to get the events.
+    MediaControls,
+    TrayIcon(TrayIconEvent),
+    Menu(MenuEvent),
+    Ipc(IpcRequest),
+    /// Sent by the sink after the song ended, with the `CurrentPlay::track` of that song.
+    TrackFinished(u64),
+    Notification(NotificationAction),
+    /// yt-dlp finished updating the playlist, with the error message if it failed.
+    PlaylistUpdated(Result<(), String>),
+    /// The config file changed.
+    ConfigChanged,
+    /// The output stream failed, like when the device disappeared. With `App::streams_opened` of that stream & the error message.
+    OutputError(u64, String),
+    /// Listed output devices, or the error message. `retry` is set for the listings of `output_device::RETRY_INTERVAL`.
+    OutputDevices { names: Result<Vec<String>, String>, retry: bool },
+}
+
+/// Song that is currently loaded in the sink, for the play statistics.
+struct CurrentPlay {
+    song: Song,
+    /// Unix timestamp.
+    start: u64,
+    duration: Duration,
+    /// Increases with every loaded song, so finished events of old songs can be ignored.
+    track: u64,
+}
+
 struct App {
     config: Config,
     window: Option<Window>,
-    tray_icon: Option<TrayIcon>,
+    tray: Option<Tray>,
     controls: Option<MediaControls>,
-    _stream: (OutputStream, OutputStreamHandle),
+    notifier: Option<Notifier>,
+    _stream: cpal::Stream,
     sink: Sink,
+    /// Increases with every opened stream, so errors of old streams can be ignored.
+    streams_opened: u64,
+    /// The stream failed, it's opened again once the devices are listed.
+    output_failed: bool,
+    last_output_error: Option<Instant>,
+    /// Name of the device that's playing.
+    output_device: String,
+    /// Chosen by the config or the user, None is the default device.
+    chosen_device: Option<String>,
+    /// Last listed devices, in the order of the tray menu.
+    output_devices: Vec<String>,
+    /// A listing after `output_device::RETRY_INTERVAL` is waiting.
+    retrying_devices: bool,
     volume: f32,
     muted: bool,
-    playlist: Playlist,
+    /// Repeat the current song.
+    repeat: bool,
+    favorites_only: bool,
+    playlists: Vec<NamedPlaylist>,
+    /// Index into `playlists`.
+    active_playlist: usize,
+    library: Library,
+    source_watcher: SourceWatcher,
+    current_play: Option<CurrentPlay>,
+    /// If yt-dlp is updating the playlist in the background.
+    updating_playlist: bool,
+    /// Amount of songs loaded, see `CurrentPlay::track`.
+    tracks_loaded: u64,
+    sleep_timer: Option<SleepTimer>,
+    /// Last shown `SleepTimer::describe`, so the tray is only updated when it changes.
+    sleep_status: Option<String>,
+    alarms: AlarmScheduler,
+    /// Volume ramping up after an alarm.
+    volume_ramp: Option<VolumeRamp>,
+    /// Error that stopped the event loop, returned from main.
+    fatal_error: Option<anyhow::Error>,
+    proxy: EventLoopProxy<UserEvent>,
 }
 
 impl App {
-    pub fn new(config: Config, playlist: Playlist) -> Result<App> {
-        let (stream, handle) = rodio::OutputStream::try_default()?;
-        let sink = Sink::try_new(&handle)?;
+    pub fn new(config: Config, playlists: Vec<NamedPlaylist>, library: Library, source_watcher: SourceWatcher, proxy: EventLoopProxy<UserEvent>) -> Result<App> {
+        let (stream, sink, output_device) = App::open_stream(&proxy, config.output_device.as_deref(), 0)?;
+        match &config.output_device {
+            Some(chosen) if *chosen != output_device => warn!("Output device \"{}\" isn't there, using \"{}\" until it is.", chosen, output_device),
+            _ => info!("Output device: {}", output_device),
+        }
+        let active_playlist = playlists.iter().position(|p| p.name.eq_ignore_ascii_case(&config.playlist)).unwrap_or(0);
+        let alarms = App::create_alarms(&config);
+        let config_proxy = proxy.clone();
+        config_reload::watch(config.config_file.clone().unwrap_or_else(paths::config_file), move || { let _ = config_proxy.send_event(UserEvent::ConfigChanged); });
 
-        Ok(App {
+        let mut app = App {
             volume: config.volume as f32,
             muted: false,
+            repeat: false,
+            favorites_only: config.favorites_only,
+            chosen_device: config.output_device.clone(),
             config,
             window: None,
-            tray_icon: None,
+            tray: None,
             controls: None,
-            _stream: (stream, handle),
+            notifier: None,
+            _stream: stream,
             sink,
-            playlist,
-        })
+            streams_opened: 0,
+            output_failed: false,
+            last_output_error: None,
+            output_device,
+            // Listed on another thread, it's slow on some systems.
+            output_devices: Vec::new(),
+            retrying_devices: false,
+            playlists,
+            active_playlist,
+            library,
+            source_watcher,
+            current_play: None,
+            updating_playlist: false,
+            tracks_loaded: 0,
+            sleep_timer: None,
+            sleep_status: None,
+            alarms,
+            volume_ramp: None,
+            fatal_error: None,
+            proxy,
+        };
+        app.update_song_states();
+        app.list_output_devices(Duration::ZERO);
+
+        Ok(app)
     }
 
     fn update_song(&mut self) -> Result<()> {
-        let song = self.playlist.seek(0).unwrap();
+        let Some(song) = self.playlist_mut().seek(0) else { return Ok(()) };
 
-        println!("Playing: {}", song.name());
+        info!("Playing: {}", song.name());
 
         if let Some(controls) = &mut self.controls {
             controls.set_metadata(MediaControlsMetadata {
@@ -61,22 +197,383 @@ impl App {
             })?;
         }
 
+        if let Some(notifier) = &self.notifier {
+            // The notification daemon may have gone away, not worth stopping the music for.
+            if let Err(err) = notifier.notify(&song, !self.sink.is_paused()) {
+                warn!("Failed to show notification: {}", err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Records the play of the current song, if there is one.
+    fn finish_play(&mut self, outcome: PlayOutcome) -> Result<()> {
+        if let Some(current_play) = self.current_play.take() {
+            let played = match outcome {
+                PlayOutcome::Completed => current_play.duration,
+                _ => self.sink.get_pos().min(current_play.duration),
+            };
+            self.library.record_play(&current_play.song, current_play.start, played, current_play.duration, outcome)?;
+            self.update_song_states();
+        }
+        Ok(())
+    }
+
+    fn playlist(&self) -> &Playlist {
+        &self.playlists[self.active_playlist].playlist
+    }
+
+    fn playlist_mut(&mut self) -> &mut Playlist {
+        &mut self.playlists[self.active_playlist].playlist
+    }
+
+    /// Updates the playlists with the song statistics, ratings, etc. from the library.
+    /// This also refreshes which songs are in the smart playlists.
+    fn update_song_states(&mut self) {
+        let now = unix_time_now();
+        for named in &mut self.playlists {
+            let weights = weights::song_weights(named.playlist.songs(), &self.library, &self.config.weight_options);
+            named.playlist.set_weights(weights);
+
+            let entries = named.playlist.songs().iter().map(|song| self.library.entry(song)).collect::<Vec<_>>();
+            let blocked = entries.iter()
+                .map(|entry| entry.is_some_and(|entry| entry.blocked || entry.unplayable.is_some()))
+                .collect();
+            let filtered = entries.iter()
+                .map(|entry| match entry {
+                    Some(entry) => (self.favorites_only && !entry.favorite)
+                        || matches!(&named.kind, PlaylistKind::Smart(smart) if !smart.matches(entry, now)),
+                    None => self.favorites_only || matches!(named.kind, PlaylistKind::Smart(_)),
+                })
+                .collect();
+            named.playlist.set_blocked(blocked);
+            named.playlist.set_filtered(filtered);
+        }
+    }
+
+    /// Stops the current song and continues with the playlist.
+    fn switch_playlist(&mut self, playlist_index: usize) -> Result<()> {
+        if playlist_index == self.active_playlist {
+            return Ok(());
+        }
+        self.finish_play(PlayOutcome::Stopped)?;
+        self.active_playlist = playlist_index;
+        // Continue after the song that was playing when last switched away from this playlist.
+        self.seek_song(if self.playlist().is_started() { 1 } else { 0 })
+    }
+
+    fn update_tray(&mut self) {
+        if let Some(tray) = &self.tray {
+            let entry = self.current_play.as_ref().and_then(|p| self.library.entry(&p.song));
+            let result = tray.update(&TrayState {
+                title: self.current_play.as_ref().map(|p| match p.song.artist() {
+                    Some(artist) => format!("{} - {}", artist, p.song.name()),
+                    None => p.song.name(),
+                }),
+                playing: self.is_playing(),
+                volume: self.volume,
+                muted: self.muted,
+                shuffle: self.playlist().shuffle_mode() != ShuffleMode::Normal,
+                repeat: self.repeat,
+                active_playlist: self.active_playlist,
+                updating: self.updating_playlist,
+                favorite: entry.is_some_and(|e| e.favorite),
+                rating: entry.and_then(|e| e.rating),
+                favorites_only: self.favorites_only,
+                sleep: self.sleep_status.clone(),
+                output_device: self.chosen_device.clone(),
+            });
+            if let Err(err) = result {
+                warn!("Failed to update tray: {}", err);
+            }
+        }
+    }
+
+    /// Toggles between no shuffle and the configured shuffle, for the current playlist.
+    fn toggle_shuffle(&mut self) {
+        let shuffle_mode = match (self.playlist().shuffle_mode(), self.config.shuffle) {
+            (ShuffleMode::Normal, ShuffleMode::Normal) => ShuffleMode::SmartRandom,
+            (ShuffleMode::Normal, shuffle_mode) => shuffle_mode,
+            _ => ShuffleMode::Normal,
+        };
+        self.playlist_mut().set_shuffle_mode(shuffle_mode);
+        self.update_tray();
+    }
+
+    /// Downloads new songs of the yt-dlp playlist in the background, they're picked up by the source watcher.
+    fn start_playlist_update(&mut self) {
+        if self.updating_playlist {
+            return;
+        }
+        let Some((url, directory)) = self.source_watcher.sources().iter().find_map(|source| match source {
+            PlaylistSource::YtDlp { url, directory } => Some((url.clone(), directory.clone())),
+            _ => None,
+        }) else { return };
+        let yt_dlp_path = self.config.yt_dlp_path.clone();
+        let ffmpeg_path = self.config.ffmpeg_path.clone();
+        let proxy = self.proxy.clone();
+        std::thread::spawn(move || {
+            let result = yt_dlp::update_playlist(&directory, &yt_dlp_path, &ffmpeg_path, &url).map_err(|err| err.to_string());
+            let _ = proxy.send_event(UserEvent::PlaylistUpdated(result));
+        });
+        self.updating_playlist = true;
+        self.update_tray();
+    }
+
+    /// Opens the folder of the current song in the file manager.
+    fn open_current_folder(&self) -> Result<()> {
+        let Some(current_play) = &self.current_play else { return Ok(()) };
+        let file = std::path::absolute(current_play.song.file())?;
+        #[cfg(target_os = "windows")]
+        {
+            use std::os::windows::process::CommandExt;
+            // Explorer doesn't understand the quoting of normal arguments.
+            std::process::Command::new("explorer").raw_arg(format!("/select,\"{}\"", file.display())).spawn()?;
+        }
+        #[cfg(not(target_os = "windows"))]
+        std::process::Command::new("xdg-open").arg(file.parent().unwrap_or(&file)).spawn()?;
         Ok(())
     }
 
+    /// Changes the library entry of the current song, and saves the library.
+    fn update_current_entry(&mut self, update: impl FnOnce(&mut library::LibraryEntry)) -> Result<Option<Song>> {
+        self.update_current_song(|library, song| {
+            if let Some(entry) = library.entry_mut(song) {
+                update(entry);
+            }
+        })
+    }
+
+    fn update_current_song(&mut self, update: impl FnOnce(&mut Library, &Song)) -> Result<Option<Song>> {
+        let Some(song) = self.current_play.as_ref().map(|p| p.song.clone()) else { return Ok(None) };
+        update(&mut self.library, &song);
+        self.library.save()?;
+        self.update_song_states();
+        self.update_tray();
+        Ok(Some(song))
+    }
+
+    /// 0 removes the rating.
+    fn rate_current(&mut self, rating: u8) -> Result<Option<Song>> {
+        self.update_current_song(|library, song| { library.set_rating(song, rating); })
+    }
+
+    fn toggle_favorite_current(&mut self) -> Result<Option<Song>> {
+        self.update_current_entry(|entry| entry.favorite = !entry.favorite)
+    }
+
+    /// Blocks the current song and skips to the next song.
+    fn block_current(&mut self) -> Result<Option<Song>> {
+        let song = self.update_current_entry(|entry| entry.blocked = true)?;
+        if song.is_some() {
+            // Skipped, not stopped, so blocking doesn't count as a play.
+            self.finish_play(PlayOutcome::Skipped)?;
+            self.seek_song(1)?;
+        }
+        Ok(song)
+    }
+
+    fn set_favorites_only(&mut self, favorites_only: bool) {
+        self.favorites_only = favorites_only;
+        self.update_song_states();
+        self.update_tray();
+    }
+
     fn seek_song(&mut self, offset: isize) -> Result<()> {
+        // Checked before the current song is stopped, so it isn't stopped for nothing.
+        if self.current_play.is_some() && !self.playlist().can_seek(offset) {
+            if offset < 0 {
+                debug!("No song before the first one, restarting it.");
+                self.sink.try_seek(Duration::ZERO).map_err(|err| anyhow!("Failed to restart the song: {}", err))?;
+            }
+            return Ok(());
+        }
+
+        // Seeking to the next song before the current one ended is a skip.
+        self.finish_play(if offset > 0 { PlayOutcome::Skipped } else { PlayOutcome::Stopped })?;
+
         let was_playing = !self.sink.is_paused();
         self.sink.clear();
-        if let Some(song) = self.playlist.seek(offset) {
-            song.sink_load(&mut self.sink, self.config.loudness_normalization)?;
-            self.update_song()?;
+        let mut offset = offset;
+        let mut failed = 0;
+        while let Some(song) = self.playlist_mut().seek(offset) {
+            let normalization = self.config.loudness_normalization;
+            let cached_gain = self.library.loudness_gain(&song, normalization);
+            let (duration, gain) = match song.sink_load(&mut self.sink, normalization, cached_gain) {
+                Ok(loaded) => loaded,
+                Err(err) => {
+                    // A bad file shouldn't stop the music, skip it from now on.
+                    warn!("Skipping unplayable song {:?}: {}", song.file(), err);
+                    self.sink.clear();
+                    if let Some(entry) = self.library.entry_mut(&song) {
+                        entry.unplayable = Some(err.to_string());
+                    }
+                    self.library.save()?;
+                    self.update_song_states();
+                    failed += 1;
+                    if failed >= self.playlist().songs().len() {
+                        break;
+                    }
+                    offset = 1;
+                    continue;
+                },
+            };
+            self.tracks_loaded += 1;
+            let track = self.tracks_loaded;
+            self.append_track_finished(track);
+            self.current_play = Some(CurrentPlay { song: song.clone(), start: unix_time_now(), duration, track });
+            if cached_gain.is_none() {
+                self.library.set_loudness_gain(&song, LoudnessGain { normalization, gain });
+                self.library.save()?;
+            }
             if was_playing {
                 self.sink.play()
             }
+            self.update_song()?;
+            self.update_tray();
+            break;
+        }
+        if self.current_play.is_none() {
+            error!("No playable songs.");
+            self.update_playback()?;
+        }
+        Ok(())
+    }
+
+    /// Sends `UserEvent::TrackFinished` when the sink gets to this point, after the song.
+    fn append_track_finished(&self, track: u64) {
+        let proxy = self.proxy.clone();
+        self.sink.append(rodio::source::EmptyCallback::<f32>::new(Box::new(move || {
+            let _ = proxy.send_event(UserEvent::TrackFinished(track));
+        })));
+    }
+
+    /// Switches to another output device, None for the default device.
+    fn set_output_device(&mut self, device: Option<String>) -> Result<()> {
+        self.chosen_device = device;
+        self.open_output_device()?;
+        self.update_tray();
+        Ok(())
+    }
+
+    /// Stream errors are sent as `UserEvent::OutputError` with `stream`.
+    fn open_stream(proxy: &EventLoopProxy<UserEvent>, device: Option<&str>, stream: u64) -> Result<(cpal::Stream, Sink, String), OutputDeviceError> {
+        let proxy = proxy.clone();
+        let mut failed = false;
+        output_device::open(device, move |err| {
+            // Only sent once, a broken stream may keep failing until it's replaced.
+            if !failed {
+                failed = true;
+                let _ = proxy.send_event(UserEvent::OutputError(stream, err.to_string()));
+            }
+        })
+    }
+
+    /// Opens the chosen output device, or the default device if it isn't there.
+    /// The current song continues on the new device at the same position.
+    fn open_output_device(&mut self) -> Result<()> {
+        let (stream, sink, device) = App::open_stream(&self.proxy, self.chosen_device.as_deref(), self.streams_opened + 1)?;
+        self.streams_opened += 1;
+        self.output_failed = false;
+        match &self.chosen_device {
+            Some(chosen) if *chosen != device => warn!("Output device \"{}\" isn't there, using \"{}\" until it's back.", chosen, device),
+            _ => info!("Output device: {}", device),
         }
+
+        let position = self.sink.get_pos();
+        if self.sink.is_paused() {
+            sink.pause();
+        }
+        // Stopped first, the finished event of the old sink would skip the song.
+        self.sink.stop();
+        self.sink = sink;
+        self._stream = stream;
+        self.output_device = device;
+        self.apply_volume();
+
+        let Some(current_play) = &self.current_play else { return Ok(()) };
+        let (song, track) = (current_play.song.clone(), current_play.track);
+        let normalization = self.config.loudness_normalization;
+        let cached_gain = self.library.loudness_gain(&song, normalization);
+        // Decode errors aren't Sync, so they're only kept as text.
+        let continue_error = |err: &dyn std::fmt::Display| anyhow!("Failed to continue {:?} on output device \"{}\": {}", song.file(), self.output_device, err);
+        song.sink_load(&mut self.sink, normalization, cached_gain).map_err(|err| continue_error(&err))?;
+        self.append_track_finished(track);
+        self.sink.try_seek(position).map_err(|err| continue_error(&err))?;
         Ok(())
     }
 
+    /// Lists the output devices on another thread after `delay`, sending `UserEvent::OutputDevices`.
+    /// Only one delayed listing is waiting at a time.
+    fn list_output_devices(&mut self, delay: Duration) {
+        let retry = !delay.is_zero();
+        if retry {
+            if self.retrying_devices {
+                return;
+            }
+            self.retrying_devices = true;
+        }
+        let proxy = self.proxy.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(delay);
+            let names = output_device::device_names().map_err(|err| err.to_string());
+            let _ = proxy.send_event(UserEvent::OutputDevices { names, retry });
+        });
+    }
+
+    /// Lists the output devices right away, for commands that use them.
+    fn refresh_output_devices(&mut self) -> Result<()> {
+        self.output_devices = output_device::device_names()?;
+        if let Some(tray) = &mut self.tray {
+            tray.set_output_devices(&self.output_devices)?;
+        }
+        Ok(())
+    }
+
+    /// Falls back to the default device when the device that's playing failed, and goes back to the chosen device once it's there again.
+    /// While the chosen device or the stream isn't working, the devices are listed again every `output_device::RETRY_INTERVAL`.
+    fn process_output_devices(&mut self, names: Result<Vec<String>, String>, retry: bool) -> Result<()> {
+        if retry {
+            self.retrying_devices = false;
+        }
+        match names {
+            Ok(names) => {
+                self.output_devices = names;
+                if let Some(tray) = &mut self.tray {
+                    tray.set_output_devices(&self.output_devices)?;
+                }
+            },
+            Err(err) => error!("Failed to list the output devices: {}", err),
+        }
+
+        let chosen_missing = self.chosen_device.as_ref().is_some_and(|chosen| *chosen != self.output_device);
+        let chosen_back = chosen_missing && self.chosen_device.as_ref().is_some_and(|chosen| self.output_devices.contains(chosen));
+        if self.output_failed || chosen_back {
+            log_error(self.open_output_device());
+            self.update_tray();
+        }
+        if self.output_failed || self.chosen_device.as_ref().is_some_and(|chosen| *chosen != self.output_device) {
+            self.list_output_devices(output_device::RETRY_INTERVAL);
+        }
+        Ok(())
+    }
+
+    fn process_output_error(&mut self, stream: u64, message: String) {
+        if stream != self.streams_opened {
+            return;
+        }
+        warn!("Output device \"{}\" failed: {}", self.output_device, message);
+        self.output_failed = true;
+        // A device that keeps failing is only opened again every `RETRY_INTERVAL`.
+        let delay = match self.last_output_error {
+            Some(last_output_error) if last_output_error.elapsed() < output_device::RETRY_INTERVAL => output_device::RETRY_INTERVAL,
+            _ => Duration::ZERO,
+        };
+        self.last_output_error = Some(Instant::now());
+        self.list_output_devices(delay);
+    }
+
     fn update_playback(&mut self) -> Result<()> {
         if let Some(controls) = &mut self.controls {
             if self.sink.empty() {
@@ -87,6 +584,7 @@ impl App {
                 controls.set_playback(MediaControlsPlayback::Playing(None))?;
             }
         }
+        self.update_tray();
         Ok(())
     }
 
@@ -95,6 +593,10 @@ impl App {
     }
 
     fn play(&mut self) -> Result<()> {
+        // Stopped, reload the current song.
+        if self.sink.empty() {
+            self.seek_song(0)?;
+        }
         self.sink.play();
         self.update_playback()?;
         Ok(())
@@ -107,20 +609,118 @@ impl App {
     }
 
     fn stop(&mut self) -> Result<()> {
+        self.finish_play(PlayOutcome::Stopped)?;
         self.sink.stop();
         self.update_playback()?;
         Ok(())
     }
 
     fn update_volume(&mut self) -> Result<()> {
+        self.apply_volume();
+        self.update_tray();
+        Ok(())
+    }
+
+    /// Sets the volume of the sink, with the fade out of the sleep timer & the ramp up of alarms.
+    fn apply_volume(&mut self) {
         if self.muted {
             self.sink.set_volume(0.0);
         } else {
-            self.sink.set_volume(self.volume as f32);
+            let now = Instant::now();
+            let fade = self.sleep_timer.as_ref().map(|t| t.fade(now, self.song_remaining())).unwrap_or(1.0);
+            let ramp = self.volume_ramp.map(|r| r.factor(now)).unwrap_or(1.0);
+            self.sink.set_volume(self.volume * fade * ramp);
+        }
+    }
+
+    /// Time left of the current song, None if it isn't playing.
+    fn song_remaining(&self) -> Option<Duration> {
+        let current_play = self.current_play.as_ref().filter(|_| self.is_playing())?;
+        Some(current_play.duration.saturating_sub(self.sink.get_pos()))
+    }
+
+    /// Starts playing the playlist of the alarm, unless something is already playing.
+    fn start_alarm(&mut self, alarm: Alarm) -> Result<()> {
+        if self.is_playing() {
+            // Ramping up from 0 would only interrupt the music.
+            info!("Alarm of {}, already playing.", alarm.time.format("%H:%M"));
+            return Ok(());
         }
+        info!("Alarm of {}.", alarm.time.format("%H:%M"));
+        if let Some(playlist_index) = alarm.playlist.as_ref().and_then(|name| self.playlists.iter().position(|p| p.name.eq_ignore_ascii_case(name))) {
+            self.switch_playlist(playlist_index)?;
+        }
+        if let Some(volume) = alarm.volume {
+            self.volume = volume as f32;
+        }
+        self.muted = false;
+        self.volume_ramp = Some(VolumeRamp::new(alarm.ramp));
+        self.apply_volume();
+        self.play()
+    }
+
+    /// Starts alarms that are due, and ramps up the volume.
+    /// Returns when the alarms need to be checked again.
+    fn process_alarms(&mut self) -> Result<Option<Instant>> {
+        for alarm_index in self.alarms.poll() {
+            let alarm = self.alarms.alarms()[alarm_index].clone();
+            self.start_alarm(alarm)?;
+        }
+        let now = Instant::now();
+        let ramp_check = self.volume_ramp.and_then(|r| r.next_check(now));
+        if self.volume_ramp.is_some() {
+            self.apply_volume();
+            if ramp_check.is_none() {
+                self.volume_ramp = None;
+            }
+        }
+        Ok([ramp_check, self.alarms.next_check()].into_iter().flatten().min())
+    }
+
+    fn set_sleep_timer(&mut self, sleep_timer: Option<SleepTimer>) {
+        self.sleep_timer = sleep_timer;
+        self.update_sleep_status();
+        self.apply_volume();
+    }
+
+    fn update_sleep_status(&mut self) {
+        let sleep_status = self.sleep_timer.as_ref().map(|t| t.describe(Instant::now(), self.song_remaining()));
+        if sleep_status != self.sleep_status {
+            self.sleep_status = sleep_status;
+            self.update_tray();
+        }
+    }
+
+    /// Ends the sleep timer, the volume is restored for when playback continues.
+    fn finish_sleep_timer(&mut self, event_loop: &ActiveEventLoop) -> Result<()> {
+        let Some(sleep_timer) = self.sleep_timer.take() else { return Ok(()) };
+        match sleep_timer.action {
+            SleepAction::Pause => {
+                info!("Sleep timer ended, pausing.");
+                self.pause()?;
+            },
+            SleepAction::Exit => {
+                info!("Sleep timer ended, exiting.");
+                event_loop.exit();
+            },
+        }
+        self.update_sleep_status();
+        self.apply_volume();
         Ok(())
     }
 
+    /// Fades out, and ends the sleep timer when the time is up.
+    /// Returns when the sleep timer needs to be checked again.
+    fn process_sleep_timer(&mut self, event_loop: &ActiveEventLoop) -> Result<Option<Instant>> {
+        let now = Instant::now();
+        if self.sleep_timer.as_ref().is_some_and(|t| t.is_done(now)) {
+            self.finish_sleep_timer(event_loop)?;
+        }
+        self.apply_volume();
+        self.update_sleep_status();
+        Ok(self.sleep_timer.as_ref().and_then(|t| t.next_check(now, self.song_remaining())))
+    }
+
     fn create_window(event_loop: &ActiveEventLoop) -> Result<Window> {
         Ok(event_loop.create_window(
             Window::default_attributes()
@@ -129,18 +729,7 @@ impl App {
         )?)
     }
 
-    fn create_tray_icon() -> Result<TrayIcon> {
-        // TODO: Icon (Icon as current song image.)
-        let icon = tray_icon::Icon::from_rgba(vec![255, 0, 255, 255], 1, 1)?;
-        Ok(TrayIconBuilder::new()
-            .with_title("yt-dlp-music-player")
-            .with_tooltip("yt-dlp-music-player\nLeft: Next\nRight: Previous\nMiddle: Exit")
-            .with_id("yt-dlp-music-player")
-            .with_icon(icon)
-            .build()?)
-    }
-
-    fn create_controls(window: &Window) -> Result<MediaControls> {
+    fn create_controls(window: &Window, proxy: EventLoopProxy<UserEvent>) -> Result<MediaControls> {
         #[allow(unused_assignments, unused_mut)]
         let mut hwnd: Option<*mut c_void> = None;
 
@@ -155,83 +744,511 @@ impl App {
 
         let controls = create_media_controls_multi_os(CreateMediaControlsMultiOSOptions {
             hwnd,
+            notifier: Arc::new(move || { let _ = proxy.send_event(UserEvent::MediaControls); }),
         })?;
 
         Ok(controls)
     }
 
-    fn process_media_events(&mut self, _event_loop: &ActiveEventLoop) -> Result<()> {
-        if self.controls.is_some() {
-            while let Some(event) = self.controls.as_mut().unwrap().next_event() {
-                match event {
-                    MediaControlsEvent::Play => self.play()?,
-                    MediaControlsEvent::Pause => self.pause()?,
-                    MediaControlsEvent::Stop => self.stop()?,
-                    MediaControlsEvent::Next => self.seek_song(1)?,
-                    MediaControlsEvent::Previous => self.seek_song(-1)?,
-                    MediaControlsEvent::VolumeToggleMute => {
-                        self.muted = !self.muted;
-                        self.update_volume()?;
-                    },
-                    MediaControlsEvent::VolumeMute => {
-                        self.muted = true;
-                        self.update_volume()?;
-                    },
-                    MediaControlsEvent::VolumeUnmute => {
-                        self.muted = false;
-                        self.update_volume()?;
-                    },
-                    MediaControlsEvent::SetVolume(volume) => {
-                        self.volume = volume.clamp(0.0, 1.0);
-                        self.update_volume()?;
-                    },
-                    MediaControlsEvent::VolumeDown => {
-                        self.volume = (self.volume - 0.1).clamp(0.0, 1.0);
-                        self.update_volume()?;
-                    },
-                    MediaControlsEvent::VolumeUp => {
-                        self.volume = (self.volume + 0.1).clamp(0.0, 1.0);
-                        self.update_volume()?;
+    fn process_media_events(&mut self) -> Result<()> {
+        while let Some(event) = self.controls.as_mut().and_then(|controls| controls.next_event()) {
+            match event {
+                MediaControlsEvent::Play => self.play()?,
+                MediaControlsEvent::Pause => self.pause()?,
+                MediaControlsEvent::Stop => self.stop()?,
+                MediaControlsEvent::Next => self.seek_song(1)?,
+                MediaControlsEvent::Previous => self.seek_song(-1)?,
+                MediaControlsEvent::VolumeToggleMute => {
+                    self.muted = !self.muted;
+                    self.update_volume()?;
+                },
+                MediaControlsEvent::VolumeMute => {
+                    self.muted = true;
+                    self.update_volume()?;
+                },
+                MediaControlsEvent::VolumeUnmute => {
+                    self.muted = false;
+                    self.update_volume()?;
+                },
+                MediaControlsEvent::SetVolume(volume) => {
+                    self.volume = volume.clamp(0.0, 1.0);
+                    self.update_volume()?;
+                },
+                MediaControlsEvent::VolumeDown => {
+                    self.volume = (self.volume - 0.1).clamp(0.0, 1.0);
+                    self.update_volume()?;
+                },
+                MediaControlsEvent::VolumeUp => {
+                    self.volume = (self.volume + 0.1).clamp(0.0, 1.0);
+                    self.update_volume()?;
+                },
+                MediaControlsEvent::Rate(rating) => {
+                    self.rate_current(rating)?;
+                },
+                _ => debug!("Unhandled media controls event: {:?}", event),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn process_tray_icon_event(&mut self, event: TrayIconEvent) -> Result<()> {
+        if let TrayIconEvent::Click { button, button_state: tray_icon::MouseButtonState::Down, id: _, position: _, rect: _ } = event {
+            match button {
+                tray_icon::MouseButton::Left => self.seek_song(1)?,
+                // The output devices in the menu are refreshed when it's opened.
+                tray_icon::MouseButton::Right => self.list_output_devices(Duration::ZERO),
+                // Middle used to exit but was too easy to hit by accident. (Use "Quit" in the menu.)
+                tray_icon::MouseButton::Middle => {},
+            }
+        }
+        Ok(())
+    }
+
+    fn process_menu_event(&mut self, event_loop: &ActiveEventLoop, event: MenuEvent) -> Result<()> {
+        match TrayAction::from_menu_event(&event) {
+            Some(TrayAction::PlayPause) => if self.is_playing() { self.pause()? } else { self.play()? },
+            Some(TrayAction::Next) => self.seek_song(1)?,
+            Some(TrayAction::Previous) => self.seek_song(-1)?,
+            Some(TrayAction::Volume(volume)) => {
+                self.volume = (volume as f32 / 100.0).clamp(0.0, 1.0);
+                self.muted = false;
+                self.update_volume()?;
+            },
+            Some(TrayAction::ToggleMute) => {
+                self.muted = !self.muted;
+                self.update_volume()?;
+            },
+            Some(TrayAction::ToggleShuffle) => self.toggle_shuffle(),
+            Some(TrayAction::ToggleRepeat) => {
+                self.repeat = !self.repeat;
+                self.update_tray();
+            },
+            Some(TrayAction::Playlist(playlist_index)) if playlist_index < self.playlists.len() => self.switch_playlist(playlist_index)?,
+            Some(TrayAction::UpdatePlaylist) => self.start_playlist_update(),
+            Some(TrayAction::OpenFolder) => self.open_current_folder()?,
+            Some(TrayAction::ToggleFavorite) => { self.toggle_favorite_current()?; },
+            Some(TrayAction::Block) => { self.block_current()?; },
+            Some(TrayAction::Rate(rating)) => { self.rate_current(rating)?; },
+            Some(TrayAction::ToggleFavoritesOnly) => self.set_favorites_only(!self.favorites_only),
+            Some(TrayAction::Sleep(minutes)) => {
+                let until = SleepUntil::Time(Instant::now() + Duration::from_secs(minutes as u64 * 60));
+                self.set_sleep_timer(Some(SleepTimer::new(until, self.config.sleep_action)));
+            },
+            Some(TrayAction::SleepAfterSong) => self.set_sleep_timer(Some(SleepTimer::new(SleepUntil::Songs(0), self.config.sleep_action))),
+            Some(TrayAction::SleepOff) => self.set_sleep_timer(None),
+            Some(TrayAction::OutputDevice(None)) => self.set_output_device(None)?,
+            Some(TrayAction::OutputDevice(Some(index))) if index < self.output_devices.len() => self.set_output_device(Some(self.output_devices[index].clone()))?,
+            Some(TrayAction::Quit) => event_loop.exit(),
+            _ => {},
+        }
+        Ok(())
+    }
+
+    fn process_track_finished(&mut self, event_loop: &ActiveEventLoop, track: u64) -> Result<()> {
+        // The song may have been changed right when it ended.
+        if self.current_play.as_ref().is_some_and(|p| p.track == track) {
+            self.finish_play(PlayOutcome::Completed)?;
+            if self.sleep_timer.as_mut().is_some_and(|t| t.song_finished()) {
+                // Load the next song paused, so playing again doesn't repeat the last song.
+                self.sink.pause();
+                self.seek_song(if self.repeat { 0 } else { 1 })?;
+                return self.finish_sleep_timer(event_loop);
+            }
+            self.seek_song(if self.repeat { 0 } else { 1 })?;
+            self.sink.play();
+        }
+        Ok(())
+    }
+
+    fn handle_ipc_command(&mut self, command: IpcCommand) -> IpcResponse {
+        let find_song = |playlist: &Playlist, song: &str| playlist.find_song(song)
+            .ok_or_else(|| format!("No song found for \"{}\".", song));
+
+        match command {
+            IpcCommand::Status => {
+                self.update_sleep_status();
+                let mut status = self.now_playing();
+                let queue = self.playlist().queue();
+                if let Some(next) = queue.first() {
+                    status.push_str(&format!("\nUp next: {} ({} song(s) queued)", next.name(), queue.len()));
+                }
+                if let Some(sleep_status) = &self.sleep_status {
+                    status.push_str(&format!("\nSleep timer: {}", sleep_status));
+                }
+                if let Some((_, time)) = self.alarms.next_alarm() {
+                    status.push_str(&format!("\nNext alarm: {}", time.format("%a %Y-%m-%d %H:%M")));
+                }
+                Ok(status)
+            },
+            IpcCommand::Play { query, number } => {
+                let matches = search::search(self.playlist().songs(), &query);
+                let song_index = match (number, matches.first()) {
+                    (_, None) => return Err(format!("No song found for \"{}\".", query)),
+                    (Some(number), _) => matches.get(number.wrapping_sub(1))
+                        .ok_or_else(|| format!("Only {} song(s) match \"{}\".", matches.len(), query))?.index,
+                    (None, Some(best)) if best.score >= search::CONFIDENT_SCORE => best.index,
+                    (None, Some(_)) => return Err(format!("{} \"{}\", did you mean:\n{}", ipc::NO_CLEAR_MATCH, query, self.format_matches(&matches, 10))),
+                };
+                self.play_song_now(song_index).map_err(|err| err.to_string())?;
+                Ok(format!("Playing: {}", self.playlist().songs()[song_index].name()))
+            },
+            IpcCommand::Search { query, limit } => {
+                let matches = search::search(self.playlist().songs(), &query);
+                if matches.is_empty() {
+                    Err(format!("No song found for \"{}\".", query))
+                } else {
+                    Ok(self.format_matches(&matches, limit))
+                }
+            },
+            IpcCommand::Rate { rating } => match self.rate_current(rating).map_err(|err| err.to_string())? {
+                Some(song) if rating == 0 => Ok(format!("Removed rating of: {}", song.name())),
+                Some(song) => Ok(format!("Rated {} star(s): {}", rating.min(5), song.name())),
+                None => Err("Not playing.".to_string()),
+            },
+            IpcCommand::Favorite => match self.toggle_favorite_current().map_err(|err| err.to_string())? {
+                Some(song) if self.library.entry(&song).is_some_and(|e| e.favorite) => Ok(format!("Added to favorites: {}", song.name())),
+                Some(song) => Ok(format!("Removed from favorites: {}", song.name())),
+                None => Err("Not playing.".to_string()),
+            },
+            IpcCommand::Block => match self.block_current().map_err(|err| err.to_string())? {
+                Some(song) => Ok(format!("Blocked: {}", song.name())),
+                None => Err("Not playing.".to_string()),
+            },
+            IpcCommand::Unblock { song } => {
+                let song = self.playlist().songs()[find_song(self.playlist(), &song)?].clone();
+                if let Some(entry) = self.library.entry_mut(&song) {
+                    entry.blocked = false;
+                }
+                self.library.save().map_err(|err| err.to_string())?;
+                self.update_song_states();
+                Ok(format!("Unblocked: {}", song.name()))
+            },
+            IpcCommand::FavoritesOnly { enabled } => {
+                self.set_favorites_only(enabled);
+                Ok(format!("Only playing favorites: {}", enabled))
+            },
+            IpcCommand::Playlist { name: None } => {
+                Ok(self.playlists.iter().enumerate().map(|(i, named)| format!(
+                    "{} {} ({} songs)",
+                    if i == self.active_playlist { "*" } else { " " },
+                    named.name,
+                    named.playlist.included_count(),
+                )).collect::<Vec<_>>().join("\n"))
+            },
+            IpcCommand::Playlist { name: Some(name) } => {
+                let playlist_index = self.playlists.iter().position(|p| p.name.eq_ignore_ascii_case(&name))
+                    .ok_or_else(|| format!("No playlist named \"{}\".", name))?;
+                self.switch_playlist(playlist_index).map_err(|err| err.to_string())?;
+                let named = &self.playlists[playlist_index];
+                if named.playlist.playable_count() == 0 {
+                    Ok(format!("Playlist {} has no playable songs.", named.name))
+                } else if named.playlist.included_count() == 0 {
+                    Ok(format!("Playing playlist: {} (No songs match, playing all songs that aren't blocked.)", named.name))
+                } else {
+                    Ok(format!("Playing playlist: {}", named.name))
+                }
+            },
+            IpcCommand::Export { file, overwrite, format, playlist, history } => {
+                let format = format.or_else(|| playlist_file::PlaylistFileFormat::from_path(&file))
+                    .ok_or_else(|| "Unknown playlist file format, use a .m3u8 or .xspf file or set --format.".to_string())?;
+                let (title, songs) = if history {
+                    ("History".to_string(), self.playlist().history())
+                } else {
+                    let named = match playlist {
+                        Some(name) => self.playlists.iter().find(|p| p.name.eq_ignore_ascii_case(&name))
+                            .ok_or_else(|| format!("No playlist named \"{}\".", name))?,
+                        None => &self.playlists[self.active_playlist],
+                    };
+                    (named.name.clone(), named.playlist.included_songs())
+                };
+                let file = playlist_file::export_file(&paths::exports_directory(), &file).map_err(|err| err.to_string())?;
+                playlist_file::write(&file, format, &title, &songs, overwrite).map_err(|err| err.to_string())?;
+                Ok(format!("Exported {} song(s) to {:?}", songs.len(), file))
+            },
+            IpcCommand::Sleep { duration: None, songs: None, cancel: false, .. } => {
+                self.update_sleep_status();
+                Ok(self.sleep_status.clone().unwrap_or(SLEEP_TIMER_OFF.to_string()))
+            },
+            IpcCommand::Sleep { cancel: true, .. } => {
+                self.set_sleep_timer(None);
+                Ok(SLEEP_TIMER_OFF.to_string())
+            },
+            IpcCommand::Sleep { duration, songs, exit, pause, cancel: false, .. } => {
+                let until = match (duration, songs) {
+                    (Some(duration), _) => {
+                        let duration = sleep_timer::parse_sleep_duration(&duration)
+                            .ok_or_else(|| format!("Invalid duration \"{}\", expected minutes or a number followed by s, m or h (like \"1h30m\")", duration))?;
+                        SleepUntil::Time(Instant::now() + duration)
                     },
-                    _ => println!("{:#?}", event),
+                    (None, Some(songs)) => SleepUntil::Songs(songs),
+                    (None, None) => return Err("Expected a duration or --songs.".to_string()),
+                };
+                let action = if exit { SleepAction::Exit } else if pause { SleepAction::Pause } else { self.config.sleep_action };
+                self.set_sleep_timer(Some(SleepTimer::new(until, action)));
+                Ok(self.sleep_status.clone().unwrap_or_default())
+            },
+            IpcCommand::Queue(QueueCommand::Add { song }) => {
+                let song_index = find_song(self.playlist(), &song)?;
+                self.playlist_mut().enqueue(song_index);
+                Ok(format!("Queued: {}", self.playlist().songs()[song_index].name()))
+            },
+            IpcCommand::Queue(QueueCommand::Next { song }) => {
+                let song_index = find_song(self.playlist(), &song)?;
+                self.playlist_mut().play_next(song_index);
+                Ok(format!("Playing next: {}", self.playlist().songs()[song_index].name()))
+            },
+            IpcCommand::Queue(QueueCommand::Remove { position }) => {
+                match self.playlist_mut().dequeue(position.wrapping_sub(1)) {
+                    Some(song) => Ok(format!("Removed: {}", song.name())),
+                    None => Err(format!("No song at position {} in the queue.", position)),
+                }
+            },
+            IpcCommand::Queue(QueueCommand::Move { from, to }) => {
+                if self.playlist_mut().move_queued(from.wrapping_sub(1), to.wrapping_sub(1)) {
+                    Ok(format!("Moved song from position {} to {}.", from, to))
+                } else {
+                    Err(format!("Queue has {} song(s).", self.playlist().queue().len()))
                 }
+            },
+            IpcCommand::Queue(QueueCommand::Clear) => {
+                self.playlist_mut().clear_queue();
+                Ok("Cleared the queue.".to_string())
+            },
+            IpcCommand::Queue(QueueCommand::List) => {
+                let mut lines = vec![self.now_playing()];
+                let queue = self.playlist().queue();
+                if queue.is_empty() {
+                    lines.push("Queue is empty.".to_string());
+                } else {
+                    lines.push("Queue:".to_string());
+                    lines.extend(queue.iter().enumerate().map(|(i, song)| format!("{:>3}. {}", i + 1, song.name())));
+                }
+                lines.push(format!("Then: {} ({:?})", self.playlists[self.active_playlist].name, self.playlist().shuffle_mode()));
+                Ok(lines.join("\n"))
+            },
+            IpcCommand::OutputDevice { name: None, default: false } => {
+                self.refresh_output_devices().map_err(|err| err.to_string())?;
+                Ok(self.output_devices.iter().map(|name| format!(
+                    "{} {}",
+                    if *name == self.output_device { "*" } else { " " },
+                    name,
+                )).collect::<Vec<_>>().join("\n"))
+            },
+            IpcCommand::OutputDevice { name, .. } => {
+                let device = match name {
+                    Some(name) => {
+                        self.refresh_output_devices().map_err(|err| err.to_string())?;
+                        Some(output_device::find_device(&self.output_devices, &name)?.clone())
+                    },
+                    None => None,
+                };
+                self.set_output_device(device).map_err(|err| err.to_string())?;
+                Ok(format!("Output device: {}", self.output_device))
+            },
+        }
+    }
+
+    fn now_playing(&self) -> String {
+        match &self.current_play {
+            Some(current_play) if self.is_playing() => format!("Playing: {}", current_play.song.name()),
+            Some(current_play) => format!("Paused: {}", current_play.song.name()),
+            None => "Not playing.".to_string(),
+        }
+    }
+
+    fn format_matches(&self, matches: &[search::SearchMatch], limit: usize) -> String {
+        matches.iter()
+            .take(limit)
+            .enumerate()
+            .map(|(i, m)| format!("{:>3}. {:>3.0}%  {}", i + 1, m.score * 100.0, self.playlist().songs()[m.index].name()))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Stops the current song and plays the song right away.
+    fn play_song_now(&mut self, song_index: usize) -> Result<()> {
+        // The song is chosen by the user, so the current one isn't counted as skipped.
+        self.finish_play(PlayOutcome::Stopped)?;
+        self.playlist_mut().play_next(song_index);
+        self.seek_song(1)?;
+        self.play()
+    }
+
+    /// `force` checks the sources right away, instead of waiting for the next check.
+    fn process_source_changes(&mut self, force: bool) -> Result<()> {
+        let changed = if force { self.source_watcher.check() } else { self.source_watcher.poll() };
+        for source_index in changed {
+            let songs = self.library.scan_source(source_index)?;
+            // Imported playlist files only have the songs in the file.
+            let added = self.playlists.iter_mut()
+                .filter(|p| !matches!(p.kind, PlaylistKind::File(_)))
+                .map(|p| p.playlist.add_songs(songs.clone()))
+                .max()
+                .unwrap_or(0);
+            if added > 0 {
+                info!("Found {} new song(s).", added);
+                self.update_song_states();
             }
         }
+        Ok(())
+    }
+
+    fn create_tray(&self) -> Result<Tray> {
+        let playlist_names = self.playlists.iter().map(|p| p.name.clone()).collect::<Vec<_>>();
+        let can_update = self.source_watcher.sources().iter().any(|s| matches!(s, PlaylistSource::YtDlp { .. }));
+        let mut tray = Tray::new(&playlist_names, can_update)?;
+        tray.set_output_devices(&self.output_devices)?;
+        Ok(tray)
+    }
+
+    fn create_alarms(config: &Config) -> AlarmScheduler {
+        let alarms = AlarmScheduler::new(config.alarms.clone(), config.missed_alarms);
+        if let Some((_, time)) = alarms.next_alarm() {
+            info!("Next alarm: {}", time.format("%a %Y-%m-%d %H:%M"));
+        }
+        alarms
+    }
+
+    /// Connects to the notification service if notifications are enabled, or disconnects if not.
+    fn update_notifier(&mut self) {
+        if !self.config.notifications || !cfg!(target_os = "linux") {
+            self.notifier = None;
+            return;
+        }
+        if self.notifier.is_some() {
+            return;
+        }
+        let proxy = self.proxy.clone();
+        match Notifier::new(move |action| { let _ = proxy.send_event(UserEvent::Notification(action)); }) {
+            Ok(notifier) => self.notifier = Some(notifier),
+            Err(err) => warn!("Failed to connect to the notification service: {}", err),
+        }
+    }
+
+    /// Reloads the changed config file, and applies what can be changed while playing.
+    fn process_config_changes(&mut self) -> Result<()> {
+        let config = Config::load().context("Failed to reload the config, the old config is kept")?;
+        let changed = self.config.changed_keys(&config);
+        if changed.is_empty() {
+            return Ok(());
+        }
+        info!("Config reloaded, changed: {}", changed.join(", "));
+        let old_config = std::mem::replace(&mut self.config, config);
 
+        for key in &changed {
+            match *key {
+                "config.volume" => {
+                    self.volume = self.config.volume as f32;
+                    self.apply_volume();
+                },
+                "config.shuffle" => {
+                    // Playlists that were toggled to no shuffle stay that way.
+                    for named in self.playlists.iter_mut().filter(|p| !matches!(p.kind, PlaylistKind::File(_))) {
+                        if named.playlist.shuffle_mode() == old_config.shuffle {
+                            named.playlist.set_shuffle_mode(self.config.shuffle);
+                        }
+                    }
+                },
+                "config.loudness-normalization" => info!("Loudness normalization {:?} is used from the next song.", self.config.loudness_normalization),
+                "config.favorites-only" => self.favorites_only = self.config.favorites_only,
+                "config.sync-interval" => self.source_watcher.set_interval(self.config.sync_interval),
+                "config.notifications" => self.update_notifier(),
+                "config.output-device" => self.set_output_device(self.config.output_device.clone())?,
+                "alarm" | "config.missed-alarms" => self.alarms = App::create_alarms(&self.config),
+                _ => {},
+            }
+        }
+        if changed.iter().any(|key| matches!(*key, "smart-playlist" | "config.playlist-files")) {
+            self.reload_playlists()?;
+        }
+        self.update_song_states();
+        self.update_tray();
+
+        let restart = changed.into_iter().filter(|key| RESTART_KEYS.contains(key)).collect::<Vec<_>>();
+        if !restart.is_empty() {
+            warn!("Restart to apply the changes to: {}", restart.join(", "));
+        }
         Ok(())
     }
 
-    fn process_tray_icon_events(&mut self, event_loop: &ActiveEventLoop) -> Result<()> {
-        while let Ok(event) = TrayIconEvent::receiver().try_recv() {
-            if let TrayIconEvent::Click { button, button_state: tray_icon::MouseButtonState::Down, id: _, position: _, rect: _ } = event {
-                match button {
-                    tray_icon::MouseButton::Left => self.seek_song(1)?,
-                    tray_icon::MouseButton::Right => self.seek_song(-1)?,
-                    tray_icon::MouseButton::Middle => event_loop.exit(),
-                }
+    /// Rebuilds the smart playlists & playlist files from the config.
+    /// Playlists that are still there keep their play order & history.
+    fn reload_playlists(&mut self) -> Result<()> {
+        // The library playlist is always first, and has every song.
+        let songs = self.playlists[0].playlist.songs().to_vec();
+        let mut playlists = load_playlists(&self.config, &songs)?;
+        let active_name = self.playlists[self.active_playlist].name.clone();
+
+        let mut old_playlists = std::mem::take(&mut self.playlists);
+        for named in &mut playlists {
+            let same = old_playlists.iter().position(|old| old.name == named.name && match (&old.kind, &named.kind) {
+                (PlaylistKind::Library, PlaylistKind::Library) | (PlaylistKind::Smart(_), PlaylistKind::Smart(_)) => true,
+                (PlaylistKind::File(old_file), PlaylistKind::File(file)) => old_file == file,
+                _ => false,
+            });
+            if let Some(index) = same {
+                // The rules of a smart playlist may have changed, the songs are refreshed by `update_song_states`.
+                std::mem::swap(&mut named.playlist, &mut old_playlists.remove(index).playlist);
             }
         }
+        self.playlists = playlists;
+
+        match self.playlists.iter().position(|p| p.name == active_name) {
+            Some(index) => self.active_playlist = index,
+            None => {
+                warn!("Playlist \"{}\" was removed, switching to \"{}\".", active_name, LIBRARY_PLAYLIST);
+                self.finish_play(PlayOutcome::Stopped)?;
+                self.active_playlist = 0;
+                self.update_song_states();
+                self.seek_song(if self.playlist().is_started() { 1 } else { 0 })?;
+            },
+        }
+
+        // The playlist menu is only made when the tray is created.
+        if self.tray.is_some() {
+            self.tray = None;
+            self.tray = Some(self.create_tray()?);
+        }
         Ok(())
     }
 }
 
-impl ApplicationHandler for App {
-    fn resumed(&mut self, event_loop: &ActiveEventLoop) {
-        let window = App::create_window(event_loop).unwrap();
-        let tray_icon = App::create_tray_icon().unwrap();
-        let controls = App::create_controls(&window).unwrap();
+impl App {
+    /// Creates the window, tray & media controls, and starts playing.
+    fn start(&mut self, event_loop: &ActiveEventLoop) -> Result<()> {
+        let window = App::create_window(event_loop)?;
+        let tray = self.create_tray()?;
+        let controls = App::create_controls(&window, self.proxy.clone())?;
 
         self.window = Some(window);
-        self.tray_icon = Some(tray_icon);
+        self.tray = Some(tray);
         self.controls = Some(controls);
 
+        self.update_notifier();
+
         if self.config.start_paused {
-            self.pause().unwrap();
+            self.pause()?;
         }
-        self.seek_song(0).unwrap();
+        self.seek_song(0)?;
 
         if self.config.hide_console {
-            hide_console().unwrap();
+            hide_console()?;
+        }
+        Ok(())
+    }
+}
+
+/// Errors while handling events are logged, one failed action shouldn't stop the music.
+fn log_error<T>(result: Result<T>) -> Option<T> {
+    result.map_err(|err| error!("{:#}", err)).ok()
+}
+
+impl ApplicationHandler<UserEvent> for App {
+    fn resumed(&mut self, event_loop: &ActiveEventLoop) {
+        if let Err(err) = self.start(event_loop) {
+            self.fatal_error = Some(err);
+            event_loop.exit();
         }
     }
 
@@ -242,57 +1259,48 @@ impl ApplicationHandler for App {
         }
     }
 
-    fn new_events(&mut self, event_loop: &ActiveEventLoop, cause: winit::event::StartCause) {
-        match cause {
-            winit::event::StartCause::Poll => {
-                self.process_media_events(&event_loop).unwrap();
-                self.process_tray_icon_events(&event_loop).unwrap();
-
-                if !self.sink.is_paused() && self.sink.empty() {
-                    self.seek_song(1).unwrap();
-                    self.sink.play();
+    fn user_event(&mut self, event_loop: &ActiveEventLoop, event: UserEvent) {
+        match event {
+            UserEvent::MediaControls => { log_error(self.process_media_events()); },
+            UserEvent::TrayIcon(event) => { log_error(self.process_tray_icon_event(event)); },
+            UserEvent::Menu(event) => { log_error(self.process_menu_event(event_loop, event)); },
+            UserEvent::Ipc(request) => {
+                let response = self.handle_ipc_command(request.command.clone());
+                request.respond(response);
+            },
+            UserEvent::TrackFinished(track) => { log_error(self.process_track_finished(event_loop, track)); },
+            UserEvent::Notification(NotificationAction::Next) => { log_error(self.seek_song(1)); },
+            UserEvent::Notification(NotificationAction::PlayPause) => { log_error(if self.is_playing() { self.pause() } else { self.play() }); },
+            UserEvent::PlaylistUpdated(result) => {
+                self.updating_playlist = false;
+                match result {
+                    Ok(()) => { log_error(self.process_source_changes(true)); },
+                    Err(err) => error!("Failed to update playlist: {}", err),
                 }
-
-                std::thread::sleep(Duration::from_millis(100));
-            }
-            winit::event::StartCause::Init => event_loop.set_control_flow(ControlFlow::Poll),
-            _ => {}
+                self.update_tray();
+            },
+            UserEvent::ConfigChanged => { log_error(self.process_config_changes()); },
+            UserEvent::OutputError(stream, message) => self.process_output_error(stream, message),
+            UserEvent::OutputDevices { names, retry } => { log_error(self.process_output_devices(names, retry)); },
         }
     }
-}
-
-
 
+    fn about_to_wait(&mut self, event_loop: &ActiveEventLoop) {
+        // Nothing else happens without an event, so only wake up for the next source check, sleep timer & alarm.
+        log_error(self.process_source_changes(false));
+        let next_check = [
+            log_error(self.process_sleep_timer(event_loop)).flatten(),
+            log_error(self.process_alarms()).flatten(),
+        ].into_iter().flatten().fold(self.source_watcher.next_check(), Instant::min);
+        event_loop.set_control_flow(ControlFlow::WaitUntil(next_check));
+    }
 
+    fn exiting(&mut self, _event_loop: &ActiveEventLoop) {
+        log_error(self.finish_play(PlayOutcome::Stopped));
+    }
+}
 
-fn update_playlist(playlist_archive_directory: &PathBuf, yt_dlp_path: &PathBuf, ffmpeg_path: &PathBuf, url: &url::Url) -> Result<()> {
-    // Update playlist archive directory with yt-dlp
-    println!("Updating playlist archive. . .");
-    let mut playlist_archive_file = playlist_archive_directory.clone();
-    playlist_archive_file.push("archive.txt");
 
-    let mut cmd = Command::new(yt_dlp_path)
-        // .arg("-f").arg("bestaudio")
-        .arg("--ffmpeg-location").arg(ffmpeg_path)
-        .arg("-x")
-        .arg("--audio-format").arg("m4a") // TODO: What is the best sounding audio format for tiniest file size?
-        .arg("--paths").arg(playlist_archive_directory)
-        // Loudness normalization.
-        // NOTE: this is disable and instead implemented in song::Song due to being unable to reliably normalize the loudness.
-        // .arg("--postprocessor-args").arg("ffmpeg:-af volume=0dB")
-        .arg("-o").arg("%(id)s %(title)s.%(ext)s")
-        .arg("--download-archive").arg(&playlist_archive_file)
-        .arg("--write-thumbnail")
-        .arg(url.to_string())
-        // .arg("https://www.youtube.com/watch?v=dQw4w9WgXcQ")
-        .stdout(Stdio::inherit()) // TODO: Is this fine to do?
-        .spawn()?;
-
-    cmd.wait()?;
-
-    println!("Done updating playlist archive.");
-    Ok(())
-}
 
 
 
@@ -307,56 +1315,211 @@ fn hide_console() -> Result<()> {
 
 
 
-fn main() -> Result<()> {
-    let config = Config::load()?;
+/// Resolves the configured playlists & directories, optionally downloading new songs.
+/// Sends the command to the player. If `play` finds no clear match in a terminal, asks which of the candidates to play.
+fn send_ipc_command(port: u16, command: &IpcCommand) -> Result<IpcResponse> {
+    if let IpcCommand::Sleep { watch: true, .. } = command {
+        return watch_sleep_timer(port, command);
+    }
+    let response = ipc::send(port, command)?;
+    let (IpcCommand::Play { query, number: None }, Err(candidates)) = (command, &response) else { return Ok(response) };
+    if !candidates.starts_with(ipc::NO_CLEAR_MATCH) || !io::stdin().is_terminal() {
+        return Ok(response);
+    }
 
-    // Get playlist ID
-    let playlist_id: String = match url::Url::parse(&config.yt_playlist) {
-        Ok(url) => {
-            if let Some(playlist_id) =
-                url.query_pairs().find_map(
-                    |(name, value)| {
-                        if name == "list" {
-                            Some(value)
-                        } else {
-                            None
-                        }
-                    },
-                )
-            {
-                playlist_id.to_string()
+    println!("{}", candidates);
+    print!("Number of the song to play (Enter to cancel): ");
+    io::stdout().flush()?;
+    let mut line = String::new();
+    io::stdin().read_line(&mut line)?;
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(Ok("Cancelled.".to_string()));
+    }
+    let number = line.parse().map_err(|_| anyhow!("Invalid number \"{}\".", line))?;
+    ipc::send(port, &IpcCommand::Play { query: query.clone(), number: Some(number) })
+}
+
+/// Prints the sleep timer every second on the same line, until it's off.
+fn watch_sleep_timer(port: u16, command: &IpcCommand) -> Result<IpcResponse> {
+    let mut last_length = 0;
+    loop {
+        let status = match ipc::send(port, command) {
+            Ok(Ok(status)) => status,
+            Ok(Err(message)) => return Ok(Err(message)),
+            // Sleep timers may exit the player.
+            Err(_) if last_length > 0 => {
+                println!();
+                return Ok(Ok("Player exited.".to_string()));
+            },
+            Err(err) => return Err(err),
+        };
+        if status == SLEEP_TIMER_OFF {
+            println!();
+            return Ok(Ok(status));
+        }
+        // Pads with spaces to overwrite longer statuses.
+        print!("\r{:<1$}", status, last_length);
+        io::stdout().flush()?;
+        last_length = status.len();
+        std::thread::sleep(Duration::from_secs(1));
+    }
+}
+
+fn load_sources(config: &Config, update: bool) -> Result<Vec<PlaylistSource>> {
+    let mut sources: Vec<PlaylistSource> = vec![];
+
+    if let Some(yt_playlist) = &config.yt_playlist {
+        let url = yt_dlp::playlist_url(yt_playlist)?;
+
+        // Get playlist directory
+        let playlists_directory = paths::playlists_directory();
+        paths::migrate_playlists(&playlists_directory, &url)?;
+        // Resolving the directory name runs yt-dlp, that's only needed once the playlist is updated.
+        let playlist_directory = yt_dlp::playlist_directory(&playlists_directory, &config.yt_dlp_path, &url, update)?;
+        fs::create_dir_all(&playlist_directory)?;
+
+        if update {
+            yt_dlp::update_playlist(
+                &playlist_directory,
+                &config.yt_dlp_path,
+                &config.ffmpeg_path,
+                &url,
+            )?;
+        }
+
+        sources.push(PlaylistSource::YtDlp { url, directory: playlist_directory });
+    }
+
+    for local_directory in &config.local_directories {
+        sources.push(PlaylistSource::LocalDirectory { directory: local_directory.clone() });
+    }
+
+    Ok(sources)
+}
+
+/// The library playlist with all songs, then the smart playlists & playlist files.
+fn load_playlists(config: &Config, songs: &[Song]) -> Result<Vec<NamedPlaylist>> {
+    let mut playlists = vec![NamedPlaylist { name: LIBRARY_PLAYLIST.to_string(), kind: PlaylistKind::Library, playlist: Playlist::new(songs.to_vec(), config.shuffle) }];
+    for smart in &config.smart_playlists {
+        playlists.push(NamedPlaylist { name: smart.name.clone(), kind: PlaylistKind::Smart(smart.clone()), playlist: Playlist::new(songs.to_vec(), config.shuffle) });
+    }
+    for playlist_file in &config.playlist_files {
+        let (file_songs, unresolved) = playlist_file::resolve(playlist_file, playlist_file::read(playlist_file)?, songs);
+        info!("Playlist file: {:?} ({} songs)", playlist_file, file_songs.len());
+        for entry in unresolved {
+            warn!("Song of playlist file {:?} not found: {}", playlist_file, entry.name());
+        }
+        if file_songs.is_empty() {
+            continue;
+        }
+        playlists.push(NamedPlaylist {
+            name: config::playlist_file_name(playlist_file),
+            kind: PlaylistKind::File(playlist_file.clone()),
+            playlist: Playlist::new(file_songs, ShuffleMode::Normal),
+        });
+    }
+    Ok(playlists)
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            // Errors before the logging is set up, like config errors, are only printed.
+            if tracing::dispatcher::has_been_set() {
+                error!("{:#}", err);
             } else {
-                panic!("Invalid URL.");
+                eprintln!("Error: {:#}", err);
             }
+            ExitCode::from(error::exit_code(&err))
+        },
+    }
+}
+
+fn run() -> Result<()> {
+    if config::run_write_command()? {
+        return Ok(());
+    }
+    let config = Config::load()?;
+    // Only the player writes to the log file, not the other commands.
+    let log_directory = logging::init(&config.log_level, config.log_format, config.log_file && config.command.is_none())?;
+    if let Some(log_directory) = log_directory {
+        info!("Logging to {:?}", log_directory);
+    }
+
+    match &config.command {
+        Some(Command::Config(ConfigCommand::Check)) => {
+            // Loading the config already checked it.
+            config.print_effective();
+            return Ok(());
+        },
+        // Already done by `run_write_command`.
+        Some(Command::Config(_)) => return Ok(()),
+        Some(Command::Stats { format, limit }) => {
+            let library = Library::load(&load_sources(&config, false)?)?;
+            return stats::print_stats(&library, *format, *limit);
+        },
+        Some(Command::Weights) => {
+            let library = Library::load(&load_sources(&config, false)?)?;
+            return weights::print_weights(&library, &config.weight_options);
+        },
+        Some(Command::ListDevices) => {
+            return Ok(output_device::print_devices()?);
+        },
+        Some(Command::Verify) => {
+            let mut library = Library::load(&load_sources(&config, false)?)?;
+            return verify::verify_library(&mut library);
+        },
+        Some(Command::Ipc(command)) => {
+            return match send_ipc_command(config.ipc_port, command)? {
+                Ok(message) => {
+                    println!("{}", message);
+                    Ok(())
+                },
+                Err(message) => Err(anyhow!(message)),
+            };
+        },
+        None => {},
+    }
+
+    match &config.config_file {
+        Some(config_file) => info!("Config file: {:?}", config_file),
+        None => info!("No config file, expected at {:?}", paths::config_file()),
+    }
+    let sources = load_sources(&config, !config.skip_playlist_update)?;
+    for source in &sources {
+        match source {
+            PlaylistSource::YtDlp { url, directory } => info!("Playlist: {} ({:?})", url, directory),
+            PlaylistSource::LocalDirectory { directory } => info!("Local directory: {:?}", directory),
         }
-        Err(_) => config.yt_playlist.clone(),
-    };
-    println!("Playlist ID: {}", &playlist_id);
-
-    // Get playlist directory
-    let mut playlist_directory = std::env::current_dir()?;
-    playlist_directory.push(&playlist_id);
-    fs::create_dir_all(&playlist_directory)?;
-    println!("Playlist archive: {:#?}", &playlist_directory);
-
-    if !config.skip_playlist_update {
-        update_playlist(
-            &playlist_directory,
-            &config.yt_dlp_path,
-            &config.ffmpeg_path,
-            &url::Url::parse(&format!(
-                "https://www.youtube.com/playlist?list={}",
-                &playlist_id
-            ))?,
-        )?;
-    }
-
-    let songs = Song::load_playlist_directory(&playlist_directory)?;
-    let playlist = Playlist::new(songs);
-
-    let event_loop = EventLoop::new()?;
-    let mut app = App::new(config, playlist)?;
+    }
+
+    let mut library = Library::load(&sources)?;
+    let songs = library.scan()?;
+    let playlists = load_playlists(&config, &songs)?;
+    let source_watcher = SourceWatcher::new(sources, config.sync_interval);
+
+    let event_loop = EventLoop::<UserEvent>::with_user_event().build()?;
+
+    let proxy = event_loop.create_proxy();
+    TrayIconEvent::set_event_handler(Some(move |event| { let _ = proxy.send_event(UserEvent::TrayIcon(event)); }));
+    let proxy = event_loop.create_proxy();
+    MenuEvent::set_event_handler(Some(move |event| { let _ = proxy.send_event(UserEvent::Menu(event)); }));
+
+    if config.ipc_port != 0 {
+        let proxy = event_loop.create_proxy();
+        // If sending fails the player is closing, dropping the request responds to the client.
+        if let Err(err) = ipc::start_server(config.ipc_port, move |request| { let _ = proxy.send_event(UserEvent::Ipc(request)); }) {
+            error!("Failed to start IPC server on port {}: {}", config.ipc_port, err);
+        }
+    }
+
+    let mut app = App::new(config, playlists, library, source_watcher, event_loop.create_proxy())?;
     event_loop.run_app(&mut app)?;
 
-    Ok(())
+    match app.fatal_error {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
 }
@@ -5,19 +5,32 @@
         - Annoying to use event receiver
         - Bad error type
 
-    Only windows is currently implemented.
+    Only windows is currently implemented, other platforms get media controls that do nothing.
     & Extra stuff like volume controls are N.Y.I.
 */
 
 #![allow(dead_code)]
 
-use std::{ffi::c_void, time::Duration};
-use anyhow::{anyhow, Result};
+use std::{ffi::c_void, sync::Arc, time::Duration};
+use thiserror::Error;
 
 // TODO: Move all MediaControls stuff to a trait, so that it's harder to fuck up
 
 
 
+#[derive(Debug, Error)]
+pub enum MediaControlsError {
+    #[error("Media controls require a window handle.")]
+    MissingWindow,
+    #[cfg(target_os = "windows")]
+    #[error("Media controls failed: {0}")]
+    Windows(#[from] ::windows::core::Error),
+}
+
+pub type Result<T, E = MediaControlsError> = std::result::Result<T, E>;
+
+
+
 #[derive(Debug, Clone)]
 pub enum MediaControlsEvent {
     Play,
@@ -34,6 +47,10 @@ pub enum MediaControlsEvent {
     SetVolume(f32),
     VolumeDown,
     VolumeUp,
+
+    /// Rate the current song, from 0 (no rating) to 5 stars.
+    /// NOTE: Windows media controls have no rating buttons, so this is never sent there.
+    Rate(u8),
 }
 
 #[derive(Debug, Clone)]
@@ -59,12 +76,19 @@ pub struct MediaControlsMetadata {
 mod windows;
 #[cfg(target_os = "windows")]
 pub use self::windows::MediaControls;
+#[cfg(not(target_os = "windows"))]
+mod other;
+#[cfg(not(target_os = "windows"))]
+pub use self::other::MediaControls;
+
 
 
+/// Called from any thread when there's a new event, use `MediaControls::next_event` to get it.
+pub type MediaControlsNotifier = Arc<dyn Fn() + Send + Sync>;
 
-#[derive(Debug)]
 pub struct CreateMediaControlsMultiOSOptions {
     pub hwnd: Option<*mut c_void>,
+    pub notifier: MediaControlsNotifier,
 }
 
 /// MediaControls::new() may have different parameters for each platform.
@@ -73,9 +97,14 @@ pub fn create_media_controls_multi_os(options: CreateMediaControlsMultiOSOptions
     #[cfg(target_os = "windows")]
     {
         if let Some(hwnd) = options.hwnd {
-            Ok(windows::MediaControls::new(hwnd)?)
+            Ok(windows::MediaControls::new(hwnd, options.notifier)?)
         } else {
-            Err(anyhow!("createMediaControlsMultiOS options requires hwnd to be set."))
+            Err(MediaControlsError::MissingWindow)
         }
     }
+    #[cfg(not(target_os = "windows"))]
+    {
+        let _ = options;
+        Ok(other::MediaControls::new())
+    }
 }
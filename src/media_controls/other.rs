@@ -0,0 +1,25 @@
+// Media controls aren't implemented on this platform yet, so this does nothing.
+
+use super::{MediaControlsEvent, MediaControlsMetadata, MediaControlsPlayback, Result};
+
+
+
+pub struct MediaControls;
+
+impl MediaControls {
+    pub fn new() -> MediaControls {
+        MediaControls
+    }
+
+    pub fn next_event(&mut self) -> Option<MediaControlsEvent> {
+        None
+    }
+
+    pub fn set_playback(&mut self, _playback: MediaControlsPlayback) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn set_metadata(&mut self, _metadata: MediaControlsMetadata) -> Result<()> {
+        Ok(())
+    }
+}
@@ -3,8 +3,8 @@
 
 use std::{ffi::c_void, sync::{Arc, Mutex}, thread};
 use windows::{core::HSTRING, Foundation::{EventRegistrationToken, TimeSpan, TypedEventHandler, Uri}, Media::{Control::GlobalSystemMediaTransportControlsSessionManager, MediaPlaybackStatus, MediaPlaybackType, SystemMediaTransportControls, SystemMediaTransportControlsButton, SystemMediaTransportControlsButtonPressedEventArgs, SystemMediaTransportControlsDisplayUpdater, SystemMediaTransportControlsTimelineProperties}, Storage::Streams::RandomAccessStreamReference, Win32::{Foundation::{HWND, LPARAM, LRESULT, WPARAM}, System::{LibraryLoader::GetModuleHandleW, WinRT::ISystemMediaTransportControlsInterop}, UI::WindowsAndMessaging::{CallNextHookEx, DispatchMessageW, GetMessageW, SetWindowsHookExW, TranslateMessage, UnhookWindowsHookEx, HC_ACTION, HHOOK, KBDLLHOOKSTRUCT, MSG, WH_KEYBOARD_LL, WM_KEYDOWN}}};
-use anyhow::Result;
-use super::{MediaControlsEvent, MediaControlsMetadata, MediaControlsPlayback};
+use tracing::{error, warn};
+use super::{MediaControlsEvent, MediaControlsMetadata, MediaControlsNotifier, MediaControlsPlayback, Result};
 
 /*
     Media volume controls *REALLY* suck on Windows.
@@ -17,7 +17,20 @@ use super::{MediaControlsEvent, MediaControlsMetadata, MediaControlsPlayback};
 // I just sorta made this fast because I actually have 0 clue on how to properly implement this.
 // Also this is very jank, when toggling mute if theres other media playing, it may just break.
 
-static mut EVENT_QUEUES: Vec<Arc<Mutex<Vec<MediaControlsEvent>>>> = vec![];
+/// Events are received on other threads, the notifier wakes up whoever handles them.
+struct EventQueue {
+    events: Mutex<Vec<MediaControlsEvent>>,
+    notifier: MediaControlsNotifier,
+}
+
+impl EventQueue {
+    fn push(&self, event: MediaControlsEvent) {
+        self.events.lock().unwrap().push(event);
+        (self.notifier)();
+    }
+}
+
+static mut EVENT_QUEUES: Vec<Arc<EventQueue>> = vec![];
 
 unsafe extern "system" fn hook_proc(n_code: i32, w_param: WPARAM, l_param: LPARAM) -> LRESULT {
     
@@ -50,7 +63,6 @@ unsafe extern "system" fn hook_proc(n_code: i32, w_param: WPARAM, l_param: LPARA
             Ok(true) => {
                 // Add to queues
                 EVENT_QUEUES.iter().for_each(|event_queue| {
-                    let mut event_queue = event_queue.lock().unwrap();
                     event_queue.push(media_event.clone());
                 });
 
@@ -65,26 +77,35 @@ unsafe extern "system" fn hook_proc(n_code: i32, w_param: WPARAM, l_param: LPARA
 }
 
 static mut HOOK_PROC_INITIALIZED: bool = false;
-unsafe fn init_hook_proc(event_queue: Arc<Mutex<Vec<MediaControlsEvent>>>) -> Result<()> {
+unsafe fn init_hook_proc(event_queue: Arc<EventQueue>) -> Result<()> {
     EVENT_QUEUES.push(event_queue);
 
     if HOOK_PROC_INITIALIZED { return Ok(()) }
     HOOK_PROC_INITIALIZED = true;
 
+    // Without the hook the volume keys just aren't hijacked, the other media controls still work.
     thread::spawn(|| {
-        let h_instance = GetModuleHandleW(None).unwrap();
-        let hook_id = SetWindowsHookExW(WH_KEYBOARD_LL, Some(hook_proc), h_instance, 0).unwrap();
-        if hook_id.is_invalid() {
-            panic!("Invalid hook!");
-        }
-    
+        let hook_id = match GetModuleHandleW(None).and_then(|h_instance| SetWindowsHookExW(WH_KEYBOARD_LL, Some(hook_proc), h_instance, 0)) {
+            Ok(hook_id) if !hook_id.is_invalid() => hook_id,
+            Ok(_) => {
+                error!("Failed to hook volume keys: invalid hook");
+                return;
+            },
+            Err(err) => {
+                error!("Failed to hook volume keys: {}", err);
+                return;
+            },
+        };
+
         let mut msg: MSG = std::mem::zeroed();
         while GetMessageW(&mut msg, HWND(std::ptr::null_mut()), 0, 0).into() {
             let _ = TranslateMessage(&msg);
             DispatchMessageW(&msg);
         }
         
-        UnhookWindowsHookEx(hook_id).unwrap();
+        if let Err(err) = UnhookWindowsHookEx(hook_id) {
+            warn!("Failed to unhook volume keys: {}", err);
+        }
     });
 
     Ok(())
@@ -96,12 +117,12 @@ pub struct MediaControls {
     controls: SystemMediaTransportControls,
     display_updater: SystemMediaTransportControlsDisplayUpdater,
     timeline_properties: SystemMediaTransportControlsTimelineProperties,
-    event_queue: Arc<Mutex<Vec<MediaControlsEvent>>>,
+    event_queue: Arc<EventQueue>,
     button_handler_token: Option<EventRegistrationToken>,
 }
 
 impl MediaControls {
-    pub fn new(hwnd: *mut c_void) -> Result<MediaControls> {
+    pub fn new(hwnd: *mut c_void, notifier: MediaControlsNotifier) -> Result<MediaControls> {
         let interop: ISystemMediaTransportControlsInterop = windows::core::factory::<
             SystemMediaTransportControls,
             ISystemMediaTransportControlsInterop,
@@ -113,7 +134,7 @@ impl MediaControls {
 
         let mut controls = MediaControls {
             controls, display_updater, timeline_properties,
-            event_queue: Arc::new(Mutex::new(Vec::new())),
+            event_queue: Arc::new(EventQueue { events: Mutex::new(Vec::new()), notifier }),
             button_handler_token: None,
         };
 
@@ -139,7 +160,7 @@ impl MediaControls {
 
         let button_handler = TypedEventHandler::new(
             move |_, args: &Option<_>| {
-                let args: &SystemMediaTransportControlsButtonPressedEventArgs = args.as_ref().unwrap();
+                let Some(args): Option<&SystemMediaTransportControlsButtonPressedEventArgs> = args.as_ref() else { return Ok(()) };
                 let button = args.Button()?;
 
                 let event: Option<MediaControlsEvent> = match button {
@@ -154,7 +175,6 @@ impl MediaControls {
                 };
 
                 if let Some(event) = event {
-                    let mut event_queue = event_queue.lock().unwrap();
                     event_queue.push(event);
                 }
 
@@ -195,7 +215,7 @@ impl MediaControls {
 
 
     pub fn next_event(&mut self) -> Option<MediaControlsEvent> {
-        let mut event_queue = self.event_queue.lock().unwrap();
+        let mut event_queue = self.event_queue.events.lock().unwrap();
         if event_queue.is_empty() {
             None
         } else {
@@ -261,7 +281,9 @@ impl MediaControls {
 
 impl Drop for MediaControls {
     fn drop(&mut self) {
-        self.destroy().expect("Failed to destroy MediaControls");
+        if let Err(err) = self.destroy() {
+            warn!("Failed to destroy media controls: {}", err);
+        }
     }
 }
 
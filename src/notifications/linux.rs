@@ -0,0 +1,219 @@
+// Uses org.freedesktop.Notifications over D-Bus.
+
+use std::{collections::HashMap, sync::{atomic::{AtomicU32, Ordering}, Arc}, thread};
+use anyhow::Result;
+use zbus::{blocking::Connection, zvariant::Value};
+use crate::song::Song;
+use super::NotificationAction;
+
+
+
+#[zbus::proxy(
+    interface = "org.freedesktop.Notifications",
+    default_service = "org.freedesktop.Notifications",
+    default_path = "/org/freedesktop/Notifications"
+)]
+trait Notifications {
+    #[allow(clippy::too_many_arguments)]
+    fn notify(
+        &self,
+        app_name: &str,
+        replaces_id: u32,
+        app_icon: &str,
+        summary: &str,
+        body: &str,
+        actions: &[&str],
+        hints: HashMap<&str, Value<'_>>,
+        expire_timeout: i32,
+    ) -> zbus::Result<u32>;
+
+    #[zbus(signal)]
+    fn action_invoked(&self, id: u32, action_key: String) -> zbus::Result<()>;
+}
+
+/// The body may contain markup, so it needs to be escaped.
+fn escape_markup(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+
+
+pub struct Notifier {
+    proxy: NotificationsProxyBlocking<'static>,
+    /// ID of the last notification, 0 if there is none.
+    notification_id: Arc<AtomicU32>,
+}
+
+impl Notifier {
+    /// Connects to the session bus, `on_action` is called on another thread when an action button is clicked.
+    pub fn new(on_action: impl Fn(NotificationAction) + Send + 'static) -> Result<Notifier> {
+        Notifier::with_connection(Connection::session()?, on_action)
+    }
+
+    fn with_connection(connection: Connection, on_action: impl Fn(NotificationAction) + Send + 'static) -> Result<Notifier> {
+        let proxy = NotificationsProxyBlocking::new(&connection)?;
+        let notification_id = Arc::new(AtomicU32::new(0));
+
+        let signals = proxy.receive_action_invoked()?;
+        let id = Arc::clone(&notification_id);
+        thread::spawn(move || {
+            for signal in signals {
+                let Ok(args) = signal.args() else { continue };
+                // Other programs' notifications send this signal too.
+                if args.id != id.load(Ordering::Relaxed) {
+                    continue;
+                }
+                if let Some(action) = NotificationAction::from_key(&args.action_key) {
+                    on_action(action);
+                }
+            }
+        });
+
+        Ok(Notifier { proxy, notification_id })
+    }
+
+    /// Shows the song, replacing the previous notification.
+    pub fn notify(&self, song: &Song, playing: bool) -> Result<()> {
+        let cover = song.cover().map(|file| url::Url::from_file_path(&file).map(|u| u.to_string()).unwrap_or_default());
+
+        let mut hints = HashMap::new();
+        if let Some(cover) = &cover {
+            hints.insert("image-path", Value::from(cover.as_str()));
+        }
+        // Don't keep every song in the notification history.
+        hints.insert("transient", Value::from(true));
+
+        let actions = [
+            NotificationAction::PlayPause.key(), if playing { "Pause" } else { "Play" },
+            NotificationAction::Next.key(), "Next",
+        ];
+        let id = self.proxy.notify(
+            "yt-dlp-music-player",
+            self.notification_id.load(Ordering::Relaxed),
+            "audio-x-generic",
+            &song.name(),
+            &escape_markup(&song.artist().unwrap_or_default()),
+            &actions,
+            hints,
+            -1,
+        )?;
+        self.notification_id.store(id, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, io::{BufRead, BufReader}, process::{Child, Command, Stdio}, sync::{mpsc, Mutex}, time::Duration};
+    use zbus::{blocking::connection::Builder, zvariant::OwnedValue};
+
+    /// Private session bus, so the test doesn't show notifications on the desktop.
+    struct TestBus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl TestBus {
+        /// None if dbus-daemon isn't installed.
+        fn start() -> Option<TestBus> {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .ok()?;
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().unwrap()).read_line(&mut address).unwrap();
+            Some(TestBus { daemon, address: address.trim().to_string() })
+        }
+
+        fn connect(&self) -> Connection {
+            Builder::address(self.address.as_str()).unwrap().build().unwrap()
+        }
+    }
+
+    impl Drop for TestBus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    #[derive(Debug)]
+    struct ReceivedNotification {
+        replaces_id: u32,
+        summary: String,
+        body: String,
+        actions: Vec<String>,
+        hints: HashMap<String, OwnedValue>,
+    }
+
+    /// Stand-in for the notification daemon of the desktop, remembers the notifications.
+    struct StandInDaemon {
+        received: Arc<Mutex<Vec<ReceivedNotification>>>,
+    }
+
+    const NOTIFICATION_ID: u32 = 42;
+
+    #[zbus::interface(name = "org.freedesktop.Notifications")]
+    impl StandInDaemon {
+        #[allow(clippy::too_many_arguments)]
+        fn notify(&self, _app_name: &str, replaces_id: u32, _app_icon: &str, summary: &str, body: &str, actions: Vec<String>, hints: HashMap<String, OwnedValue>, _expire_timeout: i32) -> u32 {
+            self.received.lock().unwrap().push(ReceivedNotification { replaces_id, summary: summary.to_string(), body: body.to_string(), actions, hints });
+            NOTIFICATION_ID
+        }
+    }
+
+    #[test]
+    fn notifications() {
+        let Some(bus) = TestBus::start() else {
+            eprintln!("dbus-daemon isn't installed, skipping the notification test.");
+            return;
+        };
+        let received = Arc::new(Mutex::new(vec![]));
+        let daemon = Builder::address(bus.address.as_str()).unwrap()
+            .serve_at("/org/freedesktop/Notifications", StandInDaemon { received: Arc::clone(&received) }).unwrap()
+            .name("org.freedesktop.Notifications").unwrap()
+            .build().unwrap();
+
+        let (actions, action_receiver) = mpsc::channel();
+        let notifier = Notifier::with_connection(bus.connect(), move |action| actions.send(action).unwrap()).unwrap();
+
+        let directory = env::temp_dir().join(format!("yt-dlp-music-player-test-notifications-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let cover = directory.join("abc123 Song.jpg");
+        fs::write(&cover, []).unwrap();
+        let song = Song::new(directory.join("abc123 Song.m4a"), Some("abc123".to_string()), "Song".to_string(), Some("Tom & Jerry".to_string()), None);
+        notifier.notify(&song, true).unwrap();
+        notifier.notify(&song, false).unwrap();
+
+        let received = std::mem::take(&mut *received.lock().unwrap());
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].replaces_id, 0);
+        // The second notification replaces the first.
+        assert_eq!(received[1].replaces_id, NOTIFICATION_ID);
+        assert_eq!(received[0].summary, "Song");
+        assert_eq!(received[0].body, "Tom &amp; Jerry");
+        assert_eq!(received[0].actions, vec!["play-pause", "Pause", "next", "Next"]);
+        assert_eq!(received[1].actions, vec!["play-pause", "Play", "next", "Next"]);
+        let image_path = received[0].hints.get("image-path").and_then(|path| String::try_from(path.try_clone().unwrap()).ok());
+        assert_eq!(image_path, Some(url::Url::from_file_path(&cover).unwrap().to_string()));
+        assert_eq!(received[0].hints.get("transient").and_then(|transient| bool::try_from(transient).ok()), Some(true));
+
+        // Buttons of other notifications are ignored.
+        let emit = |id: u32, action: &str| daemon.emit_signal(None::<()>, "/org/freedesktop/Notifications", "org.freedesktop.Notifications", "ActionInvoked", &(id, action)).unwrap();
+        emit(NOTIFICATION_ID + 1, "next");
+        emit(NOTIFICATION_ID, "unknown");
+        emit(NOTIFICATION_ID, "next");
+        emit(NOTIFICATION_ID, "play-pause");
+        let timeout = Duration::from_secs(5);
+        assert_eq!(action_receiver.recv_timeout(timeout), Ok(NotificationAction::Next));
+        assert_eq!(action_receiver.recv_timeout(timeout), Ok(NotificationAction::PlayPause));
+        assert!(action_receiver.recv_timeout(Duration::from_millis(200)).is_err());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
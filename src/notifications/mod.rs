@@ -0,0 +1,58 @@
+
+/*
+    Desktop notifications on song change, with Next & Play/Pause buttons.
+    Every notification replaces the previous one, so they don't pile up.
+
+    Only Linux is implemented, Windows already shows the media controls overlay.
+*/
+
+#[cfg(not(target_os = "linux"))]
+use anyhow::Result;
+#[cfg(not(target_os = "linux"))]
+use crate::song::Song;
+
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotificationAction {
+    Next,
+    PlayPause,
+}
+
+impl NotificationAction {
+    fn key(&self) -> &'static str {
+        match self {
+            NotificationAction::Next => "next",
+            NotificationAction::PlayPause => "play-pause",
+        }
+    }
+
+    fn from_key(key: &str) -> Option<NotificationAction> {
+        match key {
+            "next" => Some(NotificationAction::Next),
+            "play-pause" => Some(NotificationAction::PlayPause),
+            _ => None,
+        }
+    }
+}
+
+
+
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(target_os = "linux")]
+pub use self::linux::Notifier;
+
+#[cfg(not(target_os = "linux"))]
+pub struct Notifier;
+
+#[cfg(not(target_os = "linux"))]
+impl Notifier {
+    pub fn new(_on_action: impl Fn(NotificationAction) + Send + 'static) -> Result<Notifier> {
+        Err(anyhow::anyhow!("Desktop notifications are only supported on Linux."))
+    }
+
+    pub fn notify(&self, _song: &Song, _playing: bool) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,116 @@
+/*
+    Audio output devices, chosen by the name the system gives them.
+    Devices may disappear while playing (like Bluetooth headphones that disconnect),
+    the default device is used until the chosen device is there again.
+
+    The stream is made here instead of by rodio's `OutputStream`, which only prints stream errors.
+    A lost device is noticed by the stream error, listing the devices is slow, so they're only listed on another thread when needed.
+*/
+
+use std::time::Duration;
+use rodio::{cpal::{self, traits::{HostTrait, StreamTrait}, FromSample, SizedSample}, source::UniformSourceIterator, DeviceTrait, Sink};
+use thiserror::Error;
+
+
+
+/// How often the devices are listed while the chosen device isn't there, to go back to it once it is.
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Error)]
+pub enum OutputDeviceError {
+    #[error("Failed to list the output devices: {0}")]
+    Devices(#[from] rodio::DevicesError),
+    #[error("No output device")]
+    NoDevice,
+    #[error("Failed to open output device \"{device}\": {source}")]
+    Stream { device: String, source: rodio::StreamError },
+}
+
+pub type Result<T, E = OutputDeviceError> = std::result::Result<T, E>;
+
+/// Names of the output devices, devices without a name are left out.
+pub fn device_names() -> Result<Vec<String>> {
+    Ok(cpal::default_host().output_devices()?.filter_map(|device| device.name().ok()).collect())
+}
+
+pub fn default_device_name() -> Option<String> {
+    cpal::default_host().default_output_device().and_then(|device| device.name().ok())
+}
+
+/// Opens the device named `name`, or the default device if it's None or isn't there.
+/// Returns the stream, which must be kept until the sink isn't used anymore, and the name of the device that was opened.
+/// `on_error` is called on the audio thread, like when the device disappears.
+pub fn open(name: Option<&str>, on_error: impl FnMut(cpal::StreamError) + Send + 'static) -> Result<(cpal::Stream, Sink, String)> {
+    let host = cpal::default_host();
+    let device = match name {
+        Some(name) => host.output_devices()?.find(|device| device.name().is_ok_and(|n| n == name)),
+        None => None,
+    };
+    let device = match device {
+        Some(device) => device,
+        None => host.default_output_device().ok_or(OutputDeviceError::NoDevice)?,
+    };
+    let device_name = device.name().unwrap_or_else(|_| "Unknown device".to_string());
+    let stream_error = |source| OutputDeviceError::Stream { device: device_name.clone(), source };
+
+    let config = device.default_output_config().map_err(|err| stream_error(rodio::StreamError::DefaultStreamConfigError(err)))?;
+    let (sink, queue) = Sink::new_idle();
+    // Same as rodio does for its mixer, the songs are converted to the format of the device.
+    let source = UniformSourceIterator::<_, f32>::new(queue, config.channels(), config.sample_rate().0);
+    let stream_config = config.config();
+    let stream = match config.sample_format() {
+        cpal::SampleFormat::F32 => build_stream::<f32>(&device, &stream_config, source, on_error),
+        cpal::SampleFormat::F64 => build_stream::<f64>(&device, &stream_config, source, on_error),
+        cpal::SampleFormat::I8 => build_stream::<i8>(&device, &stream_config, source, on_error),
+        cpal::SampleFormat::I16 => build_stream::<i16>(&device, &stream_config, source, on_error),
+        cpal::SampleFormat::I32 => build_stream::<i32>(&device, &stream_config, source, on_error),
+        cpal::SampleFormat::U8 => build_stream::<u8>(&device, &stream_config, source, on_error),
+        cpal::SampleFormat::U16 => build_stream::<u16>(&device, &stream_config, source, on_error),
+        cpal::SampleFormat::U32 => build_stream::<u32>(&device, &stream_config, source, on_error),
+        _ => Err(cpal::BuildStreamError::StreamConfigNotSupported),
+    }.map_err(|err| stream_error(rodio::StreamError::BuildStreamError(err)))?;
+    stream.play().map_err(|err| stream_error(rodio::StreamError::PlayStreamError(err)))?;
+    Ok((stream, sink, device_name))
+}
+
+fn build_stream<T: SizedSample + FromSample<f32>>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mut source: impl Iterator<Item = f32> + Send + 'static,
+    on_error: impl FnMut(cpal::StreamError) + Send + 'static,
+) -> std::result::Result<cpal::Stream, cpal::BuildStreamError> {
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], _| data.iter_mut().for_each(|sample| *sample = source.next().map(T::from_sample).unwrap_or(T::EQUILIBRIUM)),
+        on_error,
+        None,
+    )
+}
+
+/// Finds the device of `query` in `names`, by the full name, or by a part of the name if only one device has it.
+/// Case is ignored.
+pub fn find_device<'a>(names: &'a [String], query: &str) -> Result<&'a String, String> {
+    if let Some(name) = names.iter().find(|name| name.eq_ignore_ascii_case(query)) {
+        return Ok(name);
+    }
+    let lowercase = query.to_lowercase();
+    let matches = names.iter().filter(|name| name.to_lowercase().contains(&lowercase)).collect::<Vec<_>>();
+    match matches.as_slice() {
+        [name] => Ok(name),
+        [] => Err(format!("No output device named \"{}\", the devices are:\n{}", query, names.join("\n"))),
+        matches => Err(format!("Multiple output devices match \"{}\":\n{}", query, matches.iter().map(|name| name.as_str()).collect::<Vec<_>>().join("\n"))),
+    }
+}
+
+/// Prints the devices for the "list-devices" command.
+pub fn print_devices() -> Result<()> {
+    let default = default_device_name();
+    for name in device_names()? {
+        if Some(&name) == default.as_ref() {
+            println!("{} (default)", name);
+        } else {
+            println!("{}", name);
+        }
+    }
+    Ok(())
+}
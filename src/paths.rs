@@ -0,0 +1,158 @@
+/*
+    Where files are stored, so it doesn't matter which directory the player is started from.
+        Config: "$XDG_CONFIG_HOME/yt-dlp-music-player/config.toml", "%APPDATA%\yt-dlp-music-player\config.toml", . . .
+        Data (downloaded playlists, library indices & exported playlists): "$XDG_DATA_HOME/yt-dlp-music-player", "%APPDATA%\yt-dlp-music-player", . . .
+        Cache (covers & loudness analysis): "$XDG_CACHE_HOME/yt-dlp-music-player", "%LOCALAPPDATA%\yt-dlp-music-player", . . .
+        State (logs): "$XDG_STATE_HOME/yt-dlp-music-player", "%LOCALAPPDATA%\yt-dlp-music-player", . . .
+        Runtime (IPC token): "$XDG_RUNTIME_DIR/yt-dlp-music-player", or else the state directory.
+
+    The XDG variables are used on every platform if set, otherwise the platform directories are used.
+    Older versions stored everything in the working directory, that's moved over once by `migrate_playlists`.
+*/
+
+use std::{env, fs, path::{Path, PathBuf}};
+use anyhow::{anyhow, Result};
+use tracing::info;
+
+
+
+const APP_DIRECTORY: &str = "yt-dlp-music-player";
+const CONFIG_FILE_NAME: &str = "config.toml";
+/// Cache of the resolved playlist directory names, see `yt_dlp::playlist_directory`.
+pub const PLAYLISTS_FILE_NAME: &str = "playlists.json";
+
+/// `variable` if it's an absolute path, as the XDG spec says relative paths should be ignored.
+fn xdg_directory(variable: &str) -> Option<PathBuf> {
+    env::var_os(variable).map(PathBuf::from).filter(|path| path.is_absolute())
+}
+
+/// Falls back to the working directory if there's no home directory at all.
+fn app_directory(variable: &str, platform_directory: fn() -> Option<PathBuf>) -> PathBuf {
+    xdg_directory(variable)
+        .or_else(platform_directory)
+        .map(|directory| directory.join(APP_DIRECTORY))
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+pub fn config_directory() -> PathBuf {
+    app_directory("XDG_CONFIG_HOME", dirs::config_dir)
+}
+
+/// Default config file, `--config` overrides this.
+pub fn config_file() -> PathBuf {
+    config_directory().join(CONFIG_FILE_NAME)
+}
+
+pub fn data_directory() -> PathBuf {
+    app_directory("XDG_DATA_HOME", dirs::data_dir)
+}
+
+/// Parent directory of the yt-dlp playlist archives.
+pub fn playlists_directory() -> PathBuf {
+    data_directory().join("playlists")
+}
+
+/// Playlist files written by the "export" command, it can't write anywhere else.
+pub fn exports_directory() -> PathBuf {
+    data_directory().join("exports")
+}
+
+/// Hash of the absolute path, for file names that are unique per path.
+pub fn path_hash(path: &Path) -> u64 {
+    let absolute = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    // FNV-1a, the std hasher may change between Rust versions.
+    absolute.to_string_lossy().bytes()
+        .fold(0xcbf29ce484222325u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Library index & play log of a source directory, nothing is written in the source directory itself.
+/// Named "<directory name>-<hash of the absolute path>", so sources with the same directory name don't share an index.
+pub fn library_directory(source_directory: &Path) -> PathBuf {
+    let absolute = std::path::absolute(source_directory).unwrap_or_else(|_| source_directory.to_path_buf());
+    let name = absolute.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or("library".to_string());
+    data_directory().join("library").join(format!("{}-{:016x}", name, path_hash(&absolute)))
+}
+
+/// Token other programs need to control the player over IPC on `port`, see `ipc`.
+/// In the runtime directory if there is one, as it's private to the user & cleared on logout.
+pub fn ipc_token_file(port: u16) -> PathBuf {
+    xdg_directory("XDG_RUNTIME_DIR")
+        .or_else(dirs::runtime_dir)
+        .map(|directory| directory.join(APP_DIRECTORY))
+        .unwrap_or_else(state_directory)
+        .join(format!("ipc-{}.token", port))
+}
+
+pub fn cache_directory() -> PathBuf {
+    app_directory("XDG_CACHE_HOME", || dirs::cache_dir().or_else(dirs::data_local_dir))
+}
+
+pub fn state_directory() -> PathBuf {
+    app_directory("XDG_STATE_HOME", || dirs::state_dir().or_else(dirs::data_local_dir))
+}
+
+
+
+/// The config file used to be read from the working directory, it's copied to the config directory if there's none yet.
+/// Returns the legacy file if it was copied.
+pub fn migrate_config_file(config_file: &Path) -> Result<Option<PathBuf>> {
+    let legacy_file = PathBuf::from(CONFIG_FILE_NAME);
+    if config_file.exists() || !legacy_file.is_file() {
+        return Ok(None);
+    }
+    if let Some(parent) = config_file.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::copy(&legacy_file, config_file)?;
+    Ok(Some(legacy_file))
+}
+
+/// Moves playlist archives of older versions from the working directory to `playlists_directory`.
+/// These are the directories listed in "./playlists.json", and "./<playlist-id>" from before that existed.
+pub fn migrate_playlists(playlists_directory: &Path, url: &url::Url) -> Result<()> {
+    let legacy_directory = env::current_dir()?;
+    if fs::canonicalize(&legacy_directory).ok() == fs::canonicalize(playlists_directory).ok() {
+        return Ok(());
+    }
+
+    let mut directory_names = vec![];
+    let legacy_cache_file = legacy_directory.join(PLAYLISTS_FILE_NAME);
+    let legacy_cache = fs::read_to_string(&legacy_cache_file).ok()
+        .and_then(|cache| serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(&cache).ok());
+    if let Some(legacy_cache) = &legacy_cache {
+        directory_names.extend(legacy_cache.values().filter_map(|name| name.as_str()).map(|name| name.to_string()));
+    }
+    if let Some((_, list)) = url.query_pairs().find(|(name, _)| name == "list") {
+        directory_names.push(list.to_string());
+    }
+
+    // Only directories that were made by yt-dlp, the working directory may be anything.
+    let legacy_playlists = directory_names.into_iter()
+        .map(|name| legacy_directory.join(name))
+        .filter(|directory| directory.join("archive.txt").is_file())
+        .collect::<Vec<_>>();
+    if legacy_playlists.is_empty() {
+        return Ok(());
+    }
+
+    fs::create_dir_all(playlists_directory)?;
+    for legacy_playlist in legacy_playlists {
+        let Some(name) = legacy_playlist.file_name() else { continue };
+        let directory = playlists_directory.join(name);
+        if directory.exists() {
+            continue;
+        }
+        info!("Moving playlist archive {:?} to {:?}", &legacy_playlist, &directory);
+        fs::rename(&legacy_playlist, &directory)
+            .map_err(|err| anyhow!("Failed to move playlist archive {:?} to {:?}, please move it yourself: {}", legacy_playlist, directory, err))?;
+    }
+
+    // Resolved names are still valid, so yt-dlp doesn't need to be asked again.
+    let cache_file = playlists_directory.join(PLAYLISTS_FILE_NAME);
+    if legacy_cache.is_some() && !cache_file.exists() {
+        fs::rename(&legacy_cache_file, &cache_file)
+            .or_else(|_| fs::copy(&legacy_cache_file, &cache_file).map(|_| ()))?;
+    }
+
+    Ok(())
+}
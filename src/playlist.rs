@@ -1,7 +1,7 @@
 
-use std::ops::Div;
-use rand::prelude::IteratorRandom;
-use crate::song::Song;
+use std::{ops::Div, path::PathBuf};
+use rand::{distributions::WeightedIndex, prelude::{Distribution, IteratorRandom}};
+use crate::{search, smart_playlist::SmartPlaylist, song::Song};
 
 
 
@@ -13,7 +13,10 @@ pub trait PlaylistSeekable {
             self.seek(0)
         } else {
             let song = self.seek(offset);
-            self.seek(-offset);
+            // A failed seek doesn't move, so there's nothing to move back.
+            if song.is_some() {
+                self.seek(-offset);
+            }
             song
         }
     }
@@ -24,6 +27,18 @@ pub trait PlaylistSeekable {
 
 
 
+/// Name of the playlist with all songs.
+pub const LIBRARY_PLAYLIST: &str = "Library";
+
+/// Shuffle mode as set in the config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShuffleMode {
+    Normal,
+    Random,
+    SmartRandom,
+    Weighted,
+}
+
 #[allow(dead_code)]
 #[derive(Debug)]
 enum PlaylistShuffle {
@@ -32,63 +47,381 @@ enum PlaylistShuffle {
     SmartRandom {
         blacklist_length: usize,
     },
+    /// Same as SmartRandom, but songs are picked using `Playlist::weights`.
+    Weighted {
+        blacklist_length: usize,
+    },
+}
+
+impl PlaylistShuffle {
+    fn new(mode: ShuffleMode, song_count: usize) -> PlaylistShuffle {
+        let blacklist_length = if song_count > 10 { song_count.div(2) } else { song_count.saturating_sub(3) };
+        match mode {
+            ShuffleMode::Normal => PlaylistShuffle::Normal,
+            ShuffleMode::Random => PlaylistShuffle::Random,
+            ShuffleMode::SmartRandom => PlaylistShuffle::SmartRandom { blacklist_length },
+            ShuffleMode::Weighted => PlaylistShuffle::Weighted { blacklist_length },
+        }
+    }
 }
 
 
 
 #[derive(Debug)]
 pub struct Playlist {
+    shuffle_mode: ShuffleMode,
     mode: PlaylistShuffle,
     songs: Vec<Song>,
+    /// Weight of each song in `songs`, only used for `PlaylistShuffle::Weighted`.
+    weights: Vec<f64>,
+    /// Songs in `songs` that are never picked, like blocked or unplayable songs.
+    blocked: Vec<bool>,
+    /// Songs in `songs` that don't match a filter (like favorites only), only picked if no song matches.
+    filtered: Vec<bool>,
+    /// Songs chosen by the user, played before any new shuffled songs.
+    queue: Vec<usize>,
     song_indices: Vec<usize>,
     song_indices_index: usize,
 }
 
 impl Playlist {
-    pub fn new(songs: Vec<Song>) -> Playlist {
+    pub fn new(songs: Vec<Song>, shuffle_mode: ShuffleMode) -> Playlist {
         Playlist {
-            mode: PlaylistShuffle::SmartRandom {
-                blacklist_length: if songs.len() > 10 { songs.len().div(2) } else { songs.len().saturating_sub(3) }
-            },
+            shuffle_mode,
+            mode: PlaylistShuffle::new(shuffle_mode, songs.len()),
+            weights: vec![1.0; songs.len()],
+            blocked: vec![false; songs.len()],
+            filtered: vec![false; songs.len()],
             songs,
+            queue: Vec::new(),
             song_indices: Vec::new(),
             song_indices_index: 0,
         }
     }
 
-    fn new_song_index(&self) -> usize {
+    pub fn songs(&self) -> &[Song] {
+        &self.songs
+    }
+
+    pub fn shuffle_mode(&self) -> ShuffleMode {
+        self.shuffle_mode
+    }
+
+    /// Only changes songs that weren't picked yet.
+    pub fn set_shuffle_mode(&mut self, shuffle_mode: ShuffleMode) {
+        self.shuffle_mode = shuffle_mode;
+        self.mode = PlaylistShuffle::new(shuffle_mode, self.songs.len());
+    }
+
+    /// Weights must be in the same order as `songs()`.
+    /// Missing weights are 1, extra weights are ignored.
+    pub fn set_weights(&mut self, mut weights: Vec<f64>) {
+        weights.resize(self.songs.len(), 1.0);
+        self.weights = weights;
+    }
+
+    /// Blocked songs must be in the same order as `songs()`, missing songs aren't blocked.
+    pub fn set_blocked(&mut self, mut blocked: Vec<bool>) {
+        blocked.resize(self.songs.len(), false);
+        self.blocked = blocked;
+    }
+
+    /// Filtered songs must be in the same order as `songs()`, missing songs aren't filtered.
+    /// If every song that isn't blocked is filtered, the filter is ignored.
+    pub fn set_filtered(&mut self, mut filtered: Vec<bool>) {
+        filtered.resize(self.songs.len(), false);
+        self.filtered = filtered;
+    }
+
+    /// Adds songs that aren't already in the playlist.
+    /// Existing songs keep their index, so the history stays valid.
+    pub fn add_songs(&mut self, songs: Vec<Song>) -> usize {
+        let mut added = 0;
+        for song in songs {
+            if !self.songs.iter().any(|s| s.file() == song.file()) {
+                self.songs.push(song);
+                self.weights.push(1.0);
+                self.blocked.push(false);
+                self.filtered.push(false);
+                added += 1;
+            }
+        }
+        self.mode = PlaylistShuffle::new(self.shuffle_mode, self.songs.len());
+        added
+    }
+
+    /// Finds a song by video ID, file path, or fuzzy search of the title & artist.
+    pub fn find_song(&self, query: &str) -> Option<usize> {
+        self.songs.iter().position(|s| s.id().as_deref() == Some(query) || s.file().to_string_lossy() == query)
+            .or_else(|| search::search(&self.songs, query).first().map(|m| m.index))
+    }
+
+    /// Amount of songs that aren't blocked or filtered.
+    pub fn included_count(&self) -> usize {
+        (0..self.songs.len()).filter(|i| !self.blocked[*i] && !self.filtered[*i]).count()
+    }
+
+    /// Amount of songs that aren't blocked.
+    pub fn playable_count(&self) -> usize {
+        self.blocked.iter().filter(|b| !**b).count()
+    }
+
+    /// Songs that may be picked, in order.
+    pub fn included_songs(&self) -> Vec<Song> {
+        self.included_song_indices().into_iter().map(|i| self.songs[i].clone()).collect()
+    }
+
+    /// Played songs up to and including the current one, oldest first.
+    pub fn history(&self) -> Vec<Song> {
+        let end = (self.song_indices_index + 1).min(self.song_indices.len());
+        self.song_indices[..end].iter().map(|i| self.songs[*i].clone()).collect()
+    }
+
+    /// If any song was picked yet.
+    pub fn is_started(&self) -> bool {
+        !self.song_indices.is_empty()
+    }
+
+    pub fn queue(&self) -> Vec<Song> {
+        self.queue.iter().map(|i| self.songs[*i].clone()).collect()
+    }
+
+    pub fn enqueue(&mut self, song_index: usize) {
+        self.queue.push(song_index);
+    }
+
+    pub fn play_next(&mut self, song_index: usize) {
+        self.queue.insert(0, song_index);
+    }
+
+    /// Position starts at 0.
+    pub fn dequeue(&mut self, position: usize) -> Option<Song> {
+        if position >= self.queue.len() {
+            return None;
+        }
+        let song_index = self.queue.remove(position);
+        Some(self.songs[song_index].clone())
+    }
+
+    /// Positions start at 0.
+    pub fn move_queued(&mut self, from: usize, to: usize) -> bool {
+        if from >= self.queue.len() || to >= self.queue.len() {
+            return false;
+        }
+        let song_index = self.queue.remove(from);
+        self.queue.insert(to, song_index);
+        true
+    }
+
+    pub fn clear_queue(&mut self) {
+        self.queue.clear();
+    }
+
+    /// If `seek(offset)` would find a song, without moving.
+    pub fn can_seek(&self, offset: isize) -> bool {
+        match self.song_indices_index.checked_add_signed(offset) {
+            // New songs are only needed past the history & the queue, there are none if every song is blocked.
+            Some(song_indices_index) => song_indices_index < self.song_indices.len() + self.queue.len() || !self.included_song_indices().is_empty(),
+            None => false,
+        }
+    }
+
+    /// Song indices that may be picked by the shuffle.
+    /// Blocked songs are never included, so this is empty if every song is blocked.
+    fn included_song_indices(&self) -> Vec<usize> {
+        let playable = (0..self.songs.len()).filter(|i| !self.blocked[*i]);
+        let included = playable.clone().filter(|i| !self.filtered[*i]).collect::<Vec<_>>();
+        if included.is_empty() {
+            playable.collect()
+        } else {
+            included
+        }
+    }
+
+    /// None if the playlist has no songs that aren't blocked.
+    fn new_song_index(&self) -> Option<usize> {
         match self.mode {
             PlaylistShuffle::Normal => {
-                if let Some(last_song_index) = self.song_indices.last().cloned() {
-                    (last_song_index + 1) % self.songs.len()
-                } else {
-                    0
+                let included = self.included_song_indices();
+                match self.song_indices.last() {
+                    // First included song after the last one, wrapping around.
+                    Some(last_song_index) => included.iter().find(|i| *i > last_song_index).or(included.first()).cloned(),
+                    None => included.first().cloned(),
                 }
             },
             PlaylistShuffle::Random => {
-                self.songs.iter().enumerate().map(|(i, _)| i).choose(&mut rand::thread_rng()).unwrap()
+                self.included_song_indices().into_iter().choose(&mut rand::thread_rng())
             },
             PlaylistShuffle::SmartRandom { blacklist_length } => {
-                let song_indices: Vec<usize> = self.songs.iter().enumerate().map(|(i, _)| i).collect::<Vec<_>>();
-                let blacklisted_songs = &self.song_indices[self.song_indices.len().saturating_sub(blacklist_length)..self.song_indices.len()];
-                let allowed_song_indices = song_indices.into_iter().filter(|i| blacklisted_songs.iter().all(|b| i != b)).collect::<Vec<_>>();
-                allowed_song_indices.into_iter().choose(&mut rand::thread_rng()).unwrap()
+                self.allowed_song_indices(blacklist_length).into_iter().choose(&mut rand::thread_rng())
+            },
+            PlaylistShuffle::Weighted { blacklist_length } => {
+                let allowed_song_indices = self.allowed_song_indices(blacklist_length);
+                match WeightedIndex::new(allowed_song_indices.iter().map(|i| self.weights[*i])) {
+                    Ok(distribution) => Some(allowed_song_indices[distribution.sample(&mut rand::thread_rng())]),
+                    // All weights are zero (or there are no songs), just pick any.
+                    Err(_) => allowed_song_indices.into_iter().choose(&mut rand::thread_rng()),
+                }
             },
         }
     }
+
+    /// All included song indices, except the last `blacklist_length` played ones.
+    fn allowed_song_indices(&self, blacklist_length: usize) -> Vec<usize> {
+        let song_indices: Vec<usize> = self.included_song_indices();
+        let blacklisted_songs = &self.song_indices[self.song_indices.len().saturating_sub(blacklist_length)..self.song_indices.len()];
+        let allowed_song_indices = song_indices.iter().cloned().filter(|i| blacklisted_songs.iter().all(|b| i != b)).collect::<Vec<_>>();
+        // The blacklist is based on the amount of all songs, so it may cover every included song.
+        if allowed_song_indices.is_empty() {
+            song_indices
+        } else {
+            allowed_song_indices
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum PlaylistKind {
+    /// All songs.
+    Library,
+    /// Every song, songs not matching the rules are excluded, so it can be refreshed without losing the history.
+    Smart(SmartPlaylist),
+    /// Imported M3U8/XSPF file, played in order.
+    File(PathBuf),
+}
+
+#[derive(Debug)]
+pub struct NamedPlaylist {
+    pub name: String,
+    pub kind: PlaylistKind,
+    pub playlist: Playlist,
 }
 
+
+
 impl PlaylistSeekable for Playlist {
+    /// None if seeking before the first song, or if the playlist has no songs that aren't blocked.
     fn seek(&mut self, offset: isize) -> Option<Song> {
-        self.song_indices_index = self.song_indices_index.checked_add_signed(offset)?;
-        if self.song_indices_index >= 0xFFFF { // Failsafe for if somehow we generate alot of song indices.
-            panic!("Something went very very wrong. ( ˘︹˘ )");
+        // Checked before the queue & history change, so a seek that finds no song changes nothing.
+        if !self.can_seek(offset) {
+            return None;
         }
-        while self.song_indices_index >= self.song_indices.len() {
-            self.song_indices.push(self.new_song_index());
+        let song_indices_index = self.song_indices_index.checked_add_signed(offset)?;
+        // Queued songs are played next, before songs that are after the current one from seeking back.
+        if offset > 0 {
+            for position in (self.song_indices_index + 1)..=song_indices_index.min(self.song_indices.len()) {
+                if self.queue.is_empty() {
+                    break;
+                }
+                self.song_indices.insert(position, self.queue.remove(0));
+            }
+        }
+        while song_indices_index >= self.song_indices.len() {
+            // Queued songs are added to the history like any other song, so seeking back still works.
+            let song_index = if self.queue.is_empty() { self.new_song_index()? } else { self.queue.remove(0) };
+            self.song_indices.push(song_index);
         }
+        self.song_indices_index = song_indices_index;
         self.songs.get(self.song_indices[self.song_indices_index]).cloned()
     }
 }
 
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn playlist(count: usize, shuffle_mode: ShuffleMode) -> Playlist {
+        let songs = (0..count).map(|i| Song::new(PathBuf::from(format!("{}.mp3", i)), None, i.to_string(), None, None)).collect();
+        Playlist::new(songs, shuffle_mode)
+    }
+
+    fn title(song: Option<Song>) -> Option<String> {
+        song.map(|song| song.name())
+    }
+
+    #[test]
+    fn queue_keeps_songs_after_current() {
+        let mut playlist = playlist(5, ShuffleMode::Normal);
+        playlist.seek(0);
+        for _ in 0..3 {
+            playlist.seek(1);
+        }
+        assert_eq!(title(playlist.seek(-2)), Some("1".to_string()));
+        playlist.enqueue(4);
+        assert_eq!(title(playlist.seek(1)), Some("4".to_string()));
+        // Songs after the current one are still there after the queued song.
+        assert_eq!(title(playlist.seek(1)), Some("2".to_string()));
+        assert_eq!(title(playlist.seek(1)), Some("3".to_string()));
+        assert_eq!(title(playlist.seek(-3)), Some("1".to_string()));
+    }
+
+    #[test]
+    fn zero_weight_never_picked() {
+        for _ in 0..100 {
+            let mut playlist = playlist(5, ShuffleMode::Weighted);
+            playlist.set_weights(vec![0.0, 0.0, 1.0, 0.0, 0.0]);
+            assert_eq!(title(playlist.seek(0)), Some("2".to_string()));
+        }
+    }
+
+    #[test]
+    fn all_zero_weights_pick_any() {
+        let mut playlist = playlist(5, ShuffleMode::Weighted);
+        playlist.set_weights(vec![0.0; 5]);
+        let mut picked = HashSet::new();
+        for i in 0..100 {
+            picked.insert(title(playlist.seek(if i == 0 { 0 } else { 1 })).unwrap());
+        }
+        // Picked like SmartRandom, so every song is played, never one of the recently played ones.
+        assert_eq!(picked.len(), 5);
+        let recent = &playlist.song_indices[playlist.song_indices.len() - 3..];
+        assert!(recent[0] != recent[1] && recent[1] != recent[2] && recent[0] != recent[2]);
+    }
+
+    #[test]
+    fn mismatched_weights() {
+        // The missing weights are 1.
+        for _ in 0..20 {
+            let mut playlist = playlist(5, ShuffleMode::Weighted);
+            playlist.set_weights(vec![0.0; 2]);
+            let song = playlist.seek(0).unwrap();
+            assert!(!["0", "1"].contains(&song.name().as_str()));
+        }
+        let mut playlist = playlist(5, ShuffleMode::Weighted);
+        playlist.set_weights(vec![1.0; 10]);
+        assert!(playlist.seek(1).is_some());
+    }
+
+    #[test]
+    fn blocked_never_picked() {
+        let mut playlist = playlist(3, ShuffleMode::Random);
+        playlist.set_blocked(vec![true, false, true]);
+        playlist.set_filtered(vec![false, true, false]);
+        // Every song that isn't blocked is filtered, so the filter is ignored, but not the blocking.
+        for i in 0..20 {
+            assert_eq!(title(playlist.seek(if i == 0 { 0 } else { 1 })), Some("1".to_string()));
+        }
+        playlist.set_blocked(vec![true; 3]);
+        assert!(!playlist.can_seek(1));
+        assert_eq!(title(playlist.seek(1)), None);
+    }
+
+    #[test]
+    fn failed_seek_keeps_queue() {
+        let mut playlist = playlist(3, ShuffleMode::Normal);
+        playlist.seek(0);
+        playlist.enqueue(2);
+        playlist.set_blocked(vec![true; 3]);
+        // Only the queued song is left, so there's no song 2 ahead.
+        assert!(!playlist.can_seek(2));
+        assert_eq!(title(playlist.seek(2)), None);
+        assert_eq!(playlist.queue().len(), 1);
+        assert_eq!(playlist.song_indices, vec![0]);
+        assert_eq!(playlist.song_indices_index, 0);
+        // The queued song still plays, blocked or not, as it was queued on purpose.
+        assert_eq!(title(playlist.seek(1)), Some("2".to_string()));
+        assert!(playlist.queue().is_empty());
+    }
+}
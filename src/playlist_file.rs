@@ -0,0 +1,447 @@
+/*
+    M3U8 & XSPF playlist files, to take playlists to & from other players.
+    Entries are resolved to songs by file path, or by video ID for URLs & yt-dlp file names.
+*/
+
+use std::{collections::HashMap, fmt::Write as _, fs, io, path::{Path, PathBuf}};
+use clap::ValueEnum;
+use quick_xml::{escape::escape, events::Event, Reader};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use crate::song::{Song, SongKind};
+
+
+
+#[derive(Debug, Error)]
+pub enum PlaylistError {
+    #[error("Unknown playlist file format {0:?}, expected .m3u8, .m3u or .xspf")]
+    UnknownFormat(PathBuf),
+    #[error("Failed to read playlist file {file:?}: {source}")]
+    Read { file: PathBuf, source: io::Error },
+    #[error("Failed to write playlist file {file:?}: {source}")]
+    Write { file: PathBuf, source: io::Error },
+    #[error("Invalid XSPF at position {position}: {source}")]
+    InvalidXspf { position: u64, source: quick_xml::Error },
+    #[error("Invalid export file name {0:?}, expected a file name like \"mix.m3u8\" without directories outside of the exports directory")]
+    InvalidExportName(PathBuf),
+    #[error("Playlist file {0:?} already exists, use --overwrite to replace it")]
+    Exists(PathBuf),
+}
+
+pub type Result<T, E = PlaylistError> = std::result::Result<T, E>;
+
+
+
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaylistFileFormat {
+    M3u8,
+    Xspf,
+}
+
+impl PlaylistFileFormat {
+    /// From the file extension, .m3u is read as UTF-8 too.
+    pub fn from_path(file: &Path) -> Option<PlaylistFileFormat> {
+        match file.extension()?.to_string_lossy().to_lowercase().as_str() {
+            "m3u8" | "m3u" => Some(PlaylistFileFormat::M3u8),
+            "xspf" => Some(PlaylistFileFormat::Xspf),
+            _ => None,
+        }
+    }
+}
+
+/// Entry of a playlist file, not yet resolved to a song.
+#[derive(Debug, Clone, Default)]
+pub struct PlaylistFileEntry {
+    /// File path or URL.
+    pub location: String,
+    /// Other locations of the same song, like XSPF `<identifier>`s.
+    pub identifiers: Vec<String>,
+    pub title: Option<String>,
+}
+
+impl PlaylistFileEntry {
+    pub fn name(&self) -> &str {
+        self.title.as_deref().unwrap_or(&self.location)
+    }
+}
+
+
+
+fn song_location(song: &Song) -> PathBuf {
+    std::path::absolute(song.file()).unwrap_or_else(|_| song.file().clone())
+}
+
+fn song_title(song: &Song) -> String {
+    match song.artist() {
+        Some(artist) => format!("{} - {}", artist, song.name()),
+        None => song.name(),
+    }
+}
+
+fn write_m3u8(title: &str, songs: &[Song]) -> String {
+    let mut text = String::from("#EXTM3U\n");
+    let _ = writeln!(text, "#PLAYLIST:{}", title);
+    for song in songs {
+        // -1 is unknown duration.
+        let seconds = song.duration().map(|d| d.as_secs() as i64).unwrap_or(-1);
+        let _ = writeln!(text, "#EXTINF:{},{}", seconds, song_title(song));
+        let _ = writeln!(text, "{}", song_location(song).display());
+    }
+    text
+}
+
+fn write_xspf(title: &str, songs: &[Song]) -> String {
+    let mut text = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n");
+    let _ = writeln!(text, "  <title>{}</title>", escape(title));
+    text.push_str("  <trackList>\n");
+    for song in songs {
+        let location = song_location(song);
+        let location = url::Url::from_file_path(&location).map(|u| u.to_string()).unwrap_or_else(|_| location.display().to_string());
+        text.push_str("    <track>\n");
+        let _ = writeln!(text, "      <location>{}</location>", escape(&location));
+        let _ = writeln!(text, "      <title>{}</title>", escape(&song.name()));
+        if let Some(artist) = song.artist() {
+            let _ = writeln!(text, "      <creator>{}</creator>", escape(&artist));
+        }
+        if let Some(duration) = song.duration() {
+            let _ = writeln!(text, "      <duration>{}</duration>", duration.as_millis());
+        }
+        text.push_str("    </track>\n");
+    }
+    text.push_str("  </trackList>\n</playlist>\n");
+    text
+}
+
+/// Existing files are only replaced if `overwrite` is set.
+pub fn write(file: &Path, format: PlaylistFileFormat, title: &str, songs: &[Song], overwrite: bool) -> Result<()> {
+    let text = match format {
+        PlaylistFileFormat::M3u8 => write_m3u8(title, songs),
+        PlaylistFileFormat::Xspf => write_xspf(title, songs),
+    };
+    let mut options = fs::OpenOptions::new();
+    options.write(true);
+    if overwrite {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    options.open(file)
+        .and_then(|mut opened| io::Write::write_all(&mut opened, text.as_bytes()))
+        .map_err(|source| match source.kind() {
+            io::ErrorKind::AlreadyExists => PlaylistError::Exists(file.to_path_buf()),
+            _ => PlaylistError::Write { file: file.to_path_buf(), source },
+        })
+}
+
+/// File in `directory` that exports named `name` are written to, which may be in subdirectories.
+/// Exports are requested over IPC, so other directories aren't allowed. (Absolute paths, "..", symbolic links)
+pub fn export_file(directory: &Path, name: &Path) -> Result<PathBuf> {
+    let invalid = || PlaylistError::InvalidExportName(name.to_path_buf());
+    if name.as_os_str().is_empty() || !name.components().all(|c| matches!(c, std::path::Component::Normal(_))) {
+        return Err(invalid());
+    }
+    let file = directory.join(name);
+    if let Some(parent) = file.parent() {
+        fs::create_dir_all(parent).map_err(|source| PlaylistError::Write { file: file.clone(), source })?;
+    }
+    // A link in the exports directory could point anywhere.
+    let mut path = directory.to_path_buf();
+    for component in name.components() {
+        path.push(component);
+        if fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+            return Err(invalid());
+        }
+    }
+    Ok(file)
+}
+
+
+
+fn read_m3u8(text: &str) -> Vec<PlaylistFileEntry> {
+    let mut entries = vec![];
+    let mut title = None;
+    for line in text.lines().map(|l| l.trim().trim_start_matches('\u{feff}')) {
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            title = info.split_once(',').map(|(_, t)| t.trim().to_string()).filter(|t| !t.is_empty());
+        } else if !line.is_empty() && !line.starts_with('#') {
+            entries.push(PlaylistFileEntry { location: line.to_string(), identifiers: vec![], title: title.take() });
+        }
+    }
+    entries
+}
+
+fn read_xspf(text: &str) -> Result<Vec<PlaylistFileEntry>> {
+    let mut reader = Reader::from_str(text);
+    reader.config_mut().trim_text(true);
+
+    let mut entries = vec![];
+    let mut track: Option<PlaylistFileEntry> = None;
+    // Element the current text belongs to.
+    let mut element = Vec::new();
+    loop {
+        match reader.read_event().map_err(|source| PlaylistError::InvalidXspf { position: reader.buffer_position(), source })? {
+            Event::Start(start) => {
+                element = start.local_name().as_ref().to_vec();
+                if element == b"track" {
+                    track = Some(PlaylistFileEntry::default());
+                }
+            },
+            Event::End(end) => {
+                if end.local_name().as_ref() == b"track" {
+                    if let Some(track) = track.take().filter(|t| !t.location.is_empty() || !t.identifiers.is_empty()) {
+                        entries.push(track);
+                    }
+                }
+                element.clear();
+            },
+            Event::Text(text) => {
+                if let Some(track) = &mut track {
+                    let text = text.unescape()
+                        .map_err(|source| PlaylistError::InvalidXspf { position: reader.buffer_position(), source })?
+                        .to_string();
+                    match element.as_slice() {
+                        b"location" if track.location.is_empty() => track.location = text,
+                        b"location" | b"identifier" => track.identifiers.push(text),
+                        b"title" => track.title = Some(text),
+                        _ => {},
+                    }
+                }
+            },
+            Event::Eof => break,
+            _ => {},
+        }
+    }
+    Ok(entries)
+}
+
+pub fn read(file: &Path) -> Result<Vec<PlaylistFileEntry>> {
+    let format = PlaylistFileFormat::from_path(file)
+        .ok_or_else(|| PlaylistError::UnknownFormat(file.to_path_buf()))?;
+    let text = fs::read_to_string(file).map_err(|source| PlaylistError::Read { file: file.to_path_buf(), source })?;
+    match format {
+        PlaylistFileFormat::M3u8 => Ok(read_m3u8(&text)),
+        PlaylistFileFormat::Xspf => read_xspf(&text),
+    }
+}
+
+
+
+/// Video ID from a video URL, like "https://www.youtube.com/watch?v=<id>" or "https://youtu.be/<id>".
+fn url_video_id(url: &url::Url) -> Option<String> {
+    url.query_pairs().find(|(key, _)| key == "v").map(|(_, id)| id.to_string())
+        .or_else(|| url.path_segments()?.rfind(|s| !s.is_empty()).map(|s| s.to_string()))
+}
+
+/// Video ID from a yt-dlp file name, "<id> <title>.m4a".
+fn file_video_id(file: &Path) -> Option<String> {
+    let stem = file.file_stem()?.to_string_lossy().to_string();
+    stem.split_once(' ').map(|(id, _)| id.to_string())
+}
+
+/// Resolves the entries to songs, entries that can't be resolved are returned separately.
+/// Files that aren't in `songs` are loaded as local songs.
+/// Relative paths are relative to the directory of the playlist file.
+pub fn resolve(file: &Path, entries: Vec<PlaylistFileEntry>, songs: &[Song]) -> (Vec<Song>, Vec<PlaylistFileEntry>) {
+    let directory = file.parent().unwrap_or(Path::new("."));
+    let songs_by_file = songs.iter()
+        .map(|song| (fs::canonicalize(song.file()).unwrap_or_else(|_| song.file().clone()), song))
+        .collect::<HashMap<_, _>>();
+    let song_by_id = |id: &str| songs.iter().find(|song| song.id().as_deref() == Some(id));
+
+    let resolve_location = |location: &str| -> Option<Song> {
+        // Single letter schemes are Windows drive letters.
+        let path = match url::Url::parse(location) {
+            Ok(url) if url.scheme() == "file" => url.to_file_path().ok()?,
+            Ok(url) if url.scheme().len() > 1 => return url_video_id(&url).and_then(|id| song_by_id(&id)).cloned(),
+            _ => directory.join(location),
+        };
+        if let Some(song) = fs::canonicalize(&path).ok().and_then(|p| songs_by_file.get(&p)) {
+            return Some((*song).clone());
+        }
+        if let Some(song) = file_video_id(&path).and_then(|id| song_by_id(&id)) {
+            return Some(song.clone());
+        }
+        (path.is_file() && Song::is_supported_file(&path)).then(|| Song::load(path, SongKind::Local))
+    };
+
+    let mut resolved = vec![];
+    let mut unresolved = vec![];
+    for entry in entries {
+        match std::iter::once(&entry.location).chain(entry.identifiers.iter()).filter(|l| !l.is_empty()).find_map(|l| resolve_location(l)) {
+            Some(song) => resolved.push(song),
+            None => unresolved.push(entry),
+        }
+    }
+    (resolved, unresolved)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Empty directory in the temp directory, so the song files exist for `resolve`.
+    fn test_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("yt-dlp-music-player-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn song_file(directory: &Path, name: &str) -> PathBuf {
+        let file = directory.join(name);
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(&file, []).unwrap();
+        file
+    }
+
+    fn test_songs(directory: &Path) -> Vec<Song> {
+        vec![
+            Song::new(song_file(directory, "abc123 First.m4a"), Some("abc123".to_string()), "First".to_string(), Some("Artist".to_string()), Some(Duration::from_secs(61))),
+            Song::new(song_file(directory, "local/Tom & Jerry.mp3"), None, "Tom & Jerry <Live>".to_string(), None, None),
+        ]
+    }
+
+    fn files(songs: &[Song]) -> Vec<PathBuf> {
+        songs.iter().map(|song| fs::canonicalize(song.file()).unwrap()).collect()
+    }
+
+    fn round_trip(format: PlaylistFileFormat, extension: &str) {
+        let directory = test_directory(extension);
+        let songs = test_songs(&directory);
+        let file = directory.join(format!("exported.{}", extension));
+        write(&file, format, "Exported & <saved>", &songs, false).unwrap();
+
+        let entries = read(&file).unwrap();
+        assert_eq!(entries.len(), 2);
+        let (resolved, unresolved) = resolve(&file, entries, &songs);
+        assert!(unresolved.is_empty());
+        assert_eq!(files(&resolved), files(&songs));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn m3u8_round_trip() {
+        round_trip(PlaylistFileFormat::M3u8, "m3u8");
+    }
+
+    #[test]
+    fn xspf_round_trip() {
+        round_trip(PlaylistFileFormat::Xspf, "xspf");
+    }
+
+    #[test]
+    fn m3u8_extinf() {
+        let text = "\u{feff}#EXTM3U\n#EXTINF:61,Artist - First\nfirst.m4a\n\n# Comment\nsecond.m4a\n#EXTINF:-1,\n  third.m4a  \n";
+        let entries = read_m3u8(text);
+        let entries = entries.iter().map(|e| (e.location.as_str(), e.title.as_deref())).collect::<Vec<_>>();
+        assert_eq!(entries, vec![("first.m4a", Some("Artist - First")), ("second.m4a", None), ("third.m4a", None)]);
+    }
+
+    #[test]
+    fn xspf_escaping() {
+        let directory = test_directory("escaping");
+        let songs = test_songs(&directory);
+        let text = write_xspf("Tom & Jerry", &songs);
+        assert!(text.contains("<title>Tom &amp; Jerry &lt;Live&gt;</title>"));
+
+        let entries = read_xspf(&text).unwrap();
+        assert_eq!(entries[0].title.as_deref(), Some("First"));
+        assert_eq!(entries[1].title.as_deref(), Some("Tom & Jerry <Live>"));
+        assert!(entries[1].location.starts_with("file://"));
+        assert!(entries[1].location.ends_with("Tom%20&%20Jerry.mp3"), "{}", entries[1].location);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn xspf_identifiers() {
+        let text = r#"<playlist><trackList>
+            <track><location>missing.m4a</location><identifier>https://youtu.be/abc123</identifier><title>First</title></track>
+            <track><title>No location</title></track>
+        </trackList></playlist>"#;
+        let entries = read_xspf(text).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].location, "missing.m4a");
+        assert_eq!(entries[0].identifiers, vec!["https://youtu.be/abc123".to_string()]);
+        assert!(read_xspf("<playlist><trackList></playlist>").is_err());
+    }
+
+    #[test]
+    fn relative_paths() {
+        let directory = test_directory("relative");
+        let songs = test_songs(&directory);
+        let other = song_file(&directory, "other/Other.flac");
+        let file = directory.join("playlists").join("relative.m3u8");
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+
+        let entries = read_m3u8("../local/Tom & Jerry.mp3\n../other/Other.flac\n");
+        let (resolved, unresolved) = resolve(&file, entries, &songs);
+        assert!(unresolved.is_empty());
+        assert_eq!(files(&resolved), vec![files(&songs)[1].clone(), fs::canonicalize(&other).unwrap()]);
+        // Files that aren't in the library are loaded as local songs.
+        assert_eq!(resolved[1].name(), "Other");
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn video_ids() {
+        let directory = test_directory("video-ids");
+        let songs = test_songs(&directory);
+        let file = directory.join("ids.m3u8");
+
+        let entries = read_m3u8("https://www.youtube.com/watch?v=abc123&list=PL1\nhttps://youtu.be/abc123\n/moved/abc123 First.m4a\n");
+        let (resolved, unresolved) = resolve(&file, entries, &songs);
+        assert!(unresolved.is_empty());
+        assert!(resolved.iter().all(|song| song.id().as_deref() == Some("abc123")));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn unresolved_entries() {
+        let directory = test_directory("unresolved");
+        let songs = test_songs(&directory);
+        song_file(&directory, "notes.txt");
+        let file = directory.join("unresolved.m3u8");
+
+        let entries = read_m3u8("#EXTINF:10,Missing\nmissing.m4a\nhttps://youtu.be/unknown\nnotes.txt\nabc123 First.m4a\n");
+        let (resolved, unresolved) = resolve(&file, entries, &songs);
+        assert_eq!(files(&resolved), vec![files(&songs)[0].clone()]);
+        let names = unresolved.iter().map(|e| e.name()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["Missing", "https://youtu.be/unknown", "notes.txt"]);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn export_names() {
+        let directory = test_directory("export-names");
+        assert_eq!(export_file(&directory, Path::new("mix.m3u8")).unwrap(), directory.join("mix.m3u8"));
+        assert_eq!(export_file(&directory, Path::new("weekly/mix.xspf")).unwrap(), directory.join("weekly").join("mix.xspf"));
+        assert!(directory.join("weekly").is_dir());
+        for name in ["", "../mix.m3u8", "weekly/../../mix.m3u8", "./mix.m3u8"] {
+            assert!(matches!(export_file(&directory, Path::new(name)), Err(PlaylistError::InvalidExportName(_))), "{:?}", name);
+        }
+        let absolute = std::env::temp_dir().join("mix.m3u8");
+        assert!(matches!(export_file(&directory, &absolute), Err(PlaylistError::InvalidExportName(_))));
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(std::env::temp_dir(), directory.join("link")).unwrap();
+            assert!(matches!(export_file(&directory, Path::new("link/mix.m3u8")), Err(PlaylistError::InvalidExportName(_))));
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn export_overwrite() {
+        let directory = test_directory("export-overwrite");
+        let songs = test_songs(&directory);
+        let file = directory.join("mix.m3u8");
+        fs::write(&file, "existing").unwrap();
+        assert!(matches!(write(&file, PlaylistFileFormat::M3u8, "Mix", &songs, false), Err(PlaylistError::Exists(_))));
+        assert_eq!(fs::read_to_string(&file).unwrap(), "existing");
+        write(&file, PlaylistFileFormat::M3u8, "Mix", &songs, true).unwrap();
+        assert_eq!(read(&file).unwrap().len(), 2);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
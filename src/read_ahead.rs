@@ -0,0 +1,253 @@
+/*
+    Streams a file from disk, a background thread keeps a bounded buffer filled ahead of the reader.
+    Decoding long songs (hours long mixes & concerts) would otherwise need the whole file in memory,
+    and reading from disk on the audio thread may stutter.
+*/
+
+use std::{collections::VecDeque, fs::File, io::{self, Read, Seek, SeekFrom}, path::Path, sync::{Arc, Condvar, Mutex}, thread};
+
+
+
+/// Maximum amount of bytes read ahead.
+const BUFFER_CAPACITY: usize = 4 * 1024 * 1024;
+/// Amount of bytes the background thread reads at a time.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+struct State {
+    /// Position in the file of the start of `buffer`.
+    start: u64,
+    buffer: VecDeque<u8>,
+    /// Increases with every seek, so chunks read before the seek are thrown away.
+    generation: u64,
+    eof: bool,
+    error: Option<io::Error>,
+    /// The reader was dropped, the background thread stops.
+    closed: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Notified when the buffer changes, or a seek or close was requested.
+    changed: Condvar,
+}
+
+
+
+pub struct ReadAheadFile {
+    shared: Arc<Shared>,
+    position: u64,
+    length: u64,
+}
+
+impl ReadAheadFile {
+    pub fn open(file: &Path) -> io::Result<ReadAheadFile> {
+        let file = File::open(file)?;
+        let length = file.metadata()?.len();
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State { start: 0, buffer: VecDeque::new(), generation: 0, eof: false, error: None, closed: false }),
+            changed: Condvar::new(),
+        });
+        let thread_shared = Arc::clone(&shared);
+        thread::Builder::new()
+            .name("read-ahead".to_string())
+            .spawn(move || read_ahead(file, &thread_shared))?;
+        Ok(ReadAheadFile { shared, position: 0, length })
+    }
+}
+
+/// Background thread, fills the buffer until it's full or the file ended.
+fn read_ahead(mut file: File, shared: &Shared) {
+    let mut chunk = vec![0; CHUNK_SIZE];
+    let mut generation = u64::MAX;
+    loop {
+        let mut state = shared.state.lock().unwrap();
+        while !state.closed && state.generation == generation && (state.eof || state.error.is_some() || state.buffer.len() >= BUFFER_CAPACITY) {
+            state = shared.changed.wait(state).unwrap();
+        }
+        if state.closed {
+            return;
+        }
+        let read_position = state.start + state.buffer.len() as u64;
+        generation = state.generation;
+        drop(state);
+
+        let result = file.seek(SeekFrom::Start(read_position)).and_then(|_| file.read(&mut chunk));
+
+        let mut state = shared.state.lock().unwrap();
+        // The reader seeked while reading, this chunk is from the wrong position.
+        if state.generation != generation {
+            continue;
+        }
+        match result {
+            Ok(0) => state.eof = true,
+            Ok(read) => state.buffer.extend(&chunk[..read]),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {},
+            Err(err) => state.error = Some(err),
+        }
+        shared.changed.notify_all();
+    }
+}
+
+impl Read for ReadAheadFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.position >= self.length {
+            return Ok(0);
+        }
+        let mut state = self.shared.state.lock().unwrap();
+        // Outside of the buffered range, continue reading ahead from the new position.
+        if self.position < state.start || self.position > state.start + state.buffer.len() as u64 {
+            state.start = self.position;
+            state.buffer.clear();
+            state.generation += 1;
+            state.eof = false;
+            state.error = None;
+            self.shared.changed.notify_all();
+        }
+        // Everything before the position was read, free it for reading further ahead.
+        let consumed = (self.position - state.start) as usize;
+        state.buffer.drain(..consumed);
+        state.start = self.position;
+
+        while state.buffer.is_empty() {
+            if let Some(err) = state.error.take() {
+                return Err(err);
+            }
+            if state.eof {
+                return Ok(0);
+            }
+            self.shared.changed.notify_all();
+            state = self.shared.changed.wait(state).unwrap();
+        }
+
+        let read = buf.len().min(state.buffer.len());
+        let (front, back) = state.buffer.as_slices();
+        let from_front = read.min(front.len());
+        buf[..from_front].copy_from_slice(&front[..from_front]);
+        buf[from_front..read].copy_from_slice(&back[..read - from_front]);
+        state.buffer.drain(..read);
+        state.start += read as u64;
+        self.position += read as u64;
+        self.shared.changed.notify_all();
+        Ok(read)
+    }
+}
+
+impl Seek for ReadAheadFile {
+    /// Only moves the position, the read ahead continues from there on the next read.
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.length.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Seek to a negative position."))?;
+        Ok(self.position)
+    }
+}
+
+impl Drop for ReadAheadFile {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.changed.notify_all();
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, path::PathBuf};
+
+    /// Larger than `BUFFER_CAPACITY`, so not all of it fits in the buffer.
+    const TEST_FILE_SIZE: usize = BUFFER_CAPACITY * 2 + CHUNK_SIZE / 2;
+
+    fn byte_at(position: usize) -> u8 {
+        // Prime, so the pattern doesn't line up with the chunks.
+        (position % 251) as u8
+    }
+
+    fn test_file(name: &str) -> PathBuf {
+        let file = std::env::temp_dir().join(format!("yt-dlp-music-player-test-{}-{}.bin", name, std::process::id()));
+        fs::write(&file, (0..TEST_FILE_SIZE).map(byte_at).collect::<Vec<_>>()).unwrap();
+        file
+    }
+
+    fn read_at(reader: &mut ReadAheadFile, position: u64, length: usize) -> Vec<u8> {
+        assert_eq!(reader.seek(SeekFrom::Start(position)).unwrap(), position);
+        let mut data = vec![0; length];
+        reader.read_exact(&mut data).unwrap();
+        data
+    }
+
+    fn expected(position: usize, length: usize) -> Vec<u8> {
+        (position..position + length).map(byte_at).collect()
+    }
+
+    #[test]
+    fn reads_whole_file() {
+        let file = test_file("whole");
+        let mut data = vec![];
+        ReadAheadFile::open(&file).unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, expected(0, TEST_FILE_SIZE));
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn seek_backwards() {
+        let file = test_file("backwards");
+        let mut reader = ReadAheadFile::open(&file).unwrap();
+        assert_eq!(read_at(&mut reader, 0, 100_000), expected(0, 100_000));
+        // Before the buffer start, the start was already freed.
+        assert_eq!(read_at(&mut reader, 10, 1000), expected(10, 1000));
+        assert_eq!(reader.seek(SeekFrom::Current(-500)).unwrap(), 510);
+        let mut data = vec![0; 10];
+        reader.read_exact(&mut data).unwrap();
+        assert_eq!(data, expected(510, 10));
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn seek_past_buffer() {
+        let file = test_file("past-buffer");
+        let mut reader = ReadAheadFile::open(&file).unwrap();
+        assert_eq!(read_at(&mut reader, 0, 10), expected(0, 10));
+        let position = BUFFER_CAPACITY + CHUNK_SIZE + 7;
+        assert_eq!(read_at(&mut reader, position as u64, 100_000), expected(position, 100_000));
+        // Forward within the buffered window.
+        let position = position + 100_000 + 1234;
+        assert_eq!(read_at(&mut reader, position as u64, 10), expected(position, 10));
+        // And back to the start after that.
+        assert_eq!(read_at(&mut reader, 0, 10), expected(0, 10));
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn end_of_file() {
+        let file = test_file("eof");
+        let mut reader = ReadAheadFile::open(&file).unwrap();
+        let mut data = vec![0; 100];
+        assert_eq!(reader.seek(SeekFrom::End(-10)).unwrap(), TEST_FILE_SIZE as u64 - 10);
+        let mut end = vec![];
+        assert_eq!(reader.read_to_end(&mut end).unwrap(), 10);
+        assert_eq!(end, expected(TEST_FILE_SIZE - 10, 10));
+        assert_eq!(reader.read(&mut data).unwrap(), 0);
+
+        reader.seek(SeekFrom::End(100)).unwrap();
+        assert_eq!(reader.read(&mut data).unwrap(), 0);
+        assert_eq!(reader.seek(SeekFrom::Current(-(TEST_FILE_SIZE as i64) - 100)).unwrap(), 0);
+        assert!(reader.seek(SeekFrom::Current(-1)).is_err());
+        assert_eq!(reader.read(&mut data).unwrap(), 100);
+        assert_eq!(data, expected(0, 100));
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn empty_file() {
+        let file = std::env::temp_dir().join(format!("yt-dlp-music-player-test-empty-{}.bin", std::process::id()));
+        fs::write(&file, []).unwrap();
+        let mut data = vec![0; 10];
+        assert_eq!(ReadAheadFile::open(&file).unwrap().read(&mut data).unwrap(), 0);
+        fs::remove_file(&file).unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+use crate::song::Song;
+
+
+
+/// Matches scoring at least this are shown as candidates.
+pub const MINIMUM_SCORE: f64 = 0.5;
+/// Matches scoring at least this are good enough to play without asking.
+pub const CONFIDENT_SCORE: f64 = 0.8;
+
+#[derive(Debug, Clone)]
+pub struct SearchMatch {
+    /// Index into the searched songs.
+    pub index: usize,
+    /// From 0 to 1, higher is better.
+    pub score: f64,
+}
+
+/// Lowercase words, without punctuation & accents. (So "beyonce" finds "Beyoncé")
+fn words(text: &str) -> Vec<String> {
+    text.nfd()
+        .filter(|c| !is_combining_mark(*c))
+        .collect::<String>()
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_string())
+        .collect()
+}
+
+/// Optimal string alignment distance, like Levenshtein distance but swapping 2 characters counts as 1 edit.
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    let mut distances = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, distance) in distances[0].iter_mut().enumerate() {
+        *distance = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            let mut distance = (distances[i - 1][j] + 1)
+                .min(distances[i][j - 1] + 1)
+                .min(distances[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
+        }
+    }
+    distances[a.len()][b.len()]
+}
+
+/// How well a single query word matches a single word.
+fn word_score(query_word: &str, word: &str) -> f64 {
+    if query_word == word {
+        return 1.0;
+    }
+    if word.starts_with(query_word) {
+        return 0.9;
+    }
+    if word.contains(query_word) {
+        return 0.8;
+    }
+
+    let query_chars = query_word.chars().collect::<Vec<_>>();
+    let chars = word.chars().collect::<Vec<_>>();
+    // Short words need to be more exact, otherwise everything matches.
+    let allowed_typos = match query_chars.len() {
+        0..=2 => 0,
+        3..=5 => 1,
+        _ => 2,
+    };
+    // Also compare with the start of the word, so typos in partially typed words still match.
+    let prefix = &chars[..chars.len().min(query_chars.len())];
+    let distance = edit_distance(&query_chars, &chars).min(edit_distance(&query_chars, prefix));
+    if distance <= allowed_typos {
+        0.75 * (1.0 - distance as f64 / (query_chars.len().max(chars.len()) as f64 + 1.0))
+    } else {
+        0.0
+    }
+}
+
+/// Scores how well the query matches the text, every query word has to match some word in the text.
+pub fn score(query: &str, text: &str) -> f64 {
+    let query_words = words(query);
+    let text_words = words(text);
+    if query_words.is_empty() || text_words.is_empty() {
+        return 0.0;
+    }
+
+    let mut total = 0.0;
+    for query_word in &query_words {
+        let best = text_words.iter().map(|w| word_score(query_word, w)).fold(0.0, f64::max);
+        if best == 0.0 {
+            return 0.0;
+        }
+        total += best;
+    }
+    let score = total / query_words.len() as f64;
+
+    // Prefer texts where the words are in the same order as in the query.
+    if text_words.join(" ").contains(&query_words.join(" ")) {
+        (score + 0.1).min(1.0)
+    } else {
+        score
+    }
+}
+
+fn song_text(song: &Song) -> String {
+    match song.artist() {
+        Some(artist) => format!("{} {}", artist, song.name()),
+        None => song.name(),
+    }
+}
+
+/// Matches with a score of at least `MINIMUM_SCORE`, best first.
+pub fn search(songs: &[Song], query: &str) -> Vec<SearchMatch> {
+    let mut matches = songs.iter()
+        .enumerate()
+        .map(|(index, song)| SearchMatch { index, score: score(query, &song_text(song)) })
+        .filter(|m| m.score >= MINIMUM_SCORE)
+        .collect::<Vec<_>>();
+    matches.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.index.cmp(&b.index)));
+    matches
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(artist: Option<&str>, title: &str) -> Song {
+        Song::new(format!("{}.m4a", title).into(), None, title.to_string(), artist.map(|a| a.to_string()), None)
+    }
+
+    #[test]
+    fn exact_match_ranks_first() {
+        let songs = [
+            song(Some("Daft Punk"), "Around the World"),
+            song(Some("Daft Punk"), "One More Time"),
+            song(Some("Punk Rock Band"), "More"),
+        ];
+        let matches = search(&songs, "one more time");
+        assert_eq!(matches[0].index, 1);
+        assert!(matches[0].score >= CONFIDENT_SCORE);
+        assert!(matches.iter().all(|m| m.index != 0));
+    }
+
+    #[test]
+    fn word_order_preferred() {
+        assert!(score("daft pun", "Daft Punk - Aerodynamic") > score("daft pun", "Punk Daft"));
+    }
+
+    #[test]
+    fn typos() {
+        assert!(score("daft pnuk", "Daft Punk") >= MINIMUM_SCORE);
+        assert!(score("aerodynamci", "Aerodynamic") >= MINIMUM_SCORE);
+        // Short words have to be exact.
+        assert_eq!(score("ab", "ac"), 0.0);
+    }
+
+    #[test]
+    fn empty_query() {
+        assert_eq!(score("", "Daft Punk"), 0.0);
+        assert_eq!(score(" - ", "Daft Punk"), 0.0);
+        assert!(search(&[song(None, "Daft Punk")], "").is_empty());
+    }
+
+    #[test]
+    fn case_insensitive() {
+        assert_eq!(score("DAFT punk", "daft PUNK"), 1.0);
+    }
+
+    #[test]
+    fn accents() {
+        assert_eq!(score("beyonce", "Beyoncé"), 1.0);
+        assert_eq!(score("BEYONCÉ", "beyonce"), 1.0);
+        assert_eq!(score("sigur ros", "Sigur Rós"), 1.0);
+    }
+}
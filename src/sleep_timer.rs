@@ -0,0 +1,117 @@
+/*
+    Stops the music after some time, or after some songs.
+    The volume fades out over the last `FADE_DURATION`, then playback pauses or the program exits.
+*/
+
+use std::time::{Duration, Instant};
+use crate::smart_playlist::parse_duration;
+
+
+
+/// The volume fades out over the last part of the sleep timer.
+pub const FADE_DURATION: Duration = Duration::from_secs(30);
+/// How often the volume is changed while fading out.
+const FADE_STEP: Duration = Duration::from_millis(250);
+
+/// What happens when the sleep timer ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SleepAction {
+    Pause,
+    Exit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SleepUntil {
+    Time(Instant),
+    /// After the current song, and this many more songs.
+    Songs(u32),
+}
+
+/// Parses sleep durations like "30m" or "1h30m", a plain number is in minutes.
+pub fn parse_sleep_duration(value: &str) -> Option<Duration> {
+    match value.parse::<f64>() {
+        Ok(minutes) => (minutes > 0.0).then(|| Duration::from_secs_f64(minutes * 60.0)),
+        Err(_) => parse_duration(value).filter(|d| !d.is_zero()),
+    }
+}
+
+
+
+#[derive(Debug, Clone)]
+pub struct SleepTimer {
+    pub until: SleepUntil,
+    pub action: SleepAction,
+}
+
+impl SleepTimer {
+    pub fn new(until: SleepUntil, action: SleepAction) -> SleepTimer {
+        SleepTimer { until, action }
+    }
+
+    /// Time until the timer ends, if known.
+    /// `song_remaining` is the time left of the current song, None if it isn't playing.
+    pub fn remaining(&self, now: Instant, song_remaining: Option<Duration>) -> Option<Duration> {
+        match self.until {
+            SleepUntil::Time(until) => Some(until.saturating_duration_since(now)),
+            SleepUntil::Songs(0) => song_remaining,
+            SleepUntil::Songs(_) => None,
+        }
+    }
+
+    /// Only for timers that end at a time, timers that end after songs are done when the last song finished.
+    pub fn is_done(&self, now: Instant) -> bool {
+        matches!(self.until, SleepUntil::Time(until) if now >= until)
+    }
+
+    /// Call when a song finished, returns if the timer is done.
+    pub fn song_finished(&mut self) -> bool {
+        match &mut self.until {
+            SleepUntil::Songs(0) => true,
+            SleepUntil::Songs(songs) => {
+                *songs -= 1;
+                false
+            },
+            SleepUntil::Time(_) => false,
+        }
+    }
+
+    /// Volume multiplier, from 1 down to 0 over the last `FADE_DURATION`.
+    pub fn fade(&self, now: Instant, song_remaining: Option<Duration>) -> f32 {
+        match self.remaining(now, song_remaining) {
+            Some(remaining) => (remaining.as_secs_f32() / FADE_DURATION.as_secs_f32()).clamp(0.0, 1.0),
+            None => 1.0,
+        }
+    }
+
+    /// When the timer needs to be checked again, for fading, ending, or the shown remaining time.
+    pub fn next_check(&self, now: Instant, song_remaining: Option<Duration>) -> Option<Instant> {
+        let remaining = self.remaining(now, song_remaining)?;
+        if remaining <= FADE_DURATION {
+            return Some(now + FADE_STEP);
+        }
+        // Remaining time is shown in minutes, and in seconds for the last minute.
+        let until_shown_change = if remaining.as_secs() < 60 {
+            Duration::from_secs(1)
+        } else {
+            Duration::from_secs(remaining.as_secs() % 60 + 1)
+        };
+        Some(now + until_shown_change.min(remaining - FADE_DURATION))
+    }
+
+    /// Like "Pausing in 25 min", for the tray tooltip & status.
+    pub fn describe(&self, now: Instant, song_remaining: Option<Duration>) -> String {
+        let action = match self.action {
+            SleepAction::Pause => "Pausing",
+            SleepAction::Exit => "Exiting",
+        };
+        match self.until {
+            SleepUntil::Time(_) | SleepUntil::Songs(0) => match self.remaining(now, song_remaining) {
+                Some(remaining) if remaining.as_secs() >= 60 => format!("{} in {} min", action, remaining.as_secs().div_ceil(60)),
+                Some(remaining) => format!("{} in {} s", action, remaining.as_secs()),
+                None => format!("{} after this song", action),
+            },
+            SleepUntil::Songs(1) => format!("{} after the next song", action),
+            SleepUntil::Songs(songs) => format!("{} after {} more songs", action, songs),
+        }
+    }
+}
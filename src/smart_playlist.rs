@@ -0,0 +1,298 @@
+/*
+    Smart playlists are built from rules over the library, every rule has to match.
+    Rules are written as "<field> <operator> <value>", for example:
+        "added < 30d"           Added in the last 30 days.
+        "rating >= 4"           Rated 4 or 5 stars.
+        "plays = 0"             Never played.
+        "duration < 5m"         Shorter than 5 minutes.
+        "uploader = Some Name"  Uploaded by "Some Name". (Same as artist)
+        "title ~ remix"         Title contains "remix".
+*/
+
+use std::{fmt, time::Duration};
+use crate::library::LibraryEntry;
+
+
+
+const FIELDS: [&str; 11] = ["added", "last-played", "plays", "skips", "rating", "duration", "artist", "uploader", "title", "favorite", "blocked"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    /// Case insensitive, only for text.
+    Contains,
+}
+
+impl Comparison {
+    fn parse(operator: &str) -> Option<Comparison> {
+        match operator {
+            "=" | "==" => Some(Comparison::Equal),
+            "!=" => Some(Comparison::NotEqual),
+            "<" => Some(Comparison::Less),
+            "<=" => Some(Comparison::LessOrEqual),
+            ">" => Some(Comparison::Greater),
+            ">=" => Some(Comparison::GreaterOrEqual),
+            "~" => Some(Comparison::Contains),
+            _ => None,
+        }
+    }
+
+    fn compare<T: PartialOrd>(&self, a: T, b: T) -> bool {
+        match self {
+            Comparison::Equal => a == b,
+            Comparison::NotEqual => a != b,
+            Comparison::Less => a < b,
+            Comparison::LessOrEqual => a <= b,
+            Comparison::Greater => a > b,
+            Comparison::GreaterOrEqual => a >= b,
+            Comparison::Contains => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rule {
+    /// Time since the song was added.
+    Added(Comparison, Duration),
+    /// Time since the song was last played, never played songs never match.
+    LastPlayed(Comparison, Duration),
+    Plays(Comparison, u32),
+    Skips(Comparison, u32),
+    /// 0 is no rating.
+    Rating(Comparison, u8),
+    Duration(Comparison, Duration),
+    Artist(Comparison, String),
+    Title(Comparison, String),
+    Favorite(bool),
+    Blocked(bool),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuleError {
+    pub rule: String,
+    pub message: String,
+}
+
+impl fmt::Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rule \"{}\": {}", self.rule, self.message)
+    }
+}
+
+/// Parses durations like "30d", "2w", "1h30m", "90s", or just seconds.
+/// Negative durations & durations too long for `Duration` (like "1e30") are invalid.
+pub fn parse_duration(value: &str) -> Option<Duration> {
+    if let Ok(seconds) = value.parse::<f64>() {
+        return Duration::try_from_secs_f64(seconds).ok();
+    }
+    let mut total = 0.0;
+    let mut number = String::new();
+    for c in value.chars() {
+        if c.is_ascii_digit() || c == '.' {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1.0,
+            'm' => 60.0,
+            'h' => 60.0 * 60.0,
+            'd' => 60.0 * 60.0 * 24.0,
+            'w' => 60.0 * 60.0 * 24.0 * 7.0,
+            _ => return None,
+        };
+        total += number.parse::<f64>().ok()? * unit;
+        number.clear();
+    }
+    if value.is_empty() || !number.is_empty() {
+        return None;
+    }
+    Duration::try_from_secs_f64(total).ok()
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "true" | "yes" => Some(true),
+        "false" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+impl Rule {
+    pub fn parse(rule: &str) -> Result<Rule, RuleError> {
+        let error = |message: String| RuleError { rule: rule.to_string(), message };
+
+        // Split at the operator, field names never contain operator characters.
+        let operator_start = rule.find(['=', '!', '<', '>', '~'])
+            .ok_or_else(|| error("expected \"<field> <operator> <value>\", like \"added < 30d\"".to_string()))?;
+        let operator_end = rule[operator_start..].find(|c: char| !"=!<>~".contains(c)).map(|i| operator_start + i).unwrap_or(rule.len());
+
+        let field = rule[..operator_start].trim().to_lowercase();
+        let operator = &rule[operator_start..operator_end];
+        let value = rule[operator_end..].trim().trim_matches('"');
+
+        let comparison = Comparison::parse(operator)
+            .ok_or_else(|| error(format!("unknown operator \"{}\", expected one of =, !=, <, <=, >, >=, ~", operator)))?;
+        if value.is_empty() {
+            return Err(error("missing value".to_string()));
+        }
+
+        let duration = || parse_duration(value)
+            .ok_or_else(|| error(format!("invalid duration \"{}\", expected a number followed by s, m, h, d or w (like \"30d\")", value)));
+        let count = || value.parse::<u32>()
+            .map_err(|_| error(format!("invalid number \"{}\"", value)));
+        let flag = || parse_bool(value)
+            .ok_or_else(|| error(format!("invalid value \"{}\", expected true or false", value)));
+        let numeric = |rule: Rule| if comparison == Comparison::Contains {
+            Err(error(format!("\"~\" only works for text fields, not \"{}\"", field)))
+        } else {
+            Ok(rule)
+        };
+        let equality = |rule: Rule| if comparison == Comparison::Equal || comparison == Comparison::NotEqual {
+            Ok(rule)
+        } else {
+            Err(error(format!("\"{}\" can only be compared with = or !=", field)))
+        };
+
+        match field.as_str() {
+            "added" => numeric(Rule::Added(comparison, duration()?)),
+            "last-played" => numeric(Rule::LastPlayed(comparison, duration()?)),
+            "plays" => numeric(Rule::Plays(comparison, count()?)),
+            "skips" => numeric(Rule::Skips(comparison, count()?)),
+            "rating" => {
+                let rating = value.parse::<u8>().ok().filter(|r| *r <= 5)
+                    .ok_or_else(|| error(format!("invalid rating \"{}\", expected 0 to 5", value)))?;
+                numeric(Rule::Rating(comparison, rating))
+            },
+            "duration" => numeric(Rule::Duration(comparison, duration()?)),
+            "artist" | "uploader" => Ok(Rule::Artist(comparison, value.to_string())),
+            "title" => Ok(Rule::Title(comparison, value.to_string())),
+            "favorite" => equality(Rule::Favorite(flag()? == (comparison == Comparison::Equal))),
+            "blocked" => equality(Rule::Blocked(flag()? == (comparison == Comparison::Equal))),
+            _ => Err(error(format!("unknown field \"{}\", expected one of {}", field, FIELDS.join(", ")))),
+        }
+    }
+
+    /// `now` is a unix timestamp.
+    pub fn matches(&self, entry: &LibraryEntry, now: u64) -> bool {
+        let age = |time: u64| Duration::from_secs(now.saturating_sub(time));
+        let text = |comparison: &Comparison, text: &str, value: &str| match comparison {
+            Comparison::Contains => text.to_lowercase().contains(&value.to_lowercase()),
+            Comparison::Equal => text.eq_ignore_ascii_case(value),
+            Comparison::NotEqual => !text.eq_ignore_ascii_case(value),
+            comparison => comparison.compare(text.to_lowercase(), value.to_lowercase()),
+        };
+
+        match self {
+            Rule::Added(comparison, duration) => comparison.compare(age(entry.date_added), *duration),
+            Rule::LastPlayed(comparison, duration) => entry.last_played.is_some_and(|t| comparison.compare(age(t), *duration)),
+            Rule::Plays(comparison, plays) => comparison.compare(entry.play_count, *plays),
+            Rule::Skips(comparison, skips) => comparison.compare(entry.skip_count, *skips),
+            Rule::Rating(comparison, rating) => comparison.compare(entry.rating.unwrap_or(0), *rating),
+            Rule::Duration(comparison, duration) => entry.duration.is_some_and(|d| comparison.compare(Duration::from_secs_f64(d), *duration)),
+            Rule::Artist(comparison, artist) => text(comparison, entry.artist.as_deref().unwrap_or(""), artist),
+            Rule::Title(comparison, title) => text(comparison, &entry.title, title),
+            Rule::Favorite(favorite) => entry.favorite == *favorite,
+            Rule::Blocked(blocked) => entry.blocked == *blocked,
+        }
+    }
+}
+
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SmartPlaylist {
+    pub name: String,
+    pub rules: Vec<Rule>,
+}
+
+impl SmartPlaylist {
+    /// Errors contain all invalid rules, not just the first one.
+    pub fn parse(name: &str, rules: &[String]) -> Result<SmartPlaylist, String> {
+        let (rules, errors): (Vec<_>, Vec<_>) = rules.iter().map(|rule| Rule::parse(rule)).partition(|r| r.is_ok());
+        if !errors.is_empty() {
+            let errors = errors.into_iter().filter_map(|e| e.err()).map(|e| format!("  {}", e)).collect::<Vec<_>>();
+            return Err(format!("Invalid smart playlist \"{}\":\n{}", name, errors.join("\n")));
+        }
+        Ok(SmartPlaylist {
+            name: name.to_string(),
+            rules: rules.into_iter().filter_map(|r| r.ok()).collect(),
+        })
+    }
+
+    pub fn matches(&self, entry: &LibraryEntry, now: u64) -> bool {
+        self.rules.iter().all(|rule| rule.matches(entry, now))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duration_units() {
+        assert_eq!(parse_duration("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("1.5"), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("90s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("5m"), Some(Duration::from_secs(5 * 60)));
+        assert_eq!(parse_duration("1h30m"), Some(Duration::from_secs(90 * 60)));
+        assert_eq!(parse_duration("30d"), Some(Duration::from_secs(30 * 24 * 60 * 60)));
+        assert_eq!(parse_duration("2w"), Some(Duration::from_secs(14 * 24 * 60 * 60)));
+        assert_eq!(parse_duration("0.5h"), Some(Duration::from_secs(30 * 60)));
+    }
+
+    #[test]
+    fn invalid_durations() {
+        for value in ["", "m", "30x", "1h30", "1h 30m", "-5", "-5m", "1..5m", "inf", "NaN"] {
+            assert_eq!(parse_duration(value), None, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn duration_overflow() {
+        assert_eq!(parse_duration("1e30"), None);
+        assert_eq!(parse_duration("99999999999999999999w"), None);
+        assert_eq!(parse_duration("18446744073709551615s1s"), None);
+    }
+
+    #[test]
+    fn valid_rules() {
+        assert_eq!(Rule::parse("added < 30d"), Ok(Rule::Added(Comparison::Less, Duration::from_secs(30 * 24 * 60 * 60))));
+        assert_eq!(Rule::parse("rating>=4"), Ok(Rule::Rating(Comparison::GreaterOrEqual, 4)));
+        assert_eq!(Rule::parse("plays == 0"), Ok(Rule::Plays(Comparison::Equal, 0)));
+        assert_eq!(Rule::parse("Duration <= 5m"), Ok(Rule::Duration(Comparison::LessOrEqual, Duration::from_secs(5 * 60))));
+        assert_eq!(Rule::parse("uploader = \"Some Name\""), Ok(Rule::Artist(Comparison::Equal, "Some Name".to_string())));
+        assert_eq!(Rule::parse("title ~ remix"), Ok(Rule::Title(Comparison::Contains, "remix".to_string())));
+        assert_eq!(Rule::parse("favorite != no"), Ok(Rule::Favorite(true)));
+        assert_eq!(Rule::parse("blocked = false"), Ok(Rule::Blocked(false)));
+    }
+
+    #[test]
+    fn invalid_rules() {
+        let message = |rule: &str| Rule::parse(rule).unwrap_err().message;
+        assert!(message("added 30d").starts_with("expected \"<field> <operator> <value>\""));
+        assert!(message("added => 30d").starts_with("unknown operator \"=>\""));
+        assert_eq!(message("added <"), "missing value");
+        assert!(message("added < 30x").starts_with("invalid duration \"30x\""));
+        assert!(message("added < 1e30").starts_with("invalid duration \"1e30\""));
+        assert_eq!(message("plays > -1"), "invalid number \"-1\"");
+        assert_eq!(message("rating = 6"), "invalid rating \"6\", expected 0 to 5");
+        assert_eq!(message("plays ~ 1"), "\"~\" only works for text fields, not \"plays\"");
+        assert_eq!(message("favorite > true"), "\"favorite\" can only be compared with = or !=");
+        assert_eq!(message("favorite = maybe"), "invalid value \"maybe\", expected true or false");
+        assert!(message("genre = rock").starts_with("unknown field \"genre\""));
+    }
+
+    #[test]
+    fn playlist_lists_every_invalid_rule() {
+        let rules = ["rating = 6", "plays > 1", "genre = rock"].map(|r| r.to_string());
+        let err = SmartPlaylist::parse("Best", &rules).unwrap_err();
+        assert_eq!(err.lines().count(), 3);
+        assert!(err.contains("rule \"rating = 6\"") && err.contains("rule \"genre = rock\""));
+    }
+}
@@ -1,64 +1,220 @@
+use std::{fs, io::{self, BufReader, Cursor, Read, Seek}, path::{Path, PathBuf}, time::Duration};
+use anyhow::Result;
+use lofty::{picture::MimeType, prelude::{Accessor, AudioFile, TaggedFileExt}};
+use rodio::{decoder::DecoderError, source::SeekError, Sink, Source};
+use thiserror::Error;
+use crate::{loudness_normalization::LoudnessNormalization, paths, read_ahead::ReadAheadFile};
 
-use std::{fs, io::{BufReader, Cursor}, path::PathBuf, time::Duration};
-use anyhow::{anyhow, Result};
-use rodio::{Sink, Source};
-use crate::loudness_normalization::LoudnessNormalization;
+
+
+/// File extensions of thumbnails written by yt-dlp next to the song, or cover images next to local songs.
+const COVER_EXTENSIONS: [&str; 4] = ["jpg", "jpeg", "png", "webp"];
+
+/// Larger files are streamed from disk instead of loaded into memory, like hours long mixes & concerts.
+const FULL_LOAD_MAX_SIZE: u64 = 32 * 1024 * 1024;
+/// Loudness of streamed songs is only analyzed over the start, analyzing hours of audio would keep the song from starting for seconds.
+const STREAMED_ANALYSIS_DURATION: Duration = Duration::from_secs(2 * 60);
+
+/// File extensions that can be decoded by rodio with the enabled features.
+const SUPPORTED_EXTENSIONS: [&str; 4] = ["m4a", "mp3", "flac", "wav"];
+
+/// Why a song can't be played.
+#[derive(Debug, Error)]
+pub enum DecodeError {
+    #[error("Failed to read song file: {0}")]
+    Read(#[from] io::Error),
+    #[error("Failed to decode song: {0}")]
+    Decode(#[from] DecoderError),
+    #[error("Failed to get song duration.")]
+    NoDuration,
+    #[error("Failed to seek back after the loudness analysis: {0}")]
+    Seek(#[from] SeekError),
+    #[error("No audio.")]
+    NoAudio,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SongKind {
+    /// Downloaded by yt-dlp, file names are "%(id)s %(title)s.m4a"
+    YtDlp,
+    /// Any audio file in a local directory, may be nested.
+    Local,
+}
 
 
 
 #[derive(Debug, Clone)]
 pub struct Song {
     file: PathBuf,
+    /// Video ID, only for songs downloaded by yt-dlp.
+    id: Option<String>,
+    title: String,
+    artist: Option<String>,
+    duration: Option<Duration>,
 }
 
 impl Song {
-    pub fn load_playlist_directory(playlist_directory: &PathBuf) -> Result<Vec<Song>> {
-        let mut songs: Vec<Song> = vec![];
-
-        let binding = fs::read_dir(playlist_directory)?
-            .collect::<Result<Vec<_>, _>>()?;
-        let song_files = binding
-            .iter()
-            .filter(|entry| entry.file_name().to_string_lossy().ends_with(".m4a"))
-            .collect::<Vec<_>>();
-
-        for song_file in song_files {
-            songs.push(Song {
-                file: song_file.path()
-            });
+    pub fn new(file: PathBuf, id: Option<String>, title: String, artist: Option<String>, duration: Option<Duration>) -> Song {
+        Song { file, id, title, artist, duration }
+    }
+
+    /// Reads the song metadata from the file tags, falling back to the file name.
+    pub fn load(file: PathBuf, kind: SongKind) -> Song {
+        // Songs without (readable) tags are still playable, so ignore any errors.
+        let tagged_file = lofty::read_from_path(&file).ok();
+        let tag = tagged_file.as_ref().and_then(|f| f.primary_tag().or(f.first_tag()));
+
+        let title = tag.and_then(|t| t.title().map(|s| s.to_string())).filter(|s| !s.is_empty());
+        let artist = tag.and_then(|t| t.artist().map(|s| s.to_string())).filter(|s| !s.is_empty());
+        let duration = tagged_file.as_ref().map(|f| f.properties().duration()).filter(|d| !d.is_zero());
+
+        let stem = file.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+        let (id, file_title) = match kind {
+            SongKind::YtDlp => match stem.split_once(' ') {
+                Some((id, title)) => (Some(id.to_string()), title.to_string()),
+                None => (None, stem),
+            },
+            SongKind::Local => (None, stem),
+        };
+
+        Song {
+            title: title.unwrap_or(file_title),
+            file, id, artist, duration,
         }
+    }
 
-        Ok(songs)
+    /// All song files in the directory, sorted.
+    /// Songs are loaded by `LibraryIndex::scan`, which only reads the tags of new & changed files.
+    pub fn playlist_directory_files(playlist_directory: &Path, kind: SongKind) -> Result<Vec<PathBuf>> {
+        let mut song_files: Vec<PathBuf> = vec![];
+        Song::collect_song_files(playlist_directory, kind, &mut song_files)?;
+        song_files.sort();
+        Ok(song_files)
     }
 
-    pub fn sink_load(&self, sink: &mut Sink, loudness_normalization: LoudnessNormalization) -> Result<Duration> {
-        
-        // Loading the whole file to prevent stuttering.
-        // Pretty sure this *should* be fine as audio data shouldn't really be > 50MiB.
-        let data = fs::read(&self.file)?;
-        let mut source = rodio::Decoder::new(BufReader::new(Cursor::new(data)))?;
-        // Could probably use rodio::Buffered, but that may add a delay on audio controls. I don't really know though, haven't tested it yet.
+    fn collect_song_files(directory: &Path, kind: SongKind, song_files: &mut Vec<PathBuf>) -> Result<()> {
+        for entry in fs::read_dir(directory)? {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                if kind == SongKind::Local {
+                    Song::collect_song_files(&path, kind, song_files)?;
+                }
+                continue;
+            }
+            let supported = match kind {
+                SongKind::YtDlp => path.extension().is_some_and(|e| e.eq_ignore_ascii_case("m4a")),
+                SongKind::Local => Song::is_supported_file(&path),
+            };
+            if supported {
+                song_files.push(path);
+            }
+        }
+        Ok(())
+    }
 
-        let duration = if let Some(duration) = source.total_duration() {
-            duration
+    /// If the file extension is one of `SUPPORTED_EXTENSIONS`.
+    pub fn is_supported_file(file: &Path) -> bool {
+        file.extension().is_some_and(|e| SUPPORTED_EXTENSIONS.contains(&e.to_string_lossy().to_lowercase().as_str()))
+    }
+
+    /// Returns the song duration & the loudness normalization gain.
+    /// `cached_gain` skips the (slow) loudness analysis if the gain was already calculated.
+    pub fn sink_load(&self, sink: &mut Sink, loudness_normalization: LoudnessNormalization, cached_gain: Option<f64>) -> Result<(Duration, f64), DecodeError> {
+        if fs::metadata(&self.file)?.len() <= FULL_LOAD_MAX_SIZE {
+            // Loading the whole file to prevent stuttering.
+            let data = fs::read(&self.file)?;
+            self.sink_append(sink, BufReader::new(Cursor::new(data)), loudness_normalization, cached_gain, None)
         } else {
-            return Err(anyhow!("Failed to get song duration."));
-        };
+            // Long songs are streamed, so memory use doesn't grow with the length of the song.
+            self.sink_append(sink, BufReader::new(ReadAheadFile::open(&self.file)?), loudness_normalization, cached_gain, Some(STREAMED_ANALYSIS_DURATION))
+        }
+    }
 
-        let amplify_amount = loudness_normalization.get_normal_amplification(&mut source);
-        source.try_seek(Duration::ZERO).unwrap(); // FIXME: ? instead of .unwrap()
+    /// `analysis_duration` limits the loudness analysis to the start of the song.
+    fn sink_append<R: Read + Seek + Send + Sync + 'static>(&self, sink: &mut Sink, data: R, loudness_normalization: LoudnessNormalization, cached_gain: Option<f64>, analysis_duration: Option<Duration>) -> Result<(Duration, f64), DecodeError> {
+        let mut source = rodio::Decoder::new(data)?;
+        // Could probably use rodio::Buffered, but that may add a delay on audio controls. I don't really know though, haven't tested it yet.
+
+        // Some formats don't have the duration in the stream, the tags may still have it.
+        let duration = source.total_duration().or(self.duration)
+            .ok_or(DecodeError::NoDuration)?;
+
+        let amplify_amount = match cached_gain {
+            Some(gain) => gain,
+            None => {
+                let gain = match analysis_duration {
+                    Some(analysis_duration) => loudness_normalization.get_normal_amplification(&mut (&mut source).take_duration(analysis_duration)),
+                    None => loudness_normalization.get_normal_amplification(&mut source),
+                };
+                source.try_seek(Duration::ZERO)?;
+                gain
+            },
+        };
         let normalized_source = source.amplify(amplify_amount as f32);
         sink.append(normalized_source);
 
-        Ok(duration)
+        Ok((duration, amplify_amount))
     }
 
-    pub fn name(&self) -> String {
-        let filename = self.file.file_name().unwrap().to_string_lossy();
-        let without_id = filename.split_at(filename.find(' ').unwrap() + 1).1;
-        let without_ext = &without_id[0..without_id.len() - 4];
-        without_ext.to_string()
+    /// Decodes the whole file, to find files that can't be played.
+    pub fn verify(&self) -> Result<(), DecodeError> {
+        let mut source = rodio::Decoder::new(BufReader::new(fs::File::open(&self.file)?))?;
+        if source.total_duration().or(self.duration).is_none() {
+            return Err(DecodeError::NoDuration);
+        }
+        if source.by_ref().count() == 0 {
+            return Err(DecodeError::NoAudio);
+        }
+        Ok(())
     }
-}
 
+    /// Cover image of the song, the thumbnail next to the song file, or else the picture embedded in the tags.
+    /// Embedded pictures are written to a file per song in the cache directory, so notifications & media controls showing it never get another song's cover.
+    pub fn cover(&self) -> Option<PathBuf> {
+        let thumbnail = COVER_EXTENSIONS.iter()
+            .map(|extension| self.file.with_extension(extension))
+            .find(|file| file.is_file());
+        if thumbnail.is_some() {
+            return thumbnail;
+        }
+
+        let tagged_file = lofty::read_from_path(&self.file).ok()?;
+        let picture = tagged_file.primary_tag().or(tagged_file.first_tag())?.pictures().first()?;
+        let extension = match picture.mime_type() {
+            Some(MimeType::Png) => "png",
+            Some(MimeType::Gif) => "gif",
+            Some(MimeType::Bmp) => "bmp",
+            _ => "jpg",
+        };
+        let directory = paths::cache_directory().join("covers");
+        fs::create_dir_all(&directory).ok()?;
+        let file = directory.join(format!("{:016x}.{}", paths::path_hash(&self.file), extension));
+        // Not written again while it may be shown.
+        if fs::read(&file).is_ok_and(|data| data == picture.data()) {
+            return Some(file);
+        }
+        fs::write(&file, picture.data()).ok()?;
+        Some(file)
+    }
 
+    pub fn file(&self) -> &PathBuf {
+        &self.file
+    }
+
+    pub fn id(&self) -> Option<String> {
+        self.id.clone()
+    }
+
+    pub fn duration(&self) -> Option<Duration> {
+        self.duration
+    }
+
+    pub fn name(&self) -> String {
+        self.title.clone()
+    }
+
+    pub fn artist(&self) -> Option<String> {
+        self.artist.clone()
+    }
+}
@@ -0,0 +1,168 @@
+use std::{fs, path::{Path, PathBuf}, time::{Duration, Instant, SystemTime}};
+use anyhow::Result;
+use crate::song::SongKind;
+
+
+
+/// Where the songs of a playlist come from.
+#[derive(Debug, Clone)]
+pub enum PlaylistSource {
+    /// yt-dlp archive directory of a playlist, may be from any site yt-dlp supports.
+    YtDlp {
+        url: url::Url,
+        directory: PathBuf,
+    },
+    /// Local directory, scanned recursively.
+    LocalDirectory {
+        directory: PathBuf,
+    },
+}
+
+impl PlaylistSource {
+    pub fn directory(&self) -> &PathBuf {
+        match self {
+            PlaylistSource::YtDlp { directory, .. } => directory,
+            PlaylistSource::LocalDirectory { directory } => directory,
+        }
+    }
+
+    pub fn song_kind(&self) -> SongKind {
+        match self {
+            PlaylistSource::YtDlp { .. } => SongKind::YtDlp,
+            PlaylistSource::LocalDirectory { .. } => SongKind::Local,
+        }
+    }
+
+    /// Modification times of every directory in this source.
+    /// Adding or removing a file changes the modification time of its parent directory,
+    /// so this is enough to know if a rescan is needed.
+    fn directory_modified_times(&self) -> Result<Vec<(PathBuf, SystemTime)>> {
+        let mut times = vec![];
+        collect_directory_modified_times(self.directory(), self.song_kind() == SongKind::Local, &mut times)?;
+        times.sort();
+        Ok(times)
+    }
+}
+
+fn collect_directory_modified_times(directory: &Path, recursive: bool, times: &mut Vec<(PathBuf, SystemTime)>) -> Result<()> {
+    times.push((directory.to_path_buf(), fs::metadata(directory)?.modified()?));
+    if recursive {
+        for entry in fs::read_dir(directory)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                collect_directory_modified_times(&entry.path(), recursive, times)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+
+
+/// Checks sources for changed directories every `interval`.
+#[derive(Debug)]
+pub struct SourceWatcher {
+    sources: Vec<PlaylistSource>,
+    modified_times: Vec<Vec<(PathBuf, SystemTime)>>,
+    interval: Duration,
+    last_check: Instant,
+}
+
+impl SourceWatcher {
+    pub fn new(sources: Vec<PlaylistSource>, interval: Duration) -> SourceWatcher {
+        let modified_times = sources.iter().map(|s| s.directory_modified_times().unwrap_or_default()).collect();
+        SourceWatcher {
+            sources,
+            modified_times,
+            interval,
+            last_check: Instant::now(),
+        }
+    }
+
+    pub fn sources(&self) -> &[PlaylistSource] {
+        &self.sources
+    }
+
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    /// When `poll` should be called next.
+    pub fn next_check(&self) -> Instant {
+        self.last_check + self.interval
+    }
+
+    /// Returns the indices of all sources that changed since the last check.
+    /// Returns nothing if it isn't time to check yet.
+    pub fn poll(&mut self) -> Vec<usize> {
+        if self.last_check.elapsed() < self.interval {
+            return vec![];
+        }
+        self.check()
+    }
+
+    /// Same as `poll`, but checks right away.
+    pub fn check(&mut self) -> Vec<usize> {
+        self.last_check = Instant::now();
+
+        let mut changed = vec![];
+        for (i, (source, modified_times)) in self.sources.iter().zip(self.modified_times.iter_mut()).enumerate() {
+            // Directory may temporarily be unavailable (e.g. unmounted drive), just try again later.
+            let Ok(new_modified_times) = source.directory_modified_times() else { continue };
+            if new_modified_times != *modified_times {
+                *modified_times = new_modified_times;
+                changed.push(i);
+            }
+        }
+        changed
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("yt-dlp-music-player-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(directory.join("Album/Disc 1")).unwrap();
+        // Modification times may be coarse, so changes right after creating the directories could go unnoticed.
+        std::thread::sleep(Duration::from_millis(50));
+        directory
+    }
+
+    #[test]
+    fn local_changes_are_recursive() {
+        let directory = test_directory("source-local-changes");
+        let mut watcher = SourceWatcher::new(vec![PlaylistSource::LocalDirectory { directory: directory.clone() }], Duration::ZERO);
+        assert!(watcher.check().is_empty());
+        fs::write(directory.join("Album/Disc 1/Song.mp3"), []).unwrap();
+        assert_eq!(watcher.check(), vec![0]);
+        assert!(watcher.check().is_empty());
+    }
+
+    #[test]
+    fn yt_dlp_changes_are_not_recursive() {
+        let directory = test_directory("source-yt-dlp-changes");
+        let source = PlaylistSource::YtDlp {
+            url: url::Url::parse("https://www.youtube.com/playlist?list=test").unwrap(),
+            directory: directory.clone(),
+        };
+        let mut watcher = SourceWatcher::new(vec![source], Duration::ZERO);
+        fs::write(directory.join("Album/Disc 1/abc Song.m4a"), []).unwrap();
+        assert!(watcher.check().is_empty());
+        fs::write(directory.join("abc Song.m4a"), []).unwrap();
+        assert_eq!(watcher.check(), vec![0]);
+    }
+
+    #[test]
+    fn poll_waits_for_interval() {
+        let directory = test_directory("source-poll");
+        let mut watcher = SourceWatcher::new(vec![PlaylistSource::LocalDirectory { directory: directory.clone() }], Duration::from_secs(60 * 60));
+        fs::write(directory.join("Song.mp3"), []).unwrap();
+        assert!(watcher.poll().is_empty());
+        assert_eq!(watcher.check(), vec![0]);
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+use anyhow::Result;
+use chrono::{Datelike, Local, TimeZone};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use crate::library::Library;
+
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlayOutcome {
+    /// Played until the end.
+    Completed,
+    /// Skipped to the next song with media controls or the tray.
+    Skipped,
+    /// Anything else, like going to the previous song or closing the program.
+    /// Only after most of the song was played, stopping earlier is a skip.
+    Stopped,
+}
+
+/// A single play of a song, stored in "plays.jsonl" next to the library index.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PlayEvent {
+    /// Video ID, or file path relative to the source directory for local songs.
+    pub song_id: String,
+    pub title: String,
+    /// Unix timestamp.
+    pub start: u64,
+    /// How long the song was listened to, in seconds.
+    pub played: f64,
+    pub outcome: PlayOutcome,
+}
+
+
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum StatsFormat {
+    Table,
+    Json,
+}
+
+#[derive(Serialize, Debug)]
+struct SongCount {
+    title: String,
+    count: u32,
+}
+
+#[derive(Serialize, Debug)]
+struct ListeningTime {
+    period: String,
+    /// In seconds.
+    time: f64,
+}
+
+#[derive(Serialize, Debug)]
+struct StatsReport {
+    top_songs: Vec<SongCount>,
+    most_skipped: Vec<SongCount>,
+    listening_time_per_day: Vec<ListeningTime>,
+    listening_time_per_week: Vec<ListeningTime>,
+    never_played: Vec<String>,
+}
+
+impl StatsReport {
+    fn new(library: &Library, limit: usize) -> Result<StatsReport> {
+        let entries = library.indices().iter().flat_map(|index| index.entries()).map(|(_, entry)| entry).collect::<Vec<_>>();
+
+        let mut top_songs = entries.iter()
+            .filter(|e| e.play_count > 0)
+            .map(|e| SongCount { title: e.title.clone(), count: e.play_count })
+            .collect::<Vec<_>>();
+        top_songs.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.title.cmp(&b.title)));
+        top_songs.truncate(limit);
+
+        let mut most_skipped = entries.iter()
+            .filter(|e| e.skip_count > 0)
+            .map(|e| SongCount { title: e.title.clone(), count: e.skip_count })
+            .collect::<Vec<_>>();
+        most_skipped.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.title.cmp(&b.title)));
+        most_skipped.truncate(limit);
+
+        let mut per_day: BTreeMap<String, f64> = BTreeMap::new();
+        let mut per_week: BTreeMap<String, f64> = BTreeMap::new();
+        for event in library.play_events()? {
+            let Some(start) = Local.timestamp_opt(event.start as i64, 0).single() else { continue };
+            let week = start.iso_week();
+            *per_day.entry(start.format("%Y-%m-%d").to_string()).or_default() += event.played;
+            *per_week.entry(format!("{}-W{:02}", week.year(), week.week())).or_default() += event.played;
+        }
+
+        // Skipped songs weren't listened to either.
+        let mut never_played = entries.iter()
+            .filter(|e| e.play_count == 0)
+            .map(|e| e.title.clone())
+            .collect::<Vec<_>>();
+        never_played.sort();
+
+        Ok(StatsReport {
+            top_songs,
+            most_skipped,
+            listening_time_per_day: per_day.into_iter().rev().map(|(period, time)| ListeningTime { period, time }).collect(),
+            listening_time_per_week: per_week.into_iter().rev().map(|(period, time)| ListeningTime { period, time }).collect(),
+            never_played,
+        })
+    }
+
+    fn print_table(&self) {
+        fn print_song_counts(header: &str, count_header: &str, songs: &[SongCount]) {
+            println!("{}", header);
+            if songs.is_empty() {
+                println!("  (none)");
+            }
+            for (i, song) in songs.iter().enumerate() {
+                println!("  {:>3}. {:>5} {}  {}", i + 1, song.count, count_header, song.title);
+            }
+            println!();
+        }
+
+        fn print_listening_times(header: &str, times: &[ListeningTime]) {
+            println!("{}", header);
+            if times.is_empty() {
+                println!("  (none)");
+            }
+            for time in times {
+                let minutes = (time.time / 60.0).round() as u64;
+                println!("  {:<10}  {:>3}h {:02}m", time.period, minutes / 60, minutes % 60);
+            }
+            println!();
+        }
+
+        print_song_counts("Top songs:", "plays", &self.top_songs);
+        print_song_counts("Most skipped songs:", "skips", &self.most_skipped);
+        print_listening_times("Listening time per day:", &self.listening_time_per_day);
+        print_listening_times("Listening time per week:", &self.listening_time_per_week);
+
+        println!("Never played songs: ({})", self.never_played.len());
+        for title in &self.never_played {
+            println!("  {}", title);
+        }
+    }
+}
+
+/// Prints the listening statistics of all songs in the library.
+pub fn print_stats(library: &Library, format: StatsFormat, limit: usize) -> Result<()> {
+    let report = StatsReport::new(library, limit)?;
+    match format {
+        StatsFormat::Table => report.print_table(),
+        StatsFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+    }
+    Ok(())
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::library::tests::{song_file, test_directory, test_library};
+
+    #[test]
+    fn report() {
+        let directory = test_directory("stats-report");
+        let mut library = test_library(&directory);
+        for name in ["A.mp3", "B.mp3", "C.mp3", "D.mp3"] {
+            song_file(&directory.join("songs"), name);
+        }
+        let songs = library.scan().unwrap();
+        let minute = Duration::from_secs(60);
+        let start = Local.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap().timestamp() as u64;
+
+        library.record_play(&songs[0], start, minute, minute, PlayOutcome::Completed).unwrap();
+        library.record_play(&songs[0], start + 60, minute, minute, PlayOutcome::Completed).unwrap();
+        library.record_play(&songs[1], start + 120, minute, minute, PlayOutcome::Completed).unwrap();
+        // Only skipped, so never played.
+        library.record_play(&songs[2], start + 180, Duration::from_secs(5), minute, PlayOutcome::Stopped).unwrap();
+        library.record_play(&songs[0], start + 24 * 60 * 60, minute, minute, PlayOutcome::Completed).unwrap();
+
+        let report = StatsReport::new(&library, 1).unwrap();
+        assert_eq!(report.top_songs.iter().map(|s| (s.title.as_str(), s.count)).collect::<Vec<_>>(), vec![("A", 3)]);
+        assert_eq!(report.most_skipped.iter().map(|s| (s.title.as_str(), s.count)).collect::<Vec<_>>(), vec![("C", 1)]);
+        assert_eq!(report.never_played, vec!["C", "D"]);
+
+        // Newest first.
+        let per_day = report.listening_time_per_day.iter().map(|t| (t.period.as_str(), t.time)).collect::<Vec<_>>();
+        assert_eq!(per_day, vec![("2024-01-02", 60.0), ("2024-01-01", 185.0)]);
+        let per_week = report.listening_time_per_week.iter().map(|t| (t.period.as_str(), t.time)).collect::<Vec<_>>();
+        assert_eq!(per_week, vec![("2024-W01", 245.0)]);
+    }
+}
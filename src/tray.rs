@@ -0,0 +1,294 @@
+use anyhow::Result;
+use tray_icon::{menu::{CheckMenuItem, Menu, MenuEvent, MenuItem, PredefinedMenuItem, Submenu}, TrayIcon, TrayIconBuilder};
+
+
+
+/// Volume presets in the volume submenu, in percent.
+const VOLUME_PRESETS: [u8; 5] = [10, 25, 50, 75, 100];
+/// Sleep timer presets in the sleep timer submenu, in minutes.
+const SLEEP_PRESETS: [u32; 5] = [15, 30, 45, 60, 90];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrayAction {
+    PlayPause,
+    Next,
+    Previous,
+    /// In percent.
+    Volume(u8),
+    ToggleMute,
+    ToggleShuffle,
+    ToggleRepeat,
+    /// Index of the playlist, in the order given to `Tray::new`.
+    Playlist(usize),
+    UpdatePlaylist,
+    /// Open the folder of the current song.
+    OpenFolder,
+    ToggleFavorite,
+    /// Never play the current song again.
+    Block,
+    /// 0 removes the rating.
+    Rate(u8),
+    ToggleFavoritesOnly,
+    /// In minutes.
+    Sleep(u32),
+    SleepAfterSong,
+    SleepOff,
+    /// Index of the device, in the order given to `Tray::set_output_devices`. None is the default device.
+    OutputDevice(Option<usize>),
+    Quit,
+}
+
+impl TrayAction {
+    pub fn from_menu_event(event: &MenuEvent) -> Option<TrayAction> {
+        match event.id.as_ref() {
+            "play-pause" => Some(TrayAction::PlayPause),
+            "next" => Some(TrayAction::Next),
+            "previous" => Some(TrayAction::Previous),
+            "mute" => Some(TrayAction::ToggleMute),
+            "shuffle" => Some(TrayAction::ToggleShuffle),
+            "repeat" => Some(TrayAction::ToggleRepeat),
+            "update-playlist" => Some(TrayAction::UpdatePlaylist),
+            "open-folder" => Some(TrayAction::OpenFolder),
+            "favorite" => Some(TrayAction::ToggleFavorite),
+            "block" => Some(TrayAction::Block),
+            "favorites-only" => Some(TrayAction::ToggleFavoritesOnly),
+            "sleep-song" => Some(TrayAction::SleepAfterSong),
+            "sleep-off" => Some(TrayAction::SleepOff),
+            "device-default" => Some(TrayAction::OutputDevice(None)),
+            "quit" => Some(TrayAction::Quit),
+            id => {
+                let (kind, value) = id.split_once('-')?;
+                match kind {
+                    "volume" => value.parse().ok().map(TrayAction::Volume),
+                    "playlist" => value.parse().ok().map(TrayAction::Playlist),
+                    "rate" => value.parse().ok().map(TrayAction::Rate),
+                    "sleep" => value.parse().ok().map(TrayAction::Sleep),
+                    "device" => value.parse().ok().map(|index| TrayAction::OutputDevice(Some(index))),
+                    _ => None,
+                }
+            },
+        }
+    }
+}
+
+/// What the tray menu shows, about the current song & playback.
+#[derive(Debug, Clone, Default)]
+pub struct TrayState {
+    /// Current song.
+    pub title: Option<String>,
+    pub playing: bool,
+    /// From 0 to 1.
+    pub volume: f32,
+    pub muted: bool,
+    pub shuffle: bool,
+    pub repeat: bool,
+    pub active_playlist: usize,
+    /// If the playlist is currently being updated.
+    pub updating: bool,
+    pub favorite: bool,
+    pub rating: Option<u8>,
+    pub favorites_only: bool,
+    /// Remaining time of the sleep timer, like "Pausing in 25 min".
+    pub sleep: Option<String>,
+    /// Chosen output device, None is the default device.
+    pub output_device: Option<String>,
+}
+
+
+
+pub struct Tray {
+    icon: TrayIcon,
+    title: MenuItem,
+    play_pause: MenuItem,
+    /// Same order as `VOLUME_PRESETS`.
+    volumes: Vec<CheckMenuItem>,
+    mute: CheckMenuItem,
+    shuffle: CheckMenuItem,
+    repeat: CheckMenuItem,
+    playlists: Vec<CheckMenuItem>,
+    /// None if there's no playlist to update.
+    update_playlist: Option<MenuItem>,
+    open_folder: MenuItem,
+    favorite: CheckMenuItem,
+    /// Index is the rating, 0 is no rating.
+    ratings: Vec<CheckMenuItem>,
+    favorites_only: CheckMenuItem,
+    sleep: Submenu,
+    sleep_off: MenuItem,
+    output_device: Submenu,
+    default_device: CheckMenuItem,
+    /// The device names with their items, these change when devices are connected & disconnected.
+    devices: Vec<(String, CheckMenuItem)>,
+}
+
+impl Tray {
+    /// `can_update` adds the "Update playlist now" item, for when there is a yt-dlp playlist.
+    pub fn new(playlist_names: &[String], can_update: bool) -> Result<Tray> {
+        let title = MenuItem::new("Not playing", false, None);
+        let play_pause = MenuItem::with_id("play-pause", "Play", true, None);
+
+        let volumes = VOLUME_PRESETS.iter()
+            .map(|volume| CheckMenuItem::with_id(format!("volume-{}", volume), format!("{}%", volume), true, false, None))
+            .collect::<Vec<_>>();
+        let mute = CheckMenuItem::with_id("mute", "Mute", true, false, None);
+        let volume = Submenu::new("Volume", true);
+        for item in &volumes {
+            volume.append(item)?;
+        }
+        volume.append_items(&[&PredefinedMenuItem::separator(), &mute])?;
+
+        let shuffle = CheckMenuItem::with_id("shuffle", "Shuffle", true, false, None);
+        let repeat = CheckMenuItem::with_id("repeat", "Repeat song", true, false, None);
+
+        let playlists = playlist_names.iter().enumerate()
+            .map(|(i, name)| CheckMenuItem::with_id(format!("playlist-{}", i), name, true, i == 0, None))
+            .collect::<Vec<_>>();
+        let playlist = Submenu::new("Playlist", playlists.len() > 1);
+        for item in &playlists {
+            playlist.append(item)?;
+        }
+
+        let sleep_off = MenuItem::with_id("sleep-off", "Off", false, None);
+        let sleep = Submenu::new("Sleep timer", true);
+        for minutes in SLEEP_PRESETS {
+            sleep.append(&MenuItem::with_id(format!("sleep-{}", minutes), format!("{} min", minutes), true, None))?;
+        }
+        sleep.append_items(&[
+            &MenuItem::with_id("sleep-song", "After this song", true, None),
+            &PredefinedMenuItem::separator(),
+            &sleep_off,
+        ])?;
+
+        let default_device = CheckMenuItem::with_id("device-default", "Default", true, true, None);
+        let output_device = Submenu::new("Output device", true);
+        output_device.append_items(&[&default_device, &PredefinedMenuItem::separator()])?;
+
+        let update_playlist = can_update.then(|| MenuItem::with_id("update-playlist", "Update playlist now", true, None));
+        let open_folder = MenuItem::with_id("open-folder", "Open folder", true, None);
+
+        let favorite = CheckMenuItem::with_id("favorite", "Favorite", true, false, None);
+        let ratings = (0..=5u8)
+            .map(|rating| {
+                let text = if rating == 0 { "No rating".to_string() } else { "★".repeat(rating as usize) };
+                CheckMenuItem::with_id(format!("rate-{}", rating), text, true, rating == 0, None)
+            })
+            .collect::<Vec<_>>();
+        let rate = Submenu::new("Rate", true);
+        for rating in &ratings {
+            rate.append(rating)?;
+        }
+        let favorites_only = CheckMenuItem::with_id("favorites-only", "Only play favorites", true, false, None);
+
+        let menu = Menu::with_items(&[
+            &title,
+            &PredefinedMenuItem::separator(),
+            &play_pause,
+            &MenuItem::with_id("next", "Next", true, None),
+            &MenuItem::with_id("previous", "Previous", true, None),
+            &volume,
+            &output_device,
+            &PredefinedMenuItem::separator(),
+            &shuffle,
+            &repeat,
+            &playlist,
+            &PredefinedMenuItem::separator(),
+            &favorite,
+            &rate,
+            &MenuItem::with_id("block", "Never play again", true, None),
+            &favorites_only,
+            &PredefinedMenuItem::separator(),
+            &sleep,
+        ])?;
+        if let Some(update_playlist) = &update_playlist {
+            menu.append(update_playlist)?;
+        }
+        menu.append_items(&[
+            &open_folder,
+            &PredefinedMenuItem::separator(),
+            &MenuItem::with_id("quit", "Quit", true, None),
+        ])?;
+
+        // TODO: Icon (Icon as current song image.)
+        let icon = tray_icon::Icon::from_rgba(vec![255, 0, 255, 255], 1, 1)?;
+        let icon = TrayIconBuilder::new()
+            .with_title("yt-dlp-music-player")
+            .with_tooltip("yt-dlp-music-player\nLeft: Next\nRight: Menu")
+            .with_id("yt-dlp-music-player")
+            .with_icon(icon)
+            .with_menu(Box::new(menu))
+            .build()?;
+
+        Ok(Tray {
+            icon, title, play_pause, volumes, mute, shuffle, repeat, playlists,
+            update_playlist, open_folder, favorite, ratings, favorites_only, sleep, sleep_off,
+            output_device, default_device, devices: vec![],
+        })
+    }
+
+    /// Replaces the items of the output device submenu, if the devices changed.
+    pub fn set_output_devices(&mut self, names: &[String]) -> Result<()> {
+        if self.devices.iter().map(|(name, _)| name).eq(names) {
+            return Ok(());
+        }
+        for (_, item) in self.devices.drain(..) {
+            self.output_device.remove(&item)?;
+        }
+        for (i, name) in names.iter().enumerate() {
+            let item = CheckMenuItem::with_id(format!("device-{}", i), name, true, false, None);
+            self.output_device.append(&item)?;
+            self.devices.push((name.clone(), item));
+        }
+        Ok(())
+    }
+
+    pub fn icon(&self) -> &TrayIcon {
+        &self.icon
+    }
+
+    pub fn update(&self, state: &TrayState) -> Result<()> {
+        self.title.set_text(state.title.as_deref().unwrap_or("Not playing"));
+        let mut tooltip = state.title.clone().unwrap_or("yt-dlp-music-player".to_string());
+        if let Some(sleep) = &state.sleep {
+            tooltip.push('\n');
+            tooltip.push_str(sleep);
+        }
+        self.icon.set_tooltip(Some(format!("{}\nLeft: Next\nRight: Menu", tooltip)))?;
+        self.play_pause.set_text(if state.playing { "Pause" } else { "Play" });
+
+        let volume = (state.volume * 100.0).round() as u8;
+        for (preset, item) in VOLUME_PRESETS.iter().zip(&self.volumes) {
+            item.set_checked(*preset == volume);
+        }
+        self.mute.set_checked(state.muted);
+        self.shuffle.set_checked(state.shuffle);
+        self.repeat.set_checked(state.repeat);
+        for (i, item) in self.playlists.iter().enumerate() {
+            item.set_checked(i == state.active_playlist);
+        }
+        if let Some(update_playlist) = &self.update_playlist {
+            update_playlist.set_text(if state.updating { "Updating playlist. . ." } else { "Update playlist now" });
+            update_playlist.set_enabled(!state.updating);
+        }
+        self.open_folder.set_enabled(state.title.is_some());
+
+        self.favorite.set_checked(state.favorite);
+        for (rating, item) in self.ratings.iter().enumerate() {
+            item.set_checked(state.rating.unwrap_or(0) as usize == rating);
+        }
+        self.favorites_only.set_checked(state.favorites_only);
+
+        self.sleep.set_text(match &state.sleep {
+            Some(sleep) => format!("Sleep timer ({})", sleep),
+            None => "Sleep timer".to_string(),
+        });
+        self.sleep_off.set_enabled(state.sleep.is_some());
+
+        // A chosen device that isn't there is played on the default device.
+        let chosen = self.devices.iter().position(|(name, _)| Some(name) == state.output_device.as_ref());
+        self.default_device.set_checked(chosen.is_none());
+        for (i, (_, item)) in self.devices.iter().enumerate() {
+            item.set_checked(Some(i) == chosen);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,28 @@
+use anyhow::{anyhow, Result};
+use crate::library::Library;
+
+
+
+/// Decodes every song in the library, to find files that can't be played.
+/// Failed songs are marked as unplayable in the library so they're skipped, songs that decode again are unmarked.
+pub fn verify_library(library: &mut Library) -> Result<()> {
+    let songs = library.scan()?;
+    let mut failed = 0;
+    for song in &songs {
+        let result = song.verify().map_err(|err| err.to_string());
+        if let Err(err) = &result {
+            println!("Failed: {:#?}: {}", song.file(), err);
+            failed += 1;
+        }
+        if let Some(entry) = library.entry_mut(song) {
+            entry.unplayable = result.err();
+        }
+    }
+    library.save()?;
+
+    println!("Verified {} song(s), {} failed.", songs.len(), failed);
+    if failed > 0 {
+        return Err(anyhow!("{} song(s) can't be played", failed));
+    }
+    Ok(())
+}
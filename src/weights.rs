@@ -0,0 +1,192 @@
+use anyhow::Result;
+use serde::Serialize;
+use crate::{library::{unix_time_now, Library, LibraryEntry}, song::Song};
+
+
+
+const SECONDS_PER_DAY: f64 = 60.0 * 60.0 * 24.0;
+
+/// How strongly skips lower the weight, the skip ratio is raised to this power.
+const SKIP_PENALTY: f64 = 2.0;
+/// Weight multiplier for songs that haven't been heard in `STALENESS_DAYS`.
+const STALENESS_BOOST: f64 = 1.0;
+const STALENESS_DAYS: f64 = 30.0;
+/// Lowest possible weight, so that no song is ever completely excluded.
+const MINIMUM_WEIGHT: f64 = 0.01;
+
+
+
+#[derive(Debug, Clone, Copy)]
+pub struct WeightOptions {
+    /// Extra weight for newly downloaded songs, fades out over `freshness_days`.
+    pub freshness_boost: f64,
+    pub freshness_days: f64,
+}
+
+/// Weight of a song for `PlaylistShuffle::Weighted`, and the factors it was calculated from.
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct SongWeight {
+    pub weight: f64,
+    /// `(1 - skips / (plays + skips + 1)) ^ SKIP_PENALTY`
+    pub skip_factor: f64,
+    /// `1 + STALENESS_BOOST * min(days since last played / STALENESS_DAYS, 1)`
+    pub staleness_factor: f64,
+    /// `1 + freshness_boost * (1 - days since added / freshness_days)`
+    pub freshness_factor: f64,
+}
+
+impl SongWeight {
+    pub fn new(entry: Option<&LibraryEntry>, options: &WeightOptions, now: u64) -> SongWeight {
+        // Songs that aren't in the library (yet) are treated as brand new.
+        let Some(entry) = entry else {
+            return SongWeight::from_factors(1.0, 1.0 + STALENESS_BOOST, 1.0 + options.freshness_boost);
+        };
+
+        let days_since = |time: u64| now.saturating_sub(time) as f64 / SECONDS_PER_DAY;
+
+        let skips = entry.skip_count as f64;
+        let plays = entry.play_count as f64;
+        let skip_factor = (1.0 - skips / (plays + skips + 1.0)).powf(SKIP_PENALTY);
+
+        let days_since_heard = days_since(entry.last_played.unwrap_or(entry.date_added));
+        let staleness_factor = 1.0 + STALENESS_BOOST * (days_since_heard / STALENESS_DAYS).min(1.0);
+
+        let days_since_added = days_since(entry.date_added);
+        let freshness_factor = if options.freshness_days > 0.0 && days_since_added < options.freshness_days {
+            1.0 + options.freshness_boost * (1.0 - days_since_added / options.freshness_days)
+        } else {
+            1.0
+        };
+
+        SongWeight::from_factors(skip_factor, staleness_factor, freshness_factor)
+    }
+
+    fn from_factors(skip_factor: f64, staleness_factor: f64, freshness_factor: f64) -> SongWeight {
+        SongWeight {
+            weight: (skip_factor * staleness_factor * freshness_factor).max(MINIMUM_WEIGHT),
+            skip_factor,
+            staleness_factor,
+            freshness_factor,
+        }
+    }
+}
+
+pub fn song_weights(songs: &[Song], library: &Library, options: &WeightOptions) -> Vec<f64> {
+    let now = unix_time_now();
+    songs.iter().map(|song| SongWeight::new(library.entry(song), options, now).weight).collect()
+}
+
+
+
+/// Prints the weight of every song in the library, so the weighted shuffle can be explained.
+pub fn print_weights(library: &Library, options: &WeightOptions) -> Result<()> {
+    let now = unix_time_now();
+    let songs = library.songs();
+    let mut weights = songs.iter()
+        .map(|song| (song, library.entry(song), SongWeight::new(library.entry(song), options, now)))
+        .collect::<Vec<_>>();
+    weights.sort_by(|a, b| b.2.weight.total_cmp(&a.2.weight));
+
+    let total_weight: f64 = weights.iter().map(|(_, _, w)| w.weight).sum();
+
+    println!("{:>7} {:>7} {:>6} {:>6} {:>6} {:>5} {:>5}  Title", "Chance", "Weight", "Skip", "Stale", "Fresh", "Plays", "Skips");
+    for (song, entry, weight) in weights {
+        println!(
+            "{:>6.2}% {:>7.3} {:>6.3} {:>6.3} {:>6.3} {:>5} {:>5}  {}",
+            weight.weight / total_weight * 100.0,
+            weight.weight,
+            weight.skip_factor,
+            weight.staleness_factor,
+            weight.freshness_factor,
+            entry.map(|e| e.play_count).unwrap_or(0),
+            entry.map(|e| e.skip_count).unwrap_or(0),
+            song.name(),
+        );
+    }
+    println!();
+    println!("Chance ignores the recently played songs, which are never picked.");
+
+    Ok(())
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_000 * SECONDS_PER_DAY as u64;
+    const OPTIONS: WeightOptions = WeightOptions { freshness_boost: 2.0, freshness_days: 10.0 };
+
+    fn days_ago(days: f64) -> u64 {
+        NOW - (days * SECONDS_PER_DAY) as u64
+    }
+
+    fn entry(plays: u32, skips: u32, last_played: Option<u64>, date_added: u64) -> LibraryEntry {
+        serde_json::from_value(serde_json::json!({
+            "video_id": null,
+            "title": "Song",
+            "artist": null,
+            "duration": null,
+            "format": "mp3",
+            "date_added": date_added,
+            "modified": date_added,
+            "play_count": plays,
+            "skip_count": skips,
+            "last_played": last_played,
+        })).unwrap()
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn skip_penalty() {
+        let weight = |plays, skips| SongWeight::new(Some(&entry(plays, skips, Some(NOW), days_ago(100.0))), &OPTIONS, NOW);
+        assert_close(weight(0, 0).skip_factor, 1.0);
+        assert_close(weight(3, 0).skip_factor, 1.0);
+        // (1 - 1 / 2) ^ 2
+        assert_close(weight(0, 1).skip_factor, 0.25);
+        // (1 - 3 / 8) ^ 2
+        assert_close(weight(4, 3).skip_factor, 0.390625);
+        assert!(weight(0, 3).weight < weight(0, 1).weight);
+        // Always skipped songs still have a chance.
+        assert_close(weight(0, 10_000).weight, MINIMUM_WEIGHT);
+    }
+
+    #[test]
+    fn staleness() {
+        let weight = |last_played| SongWeight::new(Some(&entry(1, 0, Some(last_played), days_ago(100.0))), &OPTIONS, NOW);
+        assert_close(weight(NOW).staleness_factor, 1.0);
+        assert_close(weight(days_ago(15.0)).staleness_factor, 1.0 + STALENESS_BOOST / 2.0);
+        assert_close(weight(days_ago(STALENESS_DAYS)).staleness_factor, 1.0 + STALENESS_BOOST);
+        assert_close(weight(days_ago(365.0)).staleness_factor, 1.0 + STALENESS_BOOST);
+        // Played in the future, like after changing the clock.
+        assert_close(weight(NOW + 1_000).staleness_factor, 1.0);
+
+        // Never played songs are as stale as they are old.
+        let never_played = SongWeight::new(Some(&entry(0, 0, None, days_ago(15.0))), &OPTIONS, NOW);
+        assert_close(never_played.staleness_factor, 1.0 + STALENESS_BOOST / 2.0);
+    }
+
+    #[test]
+    fn freshness() {
+        let weight = |date_added, options: &WeightOptions| SongWeight::new(Some(&entry(0, 0, Some(NOW), date_added)), options, NOW);
+        assert_close(weight(NOW, &OPTIONS).freshness_factor, 3.0);
+        assert_close(weight(days_ago(5.0), &OPTIONS).freshness_factor, 2.0);
+        assert_close(weight(days_ago(10.0), &OPTIONS).freshness_factor, 1.0);
+        assert_close(weight(days_ago(20.0), &OPTIONS).freshness_factor, 1.0);
+        let disabled = WeightOptions { freshness_boost: 2.0, freshness_days: 0.0 };
+        assert_close(weight(NOW, &disabled).freshness_factor, 1.0);
+    }
+
+    #[test]
+    fn not_in_library() {
+        let weight = SongWeight::new(None, &OPTIONS, NOW);
+        assert_close(weight.skip_factor, 1.0);
+        assert_close(weight.staleness_factor, 1.0 + STALENESS_BOOST);
+        assert_close(weight.freshness_factor, 1.0 + OPTIONS.freshness_boost);
+        assert_close(weight.weight, (1.0 + STALENESS_BOOST) * (1.0 + OPTIONS.freshness_boost));
+    }
+}
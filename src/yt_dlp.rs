@@ -0,0 +1,190 @@
+use std::{collections::BTreeMap, fs, io::{self, BufRead, BufReader, Read}, path::{Path, PathBuf}, process::{Command, ExitStatus, Stdio}, thread};
+use serde::Deserialize;
+use thiserror::Error;
+use tracing::{info, warn};
+use crate::paths;
+
+
+
+#[derive(Debug, Error)]
+pub enum DownloadError {
+    #[error("Failed to run yt-dlp {path:?}, is it installed? (Set yt-dlp-path if it isn't on the PATH.) ({source})")]
+    Spawn { path: PathBuf, source: io::Error },
+    #[error("yt-dlp failed to resolve {url} ({status}): {stderr}")]
+    Resolve { url: url::Url, status: ExitStatus, stderr: String },
+    #[error("Invalid playlist \"{playlist}\": {source}")]
+    InvalidUrl { playlist: String, source: url::ParseError },
+    #[error("Unexpected yt-dlp output: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+pub type Result<T, E = DownloadError> = std::result::Result<T, E>;
+
+
+
+/// Playlist as reported by yt-dlp.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ResolvedPlaylist {
+    /// e.g. "YoutubeTab", "Soundcloud", "BandcampAlbum"
+    pub extractor_key: String,
+    pub id: String,
+}
+
+impl ResolvedPlaylist {
+    /// Stable directory name, does not depend on how the URL was written.
+    pub fn directory_name(&self) -> String {
+        safe_directory_name(&format!("{} {}", self.extractor_key, self.id))
+    }
+}
+
+fn safe_directory_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' || c == ' ' { c } else { '_' })
+        .collect()
+}
+
+
+
+/// Turns the configured playlist into a URL yt-dlp understands.
+/// Anything that isn't a URL is assumed to be a YouTube playlist ID.
+pub fn playlist_url(playlist: &str) -> Result<url::Url> {
+    match url::Url::parse(playlist) {
+        Ok(url) => Ok(url),
+        Err(_) => url::Url::parse(&format!("https://www.youtube.com/playlist?list={}", playlist))
+            .map_err(|source| DownloadError::InvalidUrl { playlist: playlist.to_string(), source }),
+    }
+}
+
+/// Asks yt-dlp for the extractor & ID of the playlist, without downloading anything.
+pub fn resolve_playlist(yt_dlp_path: &PathBuf, url: &url::Url) -> Result<ResolvedPlaylist> {
+    let output = Command::new(yt_dlp_path)
+        .arg("--flat-playlist")
+        .arg("--dump-single-json")
+        // Only the playlist itself is needed, not all of its entries.
+        .arg("--playlist-items").arg("1")
+        .arg(url.to_string())
+        .output()
+        .map_err(|source| DownloadError::Spawn { path: yt_dlp_path.clone(), source })?;
+
+    if !output.status.success() {
+        return Err(DownloadError::Resolve {
+            url: url.clone(),
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+
+    Ok(serde_json::from_slice(&output.stdout)?)
+}
+
+/// Gets the archive directory of a playlist inside of `base_directory`.
+/// Resolved directory names are cached in "playlists.json", so yt-dlp is only asked once per URL.
+/// Without `resolve` yt-dlp isn't asked at all, names that aren't cached are guessed by `guess_playlist_directory`.
+pub fn playlist_directory(base_directory: &Path, yt_dlp_path: &PathBuf, url: &url::Url, resolve: bool) -> Result<PathBuf> {
+    let cache_file = base_directory.join(paths::PLAYLISTS_FILE_NAME);
+    let mut cache: BTreeMap<String, String> = match fs::read_to_string(&cache_file) {
+        Ok(cache) => serde_json::from_str(&cache)?,
+        Err(_) => BTreeMap::new(),
+    };
+
+    if let Some(directory_name) = cache.get(url.as_str()) {
+        return Ok(base_directory.join(directory_name));
+    }
+    if !resolve {
+        return Ok(guess_playlist_directory(base_directory, url));
+    }
+
+    let resolved = resolve_playlist(yt_dlp_path, url)?;
+    let directory = base_directory.join(resolved.directory_name());
+
+    // Playlist directories used to be named after the "list" query parameter.
+    if let Some((_, list)) = url.query_pairs().find(|(name, _)| name == "list") {
+        let legacy_directory = base_directory.join(list.as_ref());
+        if resolved.extractor_key == "YoutubeTab" && legacy_directory.is_dir() && !directory.exists() {
+            info!("Moving playlist archive {:?} to {:?}", &legacy_directory, &directory);
+            fs::rename(&legacy_directory, &directory)?;
+        }
+    }
+
+    cache.insert(url.to_string(), resolved.directory_name());
+    fs::create_dir_all(base_directory)?;
+    fs::write(&cache_file, serde_json::to_string_pretty(&cache)?)?;
+
+    Ok(directory)
+}
+
+/// Directory of a playlist that wasn't resolved yet, without running yt-dlp. (Like when the playlist isn't updated.)
+/// YouTube playlists are named like yt-dlp resolves them, or by the "list" ID of older versions if that directory exists.
+/// Other URLs are named after the URL itself, they get the resolved name once the playlist is updated.
+fn guess_playlist_directory(base_directory: &Path, url: &url::Url) -> PathBuf {
+    match url.query_pairs().find(|(name, _)| name == "list") {
+        Some((_, list)) => {
+            let legacy_directory = base_directory.join(list.as_ref());
+            if legacy_directory.is_dir() {
+                legacy_directory
+            } else {
+                base_directory.join(safe_directory_name(&format!("YoutubeTab {}", list)))
+            }
+        },
+        None => base_directory.join(safe_directory_name(&format!("{}{}", url.host_str().unwrap_or_default(), url.path()))),
+    }
+}
+
+
+
+pub fn update_playlist(playlist_archive_directory: &PathBuf, yt_dlp_path: &PathBuf, ffmpeg_path: &PathBuf, url: &url::Url) -> Result<()> {
+    // Update playlist archive directory with yt-dlp
+    info!("Updating playlist archive. . .");
+    let mut playlist_archive_file = playlist_archive_directory.clone();
+    playlist_archive_file.push("archive.txt");
+
+    let mut cmd = Command::new(yt_dlp_path)
+        // .arg("-f").arg("bestaudio")
+        .arg("--ffmpeg-location").arg(ffmpeg_path)
+        .arg("-x")
+        .arg("--audio-format").arg("m4a") // TODO: What is the best sounding audio format for tiniest file size?
+        .arg("--paths").arg(playlist_archive_directory)
+        // Loudness normalization.
+        // NOTE: this is disable and instead implemented in song::Song due to being unable to reliably normalize the loudness.
+        // .arg("--postprocessor-args").arg("ffmpeg:-af volume=0dB")
+        .arg("-o").arg("%(id)s %(title)s.%(ext)s")
+        .arg("--download-archive").arg(&playlist_archive_file)
+        .arg("--write-thumbnail")
+        // Title & artist (uploader) tags, used by the library index.
+        .arg("--embed-metadata")
+        .arg(url.to_string())
+        // .arg("https://www.youtube.com/watch?v=dQw4w9WgXcQ")
+        // Progress bars would fill the log.
+        .arg("--no-progress")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|source| DownloadError::Spawn { path: yt_dlp_path.clone(), source })?;
+
+    let stdout = cmd.stdout.take().map(|stdout| thread::spawn(move || log_lines(stdout, false)));
+    let stderr = cmd.stderr.take().map(|stderr| thread::spawn(move || log_lines(stderr, true)));
+    let status = cmd.wait()?;
+    for output in [stdout, stderr].into_iter().flatten() {
+        let _ = output.join();
+    }
+
+    // yt-dlp also fails if only some videos are unavailable, so this isn't an error.
+    if !status.success() {
+        warn!("yt-dlp exited with {}", status);
+    }
+    info!("Done updating playlist archive.");
+    Ok(())
+}
+
+/// Logs the output of yt-dlp line by line, errors & warnings are written to stderr.
+fn log_lines(output: impl Read, stderr: bool) {
+    for line in BufReader::new(output).lines().map_while(io::Result::ok) {
+        if stderr {
+            warn!(target: "yt_dlp_music_player::yt_dlp::output", "{}", line);
+        } else {
+            info!(target: "yt_dlp_music_player::yt_dlp::output", "{}", line);
+        }
+    }
+}